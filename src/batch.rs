@@ -0,0 +1,61 @@
+pub enum BatchOp {
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+}
+
+/// A set of writes that is applied atomically with `Cask::write`.
+///
+/// Writes are applied in the order they were added to the batch. After a crash either all of them
+/// are recovered or none of them.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use cask::{CaskOptions, WriteBatch};
+///
+/// let cask = CaskOptions::default().open("cask.db").unwrap();
+///
+/// let mut batch = WriteBatch::new();
+/// batch.put("user:1", "alice").put("email:alice@example.com", "user:1");
+/// batch.delete("email:alice@example.org");
+///
+/// cask.write(batch).unwrap();
+/// ```
+#[derive(Default)]
+pub struct WriteBatch {
+    pub(crate) ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// Adds the insertion of a key-value pair to the batch.
+    pub fn put<K: Into<Vec<u8>>, V: Into<Vec<u8>>>(&mut self, key: K, value: V) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Put(key.into(), value.into()));
+        self
+    }
+
+    /// Adds the removal of a key to the batch.
+    pub fn delete<K: Into<Vec<u8>>>(&mut self, key: K) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Delete(key.into()));
+        self
+    }
+
+    /// Returns the number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns `true` if the batch has no writes.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Removes all writes from the batch.
+    pub fn clear(&mut self) {
+        self.ops.clear();
+    }
+}
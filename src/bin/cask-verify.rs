@@ -0,0 +1,27 @@
+extern crate cask;
+
+use std::env;
+use std::process;
+
+fn main() {
+    let path = match env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("Usage: cask-verify <path>");
+            process::exit(2);
+        }
+    };
+
+    match cask::verify(&path) {
+        Ok(report) => {
+            print!("{}", report);
+            if !report.is_ok() {
+                process::exit(1);
+            }
+        }
+        Err(err) => {
+            eprintln!("Failed to verify {}: {}", path, err);
+            process::exit(2);
+        }
+    }
+}
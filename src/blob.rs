@@ -0,0 +1,478 @@
+use std::cmp;
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::{Cursor, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use regex::Regex;
+
+use crypto::{FileCipher, Keyring, TAG_SIZE};
+use data::SequenceNumber;
+use errors::{Error, Result};
+use header::FileHeader;
+use util::{get_file_handle, xxhash32};
+
+const BLOB_FILE_EXTENSION: &'static str = "cask.blob";
+const TEMP_FILE_EXTENSION: &'static str = "tmp";
+// values are split in chunks so that they can be streamed and verified without being buffered
+const CHUNK_SIZE: usize = 64 * 1024;
+const CHECKSUM_SIZE: usize = 4;
+
+/// Writes a value to a blob file, split in chunks which are each followed by their checksum or, if
+/// the blob is encrypted, by their authentication tag. The file is removed if the writer is
+/// dropped before the value is finished.
+pub struct BlobWriter {
+    path: PathBuf,
+    file: File,
+    // sequence number the blob is written for, which encrypted chunks are bound to
+    sequence: SequenceNumber,
+    cipher: Option<FileCipher>,
+    chunk: Vec<u8>,
+    chunks: u64,
+    len: u64,
+    finished: bool,
+}
+
+impl BlobWriter {
+    pub fn new(
+        path: PathBuf,
+        sequence: SequenceNumber,
+        cipher: Option<FileCipher>,
+    ) -> Result<BlobWriter> {
+        let mut file = get_file_handle(&path, true)?;
+
+        let header = match cipher {
+            Some(ref cipher) => cipher.header(),
+            None => FileHeader::new(None),
+        };
+        header.write(&mut file)?;
+
+        Ok(BlobWriter {
+            path: path,
+            file: file,
+            sequence: sequence,
+            cipher: cipher,
+            chunk: Vec::with_capacity(CHUNK_SIZE + TAG_SIZE),
+            chunks: 0,
+            len: 0,
+            finished: false,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn write(&mut self, mut buf: &[u8]) -> Result<()> {
+        while !buf.is_empty() {
+            let n = cmp::min(buf.len(), CHUNK_SIZE - self.chunk.len());
+            self.chunk.extend_from_slice(&buf[..n]);
+            buf = &buf[n..];
+
+            if self.chunk.len() == CHUNK_SIZE {
+                self.write_chunk()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Copies exactly `len` bytes from `reader` into the blob.
+    pub fn copy_from<R: Read>(&mut self, reader: &mut R, len: u64) -> Result<()> {
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        let mut remaining = len;
+
+        while remaining > 0 {
+            let n = cmp::min(remaining, CHUNK_SIZE as u64) as usize;
+            reader.read_exact(&mut buf[..n])?;
+            self.write(&buf[..n])?;
+            remaining -= n as u64;
+        }
+
+        Ok(())
+    }
+
+    fn write_chunk(&mut self) -> Result<()> {
+        self.len += self.chunk.len() as u64;
+
+        match self.cipher {
+            Some(ref cipher) => cipher.seal_chunk(self.sequence, self.chunks, &mut self.chunk)?,
+            None => {
+                let checksum = xxhash32(&self.chunk);
+                self.chunk.write_u32::<LittleEndian>(checksum)?;
+            }
+        }
+
+        self.file.write_all(&self.chunk)?;
+        self.chunk.clear();
+        self.chunks += 1;
+
+        Ok(())
+    }
+
+    /// Writes the last chunk and synchronizes the blob to disk, so that it's durable before the
+    /// entry referencing it is written. Returns the length of the value.
+    pub fn finish(mut self) -> Result<u64> {
+        if !self.chunk.is_empty() {
+            self.write_chunk()?;
+        }
+
+        self.file.sync_all()?;
+        self.finished = true;
+
+        Ok(self.len)
+    }
+}
+
+impl Drop for BlobWriter {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Streams a value from a blob file, verifying each chunk as it's read.
+pub struct BlobReader {
+    file: File,
+    sequence: SequenceNumber,
+    cipher: Option<FileCipher>,
+    // position of the first chunk in the file
+    data_pos: u64,
+    len: u64,
+    pos: u64,
+    chunk: Vec<u8>,
+    chunk_index: Option<u64>,
+}
+
+impl BlobReader {
+    /// Opens the blob file at `path` written for `sequence`, holding a value of `len` bytes.
+    pub fn open(
+        path: &Path,
+        sequence: SequenceNumber,
+        keyring: &Keyring,
+        len: u64,
+    ) -> Result<BlobReader> {
+        let mut file = get_file_handle(path, false)?;
+        let header = FileHeader::read(&mut file)?;
+
+        // blob files were introduced after file headers, a missing header means it's corrupt
+        if header.version == 0 {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "blob file without header",
+            )));
+        }
+
+        Ok(BlobReader {
+            file: file,
+            sequence: sequence,
+            cipher: keyring.file_cipher(&header)?,
+            data_pos: header.size(),
+            len: len,
+            pos: 0,
+            chunk: Vec::with_capacity(CHUNK_SIZE + TAG_SIZE),
+            chunk_index: None,
+        })
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns the id of the key the blob is encrypted with, if it's encrypted.
+    pub fn key_id(&self) -> Option<u32> {
+        self.cipher.as_ref().map(|c| c.key_id())
+    }
+
+    fn trailer_size(&self) -> usize {
+        if self.cipher.is_some() {
+            TAG_SIZE
+        } else {
+            CHECKSUM_SIZE
+        }
+    }
+
+    fn read_chunk(&mut self, index: u64) -> Result<()> {
+        let trailer_size = self.trailer_size();
+        let chunk_pos = self.data_pos + index * (CHUNK_SIZE + trailer_size) as u64;
+        let chunk_size = cmp::min(CHUNK_SIZE as u64, self.len - index * CHUNK_SIZE as u64) as usize;
+
+        self.chunk_index = None;
+        self.chunk.resize(chunk_size + trailer_size, 0);
+        self.file.seek(SeekFrom::Start(chunk_pos))?;
+        self.file.read_exact(&mut self.chunk)?;
+
+        match self.cipher {
+            Some(ref cipher) => cipher.open_chunk(self.sequence, index, &mut self.chunk)?,
+            None => {
+                let checksum = Cursor::new(&self.chunk[chunk_size..])
+                    .read_u32::<LittleEndian>()?;
+                let hash = xxhash32(&self.chunk[..chunk_size]);
+
+                if hash != checksum {
+                    return Err(Error::InvalidChecksum {
+                        expected: checksum,
+                        found: hash,
+                    });
+                }
+
+                self.chunk.truncate(chunk_size);
+            }
+        }
+
+        self.chunk_index = Some(index);
+
+        Ok(())
+    }
+}
+
+impl Read for BlobReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let index = self.pos / CHUNK_SIZE as u64;
+        if self.chunk_index != Some(index) {
+            self.read_chunk(index).map_err(|err| match err {
+                Error::Io(err) => err,
+                err => io::Error::new(io::ErrorKind::InvalidData, err),
+            })?;
+        }
+
+        let offset = (self.pos % CHUNK_SIZE as u64) as usize;
+        let n = cmp::min(buf.len(), self.chunk.len() - offset);
+        buf[..n].copy_from_slice(&self.chunk[offset..offset + n]);
+        self.pos += n as u64;
+
+        Ok(n)
+    }
+}
+
+impl Seek for BlobReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+
+        // only the chunks that are actually read are verified
+        match pos {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => {
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "invalid seek to a negative or overflowing position",
+                ))
+            }
+        }
+    }
+}
+
+enum ValueSource {
+    Inline(Cursor<Vec<u8>>),
+    Blob(BlobReader),
+}
+
+/// A value being streamed with `Cask::get_reader`.
+///
+/// Values stored in blob files are read and verified one chunk at a time, so seeking into them only
+/// reads the chunks that are needed. Smaller values are read from the log at once.
+pub struct ValueReader {
+    source: ValueSource,
+}
+
+impl ValueReader {
+    pub(crate) fn inline(value: Vec<u8>) -> ValueReader {
+        ValueReader { source: ValueSource::Inline(Cursor::new(value)) }
+    }
+
+    pub(crate) fn blob(reader: BlobReader) -> ValueReader {
+        ValueReader { source: ValueSource::Blob(reader) }
+    }
+
+    /// Returns the length of the value.
+    pub fn len(&self) -> u64 {
+        match self.source {
+            ValueSource::Inline(ref cursor) => cursor.get_ref().len() as u64,
+            ValueSource::Blob(ref reader) => reader.len(),
+        }
+    }
+
+    /// Returns `true` if the value is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Read for ValueReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.source {
+            ValueSource::Inline(ref mut cursor) => cursor.read(buf),
+            ValueSource::Blob(ref mut reader) => reader.read(buf),
+        }
+    }
+}
+
+impl Seek for ValueReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self.source {
+            ValueSource::Inline(ref mut cursor) => cursor.seek(pos),
+            ValueSource::Blob(ref mut reader) => reader.seek(pos),
+        }
+    }
+}
+
+pub fn get_blob_file_path(path: &Path, sequence: SequenceNumber) -> PathBuf {
+    let sequence = format!("{:020}", sequence);
+    path.join(sequence).with_extension(BLOB_FILE_EXTENSION)
+}
+
+/// Returns the path of a blob that is being written before its sequence number is known.
+pub fn get_temp_blob_file_path(path: &Path, id: u32) -> PathBuf {
+    path.join(format!(
+        "{:010}.{}.{}",
+        id,
+        BLOB_FILE_EXTENSION,
+        TEMP_FILE_EXTENSION
+    ))
+}
+
+/// Removes the blob files whose entries were never written to the log, i.e. the ones written at or
+/// after `sequence` and, if known, the ones that aren't `referenced` by any entry, along with any
+/// leftover temporary blob files.
+pub fn remove_orphan_blobs(
+    path: &Path,
+    sequence: SequenceNumber,
+    referenced: Option<&HashSet<SequenceNumber>>,
+) -> Result<()> {
+    for file in fs::read_dir(path)? {
+        let file = file?;
+        let orphan = match file.file_name().to_str().and_then(parse_blob_file_name) {
+            Some((_, true)) => true,
+            Some((s, false)) => {
+                s >= sequence || referenced.is_some_and(|referenced| !referenced.contains(&s))
+            }
+            None => continue,
+        };
+
+        if orphan {
+            warn!("Removing orphan blob file {:?}", file.path());
+            fs::remove_file(file.path())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Moves the blob files in `from` whose sequence number isn't in `referenced` to `to`, returning
+/// their sequence numbers.
+pub fn move_unreferenced_blobs(
+    from: &Path,
+    to: &Path,
+    referenced: &HashSet<SequenceNumber>,
+) -> Result<Vec<SequenceNumber>> {
+    let mut moved = Vec::new();
+
+    for file in fs::read_dir(from)? {
+        let file = file?;
+        if let Some((sequence, false)) = file.file_name().to_str().and_then(parse_blob_file_name) {
+            if !referenced.contains(&sequence) {
+                fs::rename(file.path(), get_blob_file_path(to, sequence))?;
+                moved.push(sequence);
+            }
+        }
+    }
+
+    moved.sort();
+
+    Ok(moved)
+}
+
+/// Parses the name of a blob file, returning its sequence number, or id if it's a temporary file,
+/// and whether it's a temporary file.
+fn parse_blob_file_name(file_name: &str) -> Option<(u64, bool)> {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(&format!(
+            "^(\\d+).{}(.{})?$",
+            BLOB_FILE_EXTENSION,
+            TEMP_FILE_EXTENSION
+        )).unwrap();
+    }
+
+    RE.captures(file_name).and_then(|captures| {
+        captures[1].parse::<u64>().ok().map(|n| (n, captures.get(2).is_some()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::fs::OpenOptions;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::path::Path;
+
+    use blob::{BlobReader, BlobWriter, CHUNK_SIZE, get_blob_file_path};
+    use crypto::Keyring;
+    use header::HEADER_SIZE;
+
+    #[test]
+    fn test_blob() {
+        let path = Path::new("test-blob");
+        let _ = fs::remove_dir_all(path);
+        fs::create_dir(path).unwrap();
+
+        let value: Vec<u8> = (0..CHUNK_SIZE * 2 + 100).map(|i| i as u8).collect();
+
+        let mut keyring = Keyring::default();
+        keyring.add(1, [1; 32], true);
+
+        for (sequence, cipher) in [(1, None), (2, keyring.new_cipher().unwrap())] {
+            let blob_path = get_blob_file_path(path, sequence);
+
+            let mut writer = BlobWriter::new(blob_path.clone(), sequence, cipher).unwrap();
+            writer.write(&value[..10]).unwrap();
+            writer.copy_from(&mut &value[10..], value.len() as u64 - 10).unwrap();
+            assert_eq!(writer.finish().unwrap(), value.len() as u64);
+
+            let mut read = Vec::new();
+            BlobReader::open(&blob_path, sequence, &keyring, value.len() as u64)
+                .unwrap()
+                .read_to_end(&mut read)
+                .unwrap();
+            assert_eq!(read, value);
+        }
+
+        // an encrypted blob can't be read as the blob of another entry
+        let blob_path = get_blob_file_path(path, 2);
+        let mut reader = BlobReader::open(&blob_path, 1, &keyring, value.len() as u64).unwrap();
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+
+        // a corrupt chunk fails the read
+        let blob_path = get_blob_file_path(path, 1);
+        let mut blob_file = OpenOptions::new().write(true).open(&blob_path).unwrap();
+        blob_file
+            .seek(SeekFrom::Start(HEADER_SIZE + CHUNK_SIZE as u64 + 10))
+            .unwrap();
+        blob_file.write_all(&[0]).unwrap();
+
+        let mut reader = BlobReader::open(&blob_path, 1, &keyring, value.len() as u64).unwrap();
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+
+        // an unfinished blob is removed
+        let blob_path = get_blob_file_path(path, 3);
+        BlobWriter::new(blob_path.clone(), 3, None).unwrap();
+        assert!(!blob_path.exists());
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+}
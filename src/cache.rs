@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// A bounded cache of values, keyed by the location of their entry in the log.
+///
+/// Entries are never modified in place, so a cached value is valid for as long as its data file
+/// exists. Its size is bounded by the number of value bytes it holds, and values are evicted with
+/// the CLOCK approximation of LRU like the handles of the `FilePool`, so that hits only take a
+/// shared lock.
+pub struct ValueCache {
+    capacity: usize,
+    values: RwLock<Values>,
+}
+
+struct Values {
+    slots: Vec<Slot>,
+    index: HashMap<(u32, u64), usize>,
+    // next slot considered for eviction
+    hand: usize,
+    size: usize,
+}
+
+struct Slot {
+    file_id: u32,
+    entry_pos: u64,
+    value: Vec<u8>,
+    referenced: AtomicBool,
+}
+
+impl ValueCache {
+    /// Creates a cache holding up to `capacity` bytes of values. A cache without capacity doesn't
+    /// hold anything.
+    pub fn new(capacity: usize) -> ValueCache {
+        ValueCache {
+            capacity: capacity,
+            values: RwLock::new(Values {
+                slots: Vec::new(),
+                index: HashMap::new(),
+                hand: 0,
+                size: 0,
+            }),
+        }
+    }
+
+    pub fn get(&self, file_id: u32, entry_pos: u64) -> Option<Vec<u8>> {
+        if self.capacity == 0 {
+            return None;
+        }
+
+        let values = self.values.read().unwrap();
+        values.index.get(&(file_id, entry_pos)).map(|&i| {
+            let slot = &values.slots[i];
+            slot.referenced.store(true, Ordering::Relaxed);
+            slot.value.clone()
+        })
+    }
+
+    /// Caches the `value` of the entry at `entry_pos` in the data file `file_id`, unless it's
+    /// larger than the whole cache.
+    pub fn insert(&self, file_id: u32, entry_pos: u64, value: &[u8]) {
+        if self.capacity == 0 || value.len() > self.capacity {
+            return;
+        }
+
+        let mut values = self.values.write().unwrap();
+        if values.index.contains_key(&(file_id, entry_pos)) {
+            return;
+        }
+
+        while values.size + value.len() > self.capacity {
+            values.evict();
+        }
+
+        let i = values.slots.len();
+        values.index.insert((file_id, entry_pos), i);
+        values.size += value.len();
+        values.slots.push(Slot {
+            file_id: file_id,
+            entry_pos: entry_pos,
+            value: value.to_vec(),
+            referenced: AtomicBool::new(false),
+        });
+    }
+
+    /// Removes the value of the entry at `entry_pos` in the data file `file_id`.
+    pub fn remove(&self, file_id: u32, entry_pos: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut values = self.values.write().unwrap();
+        if let Some(i) = values.index.get(&(file_id, entry_pos)).cloned() {
+            values.remove(i);
+        }
+    }
+
+    /// Removes the values of all entries in the data files `file_ids`, e.g. after they were
+    /// compacted.
+    pub fn remove_files(&self, file_ids: &[u32]) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut values = self.values.write().unwrap();
+
+        let mut i = 0;
+        while i < values.slots.len() {
+            if file_ids.contains(&values.slots[i].file_id) {
+                // the last slot is moved to `i`, which must be checked again
+                values.remove(i);
+            } else {
+                i += 1;
+            }
+        }
+    }
+}
+
+impl Values {
+    fn evict(&mut self) {
+        loop {
+            let hand = self.hand;
+            self.hand = (self.hand + 1) % self.slots.len();
+
+            // give a second chance to the values read since the hand last passed them
+            if !self.slots[hand].referenced.swap(false, Ordering::Relaxed) {
+                self.remove(hand);
+                return;
+            }
+        }
+    }
+
+    fn remove(&mut self, i: usize) {
+        let slot = self.slots.swap_remove(i);
+        self.index.remove(&(slot.file_id, slot.entry_pos));
+        self.size -= slot.value.len();
+
+        if i < self.slots.len() {
+            let moved = &self.slots[i];
+            self.index.insert((moved.file_id, moved.entry_pos), i);
+        }
+
+        if self.hand >= self.slots.len() {
+            self.hand = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cache::ValueCache;
+
+    #[test]
+    fn test_value_cache() {
+        let cache = ValueCache::new(10);
+
+        cache.insert(1, 0, b"abcd");
+        cache.insert(1, 10, b"efgh");
+        assert_eq!(cache.get(1, 0), Some(b"abcd".to_vec()));
+
+        // 1:0 was read since it was cached, so it gets a second chance and 1:10 is evicted
+        cache.insert(2, 0, b"ijkl");
+        assert_eq!(cache.get(1, 10), None);
+        assert_eq!(cache.get(1, 0), Some(b"abcd".to_vec()));
+        assert_eq!(cache.get(2, 0), Some(b"ijkl".to_vec()));
+
+        // values larger than the cache aren't cached
+        cache.insert(3, 0, b"01234567890");
+        assert_eq!(cache.get(3, 0), None);
+
+        cache.remove(2, 0);
+        assert_eq!(cache.get(2, 0), None);
+
+        cache.insert(2, 10, b"mn");
+        cache.remove_files(&[1, 2]);
+        assert_eq!(cache.get(1, 0), None);
+        assert_eq!(cache.get(2, 10), None);
+
+        // a cache without capacity doesn't hold anything
+        let cache = ValueCache::new(0);
+        cache.insert(1, 0, b"");
+        assert_eq!(cache.get(1, 0), None);
+    }
+}
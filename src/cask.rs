@@ -1,60 +1,259 @@
-use std::collections::{BTreeSet, HashMap};
-use std::collections::hash_map::{Entry as HashMapEntry, Keys};
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::collections::Bound;
+use std::collections::btree_map::Entry as BTreeMapEntry;
+use std::collections::hash_map::Entry as HashMapEntry;
 use std::default::Default;
-use std::path::PathBuf;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::mem;
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
 use std::result::Result::Ok;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
 use std::time::Duration;
+use std::vec;
 use std::vec::Vec;
 
 use time;
 
-use data::{Entry, Hint, SequenceNumber};
-use errors::Result;
-use log::{Log, LogWrite};
+use batch::{BatchOp, WriteBatch};
+use blob::{ValueReader, remove_orphan_blobs};
+use cache::ValueCache;
+use crypto::Keyring;
+use data::{Entry, Hint, MAX_INLINE_VALUE_SIZE, MAX_VALUE_SIZE, SequenceNumber, is_expired};
+use errors::{Error, Result};
+use log::{CHECKPOINTS_VERSION, Log, LogWrite};
 use stats::Stats;
-use util::human_readable_byte_count;
+use util::{human_readable_byte_count, now_millis};
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct IndexEntry {
     pub file_id: u32,
     entry_pos: u64,
     pub entry_size: u64,
     sequence: SequenceNumber,
+    expires: Option<u64>,
+}
+
+impl IndexEntry {
+    fn is_expired(&self) -> bool {
+        is_expired(self.expires)
+    }
+}
+
+type KeyRange<'a> = (Bound<&'a [u8]>, Bound<&'a [u8]>);
+
+enum IndexMap {
+    Hashed(HashMap<Vec<u8>, IndexEntry>),
+    Ordered(BTreeMap<Vec<u8>, IndexEntry>),
+}
+
+impl IndexMap {
+    fn get(&self, key: &[u8]) -> Option<&IndexEntry> {
+        match *self {
+            IndexMap::Hashed(ref map) => map.get(key),
+            IndexMap::Ordered(ref map) => map.get(key),
+        }
+    }
+
+    fn get_mut(&mut self, key: &[u8]) -> Option<&mut IndexEntry> {
+        match *self {
+            IndexMap::Hashed(ref mut map) => map.get_mut(key),
+            IndexMap::Ordered(ref mut map) => map.get_mut(key),
+        }
+    }
+
+    fn insert(&mut self, key: Vec<u8>, index_entry: IndexEntry) -> Option<IndexEntry> {
+        match *self {
+            IndexMap::Hashed(ref mut map) => map.insert(key, index_entry),
+            IndexMap::Ordered(ref mut map) => map.insert(key, index_entry),
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<IndexEntry> {
+        match *self {
+            IndexMap::Hashed(ref mut map) => map.remove(key),
+            IndexMap::Ordered(ref mut map) => map.remove(key),
+        }
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a Vec<u8>, &'a IndexEntry)> + 'a> {
+        match *self {
+            IndexMap::Hashed(ref map) => Box::new(map.iter()),
+            IndexMap::Ordered(ref map) => Box::new(map.iter()),
+        }
+    }
+
+    fn range(&self, range: KeyRange, limit: usize) -> Vec<&Vec<u8>> {
+        match *self {
+            IndexMap::Hashed(ref map) => {
+                let mut keys: Vec<_> = map.keys()
+                    .filter(|key| range.contains(key.as_slice()))
+                    .collect();
+                keys.sort();
+                keys.truncate(limit);
+                keys
+            }
+            IndexMap::Ordered(ref map) => {
+                map.range::<[u8], _>(range)
+                    .take(limit)
+                    .map(|e| e.0)
+                    .collect()
+            }
+        }
+    }
+}
+
+/// A superseded version of a key that is retained while a snapshot can still see it.
+struct Version {
+    entry: IndexEntry,
+    /// Sequence number of the write that superseded this version.
+    until: SequenceNumber,
 }
 
 struct Index {
-    map: HashMap<Vec<u8>, IndexEntry>,
+    map: IndexMap,
     stats: Stats,
+    history: BTreeMap<Vec<u8>, Vec<Version>>,
+    snapshots: BTreeMap<SequenceNumber, usize>,
 }
 
 impl Index {
-    fn new() -> Index {
+    fn new(ordered: bool) -> Index {
         Index {
-            map: HashMap::new(),
+            map: if ordered {
+                IndexMap::Ordered(BTreeMap::new())
+            } else {
+                IndexMap::Hashed(HashMap::new())
+            },
             stats: Stats::new(),
+            history: BTreeMap::new(),
+            snapshots: BTreeMap::new(),
         }
     }
 
+    /// Returns the current version of `key`, unless it has expired.
     fn get(&self, key: &[u8]) -> Option<&IndexEntry> {
-        self.map.get(key)
+        self.map.get(key).filter(|entry| !entry.is_expired())
     }
 
-    fn insert(&mut self, key: Vec<u8>, index_entry: IndexEntry) -> Option<IndexEntry> {
+    /// Returns the version of `key` visible to a snapshot taken at `sequence`, i.e. the latest
+    /// version written before `sequence`.
+    fn get_at(&self, key: &[u8], sequence: SequenceNumber) -> Option<&IndexEntry> {
+        let entry = match self.map.get(key) {
+            Some(entry) if entry.sequence < sequence => Some(entry),
+            _ => {
+                self.history.get(key).and_then(|versions| {
+                    versions
+                        .iter()
+                        .find(|v| v.entry.sequence < sequence && sequence <= v.until)
+                        .map(|v| &v.entry)
+                })
+            }
+        };
+
+        // expiry isn't tied to sequence numbers, an expired version is gone for snapshots too
+        entry.filter(|entry| !entry.is_expired())
+    }
+
+    fn insert(&mut self, key: Vec<u8>, index_entry: IndexEntry) {
         self.stats.add_entry(&index_entry);
-        self.map.insert(key, index_entry).map(|entry| {
-            self.stats.remove_entry(&entry);
-            entry
-        })
+
+        let sequence = index_entry.sequence;
+        match self.map.get_mut(&key) {
+            Some(entry) => {
+                let old_entry = mem::replace(entry, index_entry);
+                self.supersede(&key, old_entry, sequence);
+            }
+            None => {
+                self.map.insert(key, index_entry);
+            }
+        }
     }
 
-    fn remove(&mut self, key: &[u8]) -> Option<IndexEntry> {
-        self.map.remove(key).map(|entry| {
-            self.stats.remove_entry(&entry);
-            entry
-        })
+    /// Removes `key` as of the write with the given `sequence`. Returns `false` if the key didn't
+    /// exist.
+    fn remove(&mut self, key: &[u8], sequence: SequenceNumber) -> bool {
+        match self.map.remove(key) {
+            Some(entry) => {
+                self.supersede(key, entry, sequence);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn supersede(&mut self, key: &[u8], entry: IndexEntry, until: SequenceNumber) {
+        self.stats.remove_entry(&entry);
+
+        if self.is_visible(&entry, until) {
+            self.history
+                .entry(key.to_vec())
+                .or_default()
+                .push(Version {
+                    entry: entry,
+                    until: until,
+                });
+        }
+    }
+
+    /// Returns `true` if a live snapshot was taken after `entry` was written and no later than
+    /// `until`.
+    fn is_visible(&self, entry: &IndexEntry, until: SequenceNumber) -> bool {
+        entry.sequence < until && self.snapshots.range(entry.sequence + 1..=until).next().is_some()
+    }
+
+    /// Returns `true` if a copy of the entry with the given `sequence`, wherever it's stored, is
+    /// the current version of `key` or is still visible to a snapshot.
+    fn has_version(&self, key: &[u8], sequence: SequenceNumber) -> bool {
+        self.map.get(key).is_some_and(|e| e.sequence == sequence) ||
+            self.history.get(key).is_some_and(|versions| {
+                versions.iter().any(|v| v.entry.sequence == sequence)
+            })
+    }
+
+    /// Returns `true` if the entry with the given `sequence` at `entry_pos` of data file `file_id`
+    /// is the current version of `key` or is still visible to a snapshot. Other copies of the
+    /// entry, e.g. left behind by a crash during compaction, aren't live.
+    fn is_live(
+        &self,
+        key: &[u8],
+        sequence: SequenceNumber,
+        file_id: u32,
+        entry_pos: u64,
+    ) -> bool {
+        let is_entry = |e: &IndexEntry| {
+            e.sequence == sequence && e.file_id == file_id && e.entry_pos == entry_pos
+        };
+
+        self.map.get(key).is_some_and(is_entry) ||
+            self.history.get(key).is_some_and(|versions| {
+                versions.iter().any(|v| is_entry(&v.entry))
+            })
+    }
+
+    fn add_snapshot(&mut self, sequence: SequenceNumber) {
+        *self.snapshots.entry(sequence).or_insert(0) += 1;
+    }
+
+    fn release_snapshot(&mut self, sequence: SequenceNumber) {
+        if let BTreeMapEntry::Occupied(mut o) = self.snapshots.entry(sequence) {
+            *o.get_mut() -= 1;
+            if *o.get() == 0 {
+                o.remove();
+            }
+        }
+
+        let snapshots = &self.snapshots;
+        self.history.retain(|_, versions| {
+            versions.retain(|v| {
+                snapshots.range(v.entry.sequence + 1..=v.until).next().is_some()
+            });
+            !versions.is_empty()
+        });
     }
 
     fn update(&mut self, hint: Hint, file_id: u32) {
@@ -63,34 +262,95 @@ impl Index {
             entry_pos: hint.entry_pos,
             entry_size: hint.entry_size(),
             sequence: hint.sequence,
+            expires: hint.expires,
         };
 
-        match self.map.entry(hint.key.to_vec()) {
-            HashMapEntry::Occupied(mut o) => {
-                if o.get().sequence <= hint.sequence {
-                    self.stats.remove_entry(o.get());
-                    if hint.deleted {
-                        o.remove();
-                    } else {
-                        self.stats.add_entry(&index_entry);
-                        o.insert(index_entry);
-                    }
-                } else {
-                    self.stats.add_entry(&index_entry);
-                    self.stats.remove_entry(&index_entry);
-                }
+        let current_sequence = self.map.get(&hint.key).map(|e| e.sequence);
+
+        match current_sequence {
+            Some(sequence) if sequence > hint.sequence => {
+                self.stats.add_entry(&index_entry);
+                self.stats.remove_entry(&index_entry);
             }
-            HashMapEntry::Vacant(e) => {
-                if !hint.deleted {
-                    self.stats.add_entry(&index_entry);
-                    e.insert(index_entry);
-                }
+            _ if hint.deleted => {
+                self.remove(&hint.key, hint.sequence);
+            }
+            _ if index_entry.is_expired() => {
+                // an expired entry acts as a tombstone, whose bytes are dead
+                self.stats.add_entry(&index_entry);
+                self.stats.remove_entry(&index_entry);
+                self.remove(&hint.key, hint.sequence);
+            }
+            _ => {
+                self.insert(hint.key.into_owned(), index_entry);
+            }
+        }
+    }
+
+    /// Removes all expired entries, accounting their bytes as dead.
+    fn expire(&mut self) {
+        let expired: Vec<_> = self.map
+            .iter()
+            .filter(|e| e.1.is_expired())
+            .map(|e| e.0.clone())
+            .collect();
+
+        for key in expired {
+            if let Some(entry) = self.map.remove(&key) {
+                self.stats.remove_entry(&entry);
+            }
+        }
+    }
+
+    /// Points the current or retained version of the key with the same sequence as `hint` to
+    /// its new location after compaction of `compacted_files`. Versions that were superseded or
+    /// released in the meantime, and copies of versions that live outside of the compacted files,
+    /// are accounted as dead.
+    fn relocate(&mut self, hint: Hint, file_id: u32, compacted_files: &[u32]) {
+        if hint.deleted {
+            return;
+        }
+
+        let index_entry = IndexEntry {
+            file_id: file_id,
+            entry_pos: hint.entry_pos,
+            entry_size: hint.entry_size(),
+            sequence: hint.sequence,
+            expires: hint.expires,
+        };
+
+        self.stats.add_entry(&index_entry);
+
+        let is_compacted = |e: &IndexEntry| {
+            e.sequence == hint.sequence && compacted_files.contains(&e.file_id)
+        };
+
+        if let Some(entry) = self.map.get_mut(&hint.key) {
+            if is_compacted(entry) {
+                *entry = index_entry;
+                return;
             }
         }
+
+        let version = self.history.get_mut(&*hint.key).and_then(|versions| {
+            versions.iter_mut().find(|v| is_compacted(&v.entry))
+        });
+
+        if let Some(version) = version {
+            version.entry = index_entry.clone();
+        }
+
+        self.stats.remove_entry(&index_entry);
     }
 
-    pub fn keys(&self) -> Keys<Vec<u8>, IndexEntry> {
-        self.map.keys()
+    pub fn keys<'a>(&'a self) -> Box<dyn Iterator<Item = &'a Vec<u8>> + 'a> {
+        Box::new(self.map.iter().filter(|e| !e.1.is_expired()).map(|e| e.0))
+    }
+
+    pub fn range(&self, range: KeyRange) -> Vec<&Vec<u8>> {
+        let mut keys = self.map.range(range, usize::MAX);
+        keys.retain(|key| self.get(key).is_some());
+        keys
     }
 }
 
@@ -98,58 +358,257 @@ struct CaskInner {
     current_sequence: SequenceNumber,
     index: Index,
     log: Log,
+    compression: Compression,
+    compression_threshold: usize,
+    blob_threshold: usize,
+    verify_range_reads: bool,
+    cache: ValueCache,
 }
 
 impl CaskInner {
     fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        let value = match self.index.get(key) {
-            Some(index_entry) => {
-                let entry = self.log.read_entry(
-                    index_entry.file_id,
-                    index_entry.entry_pos,
-                )?;
-                if entry.deleted {
-                    warn!(
-                        "Index pointed to dead entry: Entry {{ key: {:?}, sequence: {} }} at \
-                         file: {}",
-                        entry.key,
-                        entry.sequence,
-                        index_entry.file_id
-                    );
-                    None
-                } else {
-                    Some(entry.value.into_owned())
+        match self.index.get(key) {
+            Some(index_entry) => self.read_value(index_entry),
+            _ => Ok(None),
+        }
+    }
+
+    fn get_at(&self, key: &[u8], sequence: SequenceNumber) -> Result<Option<Vec<u8>>> {
+        match self.index.get_at(key, sequence) {
+            Some(index_entry) => self.read_value(index_entry),
+            _ => Ok(None),
+        }
+    }
+
+    fn get_reader(&self, key: &[u8]) -> Result<Option<ValueReader>> {
+        let entry = match self.index.get(key) {
+            Some(index_entry) => self.read_entry(index_entry)?,
+            _ => None,
+        };
+
+        Ok(match entry {
+            Some(entry) => {
+                match entry.blob_size() {
+                    Some(len) => {
+                        let blob_reader = self.log.blob_reader(entry.blob_sequence(), len)?;
+                        Some(ValueReader::blob(blob_reader))
+                    }
+                    None => Some(ValueReader::inline(entry.into_value()?)),
                 }
             }
-            _ => None,
+            None => None,
+        })
+    }
+
+    fn get_range(&self, key: &[u8], offset: u64, len: u64) -> Result<Option<Vec<u8>>> {
+        let index_entry = match self.index.get(key) {
+            Some(index_entry) => index_entry,
+            _ => return Ok(None),
+        };
+
+        if !self.verify_range_reads {
+            let value = self.log.read_value_range(
+                index_entry.file_id,
+                index_entry.entry_pos,
+                offset,
+                len,
+            )?;
+
+            if value.is_some() {
+                return Ok(value);
+            }
+        }
+
+        let entry = match self.read_entry(index_entry)? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+
+        let value = match entry.blob_size() {
+            Some(blob_size) => {
+                // only the chunks covering the range are read and verified
+                let mut blob_reader = self
+                    .log
+                    .blob_reader(entry.blob_sequence(), blob_size)?;
+                blob_reader.seek(SeekFrom::Start(offset))?;
+
+                let mut value = Vec::new();
+                blob_reader.take(len).read_to_end(&mut value)?;
+                value
+            }
+            None => {
+                let value = entry.into_value()?;
+                let start = cmp::min(offset, value.len() as u64) as usize;
+                let end = start + cmp::min(len, (value.len() - start) as u64) as usize;
+                value[start..end].to_vec()
+            }
+        };
+
+        Ok(Some(value))
+    }
+
+    fn read_value(&self, index_entry: &IndexEntry) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.cache.get(index_entry.file_id, index_entry.entry_pos) {
+            return Ok(Some(value));
+        }
+
+        let value = match self.read_entry(index_entry)? {
+            Some(entry) => {
+                match entry.blob_size() {
+                    Some(len) => {
+                        let mut value = Vec::with_capacity(len as usize);
+                        self.log.blob_reader(entry.blob_sequence(), len)?.read_to_end(
+                            &mut value,
+                        )?;
+                        Some(value)
+                    }
+                    None => Some(entry.into_value()?),
+                }
+            }
+            None => None,
         };
 
+        if let Some(ref value) = value {
+            self.cache.insert(index_entry.file_id, index_entry.entry_pos, value);
+        }
+
         Ok(value)
     }
 
-    fn put(&mut self, key: Vec<u8>, value: &[u8]) -> Result<()> {
+    /// Drops the cached value of the current version of `key`, which is about to be superseded.
+    fn invalidate(&self, key: &[u8]) {
+        if let Some(index_entry) = self.index.map.get(key) {
+            self.cache.remove(index_entry.file_id, index_entry.entry_pos);
+        }
+    }
+
+    fn read_entry<'a>(&self, index_entry: &IndexEntry) -> Result<Option<Entry<'a>>> {
+        let entry = self.log.read_entry(
+            index_entry.file_id,
+            index_entry.entry_pos,
+        )?;
+
+        if entry.deleted {
+            warn!(
+                "Index pointed to dead entry: Entry {{ key: {:?}, sequence: {} }} at file: {}",
+                entry.key,
+                entry.sequence,
+                index_entry.file_id
+            );
+            Ok(None)
+        } else {
+            Ok(Some(entry))
+        }
+    }
+
+    /// Returns `true` if a value of `len` bytes must be stored in a blob file instead of the log.
+    fn is_blob(&self, len: usize) -> Result<bool> {
+        if len > MAX_VALUE_SIZE as usize {
+            return Err(Error::InvalidValueSize(len));
+        }
+
+        // values too large for an entry are stored in blob files regardless of the threshold
+        Ok(len >= self.blob_threshold || len > MAX_INLINE_VALUE_SIZE as usize)
+    }
+
+    fn put(&mut self, key: Vec<u8>, value: &[u8], expires: Option<u64>) -> Result<()> {
+        if self.is_blob(value.len())? {
+            let sequence = self.current_sequence;
+            let mut blob_writer = self.log.blob_writer(sequence)?;
+            blob_writer.write(value)?;
+
+            let blob_file_path = blob_writer.path().to_path_buf();
+            let len = blob_writer.finish()?;
+
+            return self.put_blob(key, &blob_file_path, len, sequence, expires);
+        }
+
+        let index_entry = {
+            let mut entry = Entry::new(self.current_sequence, &*key, value)?;
+            entry.expires = expires;
+            entry.compress(self.compression, self.compression_threshold)?;
+
+            self.append(&entry)?
+        };
+
+        self.invalidate(&key);
+        self.index.insert(key, index_entry);
+
+        Ok(())
+    }
+
+    /// Inserts a value of `len` bytes that was written to the blob file at `blob_file_path` for
+    /// `blob_sequence`.
+    fn put_blob(
+        &mut self,
+        key: Vec<u8>,
+        blob_file_path: &Path,
+        len: u64,
+        blob_sequence: SequenceNumber,
+        expires: Option<u64>,
+    ) -> Result<()> {
+        let sequence = self.current_sequence;
+
         let index_entry = {
-            let entry = Entry::new(self.current_sequence, &*key, value)?;
+            let entry = Entry::blob(sequence, &*key, len, blob_sequence).map(|mut entry| {
+                entry.expires = expires;
+                entry
+            });
 
-            let (file_id, file_pos) = self.log.append_entry(&entry)?;
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    let _ = fs::remove_file(blob_file_path);
+                    return Err(err);
+                }
+            };
 
-            self.current_sequence += 1;
+            self.log.commit_blob(blob_file_path, blob_sequence)?;
 
-            IndexEntry {
-                file_id: file_id,
-                entry_pos: file_pos,
-                entry_size: entry.size(),
-                sequence: entry.sequence,
+            match self.append(&entry) {
+                Ok(index_entry) => index_entry,
+                Err(err) => {
+                    let _ = self.log.remove_blob(blob_sequence);
+                    return Err(err);
+                }
             }
         };
 
+        self.invalidate(&key);
         self.index.insert(key, index_entry);
 
         Ok(())
     }
 
+    /// Writes `value` to the blob file of the entry with `sequence`.
+    fn write_blob(&self, sequence: SequenceNumber, value: &[u8]) -> Result<()> {
+        let mut blob_writer = self.log.blob_writer(sequence)?;
+        blob_writer.write(value)?;
+
+        let blob_file_path = blob_writer.path().to_path_buf();
+        blob_writer.finish()?;
+
+        self.log.commit_blob(&blob_file_path, sequence)
+    }
+
+    fn append(&mut self, entry: &Entry) -> Result<IndexEntry> {
+        let (file_id, file_pos) = self.log.append_entry(entry)?;
+
+        self.current_sequence += 1;
+
+        Ok(IndexEntry {
+            file_id: file_id,
+            entry_pos: file_pos,
+            entry_size: entry.size(),
+            sequence: entry.sequence,
+            expires: entry.expires,
+        })
+    }
+
     fn delete(&mut self, key: &[u8]) -> Result<()> {
-        if self.index.remove(key).is_some() {
+        self.invalidate(key);
+
+        if self.index.remove(key, self.current_sequence) {
             let entry = Entry::deleted(self.current_sequence, key);
             self.log.append_entry(&entry)?;
             self.current_sequence += 1;
@@ -158,44 +617,483 @@ impl CaskInner {
         Ok(())
     }
 
-    pub fn keys(&self) -> Keys<Vec<u8>, IndexEntry> {
+    fn compare_and_swap(
+        &mut self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool> {
+        if self.get(key)?.as_deref() != expected {
+            return Ok(false);
+        }
+
+        match new {
+            Some(value) => self.put(key.to_vec(), value, None)?,
+            None => self.delete(key)?,
+        }
+
+        Ok(true)
+    }
+
+    fn write(&mut self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let sequence = self.current_sequence;
+
+        // the blobs of the batch are moved in place before it's appended, and removed if it fails
+        let mut blobs = Vec::new();
+
+        let res = batch
+            .ops
+            .iter()
+            .zip(sequence..)
+            .map(|(op, sequence)| match *op {
+                BatchOp::Put(ref key, ref value) => {
+                    if self.is_blob(value.len())? {
+                        self.write_blob(sequence, value)?;
+                        blobs.push(sequence);
+                        return Entry::blob(sequence, &**key, value.len() as u64, sequence);
+                    }
+
+                    let mut entry = Entry::new(sequence, &**key, &**value)?;
+                    entry.compress(self.compression, self.compression_threshold)?;
+                    Ok(entry)
+                }
+                BatchOp::Delete(ref key) => Ok(Entry::deleted(sequence, &**key)),
+            })
+            .collect::<Result<Vec<_>>>()
+            .and_then(|entries| {
+                let entry_positions = self.log.append_batch(sequence, &entries)?;
+                Ok((entries, entry_positions))
+            });
+
+        let (entries, entry_positions) = match res {
+            Ok(res) => res,
+            Err(err) => {
+                for sequence in blobs {
+                    let _ = self.log.remove_blob(sequence);
+                }
+                return Err(err);
+            }
+        };
+
+        self.current_sequence += entries.len() as u64;
+
+        for (entry, (file_id, entry_pos)) in entries.iter().zip(entry_positions) {
+            self.invalidate(&entry.key);
+
+            if entry.deleted {
+                self.index.remove(&entry.key, entry.sequence);
+            } else {
+                let index_entry = IndexEntry {
+                    file_id: file_id,
+                    entry_pos: entry_pos,
+                    entry_size: entry.size(),
+                    sequence: entry.sequence,
+                    expires: entry.expires,
+                };
+
+                self.index.insert(entry.key.to_vec(), index_entry);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn keys<'a>(&'a self) -> Box<dyn Iterator<Item = &'a Vec<u8>> + 'a> {
         self.index.keys()
     }
+
+    fn range_values(&self, range: KeyRange) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut values = Vec::new();
+
+        for key in self.index.range(range) {
+            if let Some(value) = self.get(key)? {
+                values.push((key.clone(), value));
+            }
+        }
+
+        Ok(values)
+    }
 }
 
-/// An handle to a `Cask` database.
-///
-/// This handle can be "cheaply" cloned and safely shared between threads. `Cask`s cannot be used
-/// concurrently by separate processes and this is ensured by using a file lock in the `Cask` dir.
-#[derive(Clone)]
-pub struct Cask {
-    path: PathBuf,
-    options: CaskOptions,
-    dropped: Arc<AtomicBool>,
-    inner: Arc<RwLock<CaskInner>>,
-    compaction: Arc<Mutex<()>>,
+const ITER_CHUNK_SIZE: usize = 1024;
+
+enum IterKeys {
+    /// Resume after the last key returned, only possible with an ordered index.
+    After(Option<Vec<u8>>),
+    /// Keys copied out of an unordered index when the iteration started.
+    Snapshot(vec::IntoIter<Vec<u8>>),
 }
 
-/// `Cask` configuration. Provides control over the properties and behavior of the `Cask` instance.
-///
-/// # Examples
-///
-/// ```rust,no_run
-/// use cask::{CaskOptions, SyncStrategy};
-///
-/// let cask = CaskOptions::default()
-///     .compaction_check_frequency(1200)
-///     .sync(SyncStrategy::Never)
-///     .max_file_size(1024 * 1024 * 1024)
-///     .open("cask.db")
-///     .unwrap();
-/// ```
-#[derive(Clone)]
-pub struct CaskOptions {
-    create: bool,
+impl IterKeys {
+    /// `history` also includes the keys with retained versions, which are needed when iterating
+    /// over a snapshot.
+    fn new(index: &Index, history: bool) -> IterKeys {
+        match index.map {
+            IndexMap::Ordered(_) => IterKeys::After(None),
+            IndexMap::Hashed(ref map) => {
+                let mut keys: Vec<_> = map.keys().cloned().collect();
+                if history {
+                    keys.extend(
+                        index
+                            .history
+                            .keys()
+                            .filter(|key| !map.contains_key(*key))
+                            .cloned(),
+                    );
+                }
+                IterKeys::Snapshot(keys.into_iter())
+            }
+        }
+    }
+
+    fn next_keys(&mut self, index: &Index, limit: usize, history: bool) -> Vec<Vec<u8>> {
+        match *self {
+            IterKeys::After(ref mut last) => {
+                let keys: Vec<_> = {
+                    let start = match *last {
+                        Some(ref key) => Bound::Excluded(key.as_slice()),
+                        None => Bound::Unbounded,
+                    };
+                    let range = (start, Bound::Unbounded);
+
+                    let mut keys: Vec<_> = index
+                        .map
+                        .range(range, limit)
+                        .into_iter()
+                        .cloned()
+                        .collect();
+
+                    if history {
+                        keys.extend(
+                            index
+                                .history
+                                .range::<[u8], _>(range)
+                                .take(limit)
+                                .map(|e| e.0.clone()),
+                        );
+                        keys.sort();
+                        keys.dedup();
+                        keys.truncate(limit);
+                    }
+
+                    keys
+                };
+
+                if let Some(key) = keys.last() {
+                    *last = Some(key.clone());
+                }
+
+                keys
+            }
+            IterKeys::Snapshot(ref mut keys) => keys.take(limit).collect(),
+        }
+    }
+}
+
+struct IterCursor {
+    inner: Arc<RwLock<CaskInner>>,
+    keys: IterKeys,
+    /// Sequence number of the snapshot being iterated, if any.
+    sequence: Option<SequenceNumber>,
+    done: bool,
+}
+
+impl IterCursor {
+    fn new(inner: &Arc<RwLock<CaskInner>>, sequence: Option<SequenceNumber>) -> IterCursor {
+        let keys = IterKeys::new(&inner.read().unwrap().index, sequence.is_some());
+
+        IterCursor {
+            inner: inner.clone(),
+            keys: keys,
+            sequence: sequence,
+            done: false,
+        }
+    }
+
+    /// Maps the next chunk of keys with `f` while holding the read lock. Returns `None` once all
+    /// keys have been visited.
+    fn next_chunk<T, F>(&mut self, mut f: F) -> Option<Result<Vec<T>>>
+    where
+        F: FnMut(&CaskInner, Vec<u8>) -> Result<Option<T>>,
+    {
+        if self.done {
+            return None;
+        }
+
+        let inner = self.inner.read().unwrap();
+        let keys = self.keys.next_keys(
+            &inner.index,
+            ITER_CHUNK_SIZE,
+            self.sequence.is_some(),
+        );
+
+        if keys.is_empty() {
+            self.done = true;
+            return None;
+        }
+
+        let mut chunk = Vec::with_capacity(keys.len());
+        for key in keys {
+            match f(&inner, key) {
+                Ok(Some(item)) => chunk.push(item),
+                Ok(None) => {}
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+
+        Some(Ok(chunk))
+    }
+}
+
+/// An iterator over the key-value pairs of a `Cask`, created by `Cask::iter`.
+///
+/// Entries are read in chunks, each under a short-lived read lock, so that writes and compaction
+/// can proceed during the iteration. Every key that exists for the whole iteration is returned
+/// exactly once, with the value it had when its chunk was read. Keys inserted or deleted during
+/// the iteration may or may not be returned.
+///
+/// With an ordered index keys are returned in lexicographic order and only the current chunk is
+/// kept in memory. Otherwise all keys are copied out of the index when the iteration starts and
+/// keys inserted afterwards are never returned.
+///
+/// An iterator created by `Snapshot::iter` returns the key-value pairs as of the snapshot instead.
+pub struct Iter {
+    cursor: IterCursor,
+    chunk: vec::IntoIter<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Iterator for Iter {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
+        loop {
+            if let Some(item) = self.chunk.next() {
+                return Some(Ok(item));
+            }
+
+            let sequence = self.cursor.sequence;
+            let chunk = self.cursor.next_chunk(|inner, key| {
+                let value = match sequence {
+                    Some(sequence) => inner.get_at(&key, sequence)?,
+                    None => inner.get(&key)?,
+                };
+                Ok(value.map(|value| (key, value)))
+            });
+
+            match chunk {
+                Some(Ok(chunk)) => self.chunk = chunk.into_iter(),
+                Some(Err(err)) => return Some(Err(err)),
+                None => return None,
+            }
+        }
+    }
+}
+
+/// An iterator over the keys of a `Cask`, created by `Cask::keys_iter`.
+///
+/// Follows the same semantics as `Iter`.
+pub struct KeysIter {
+    cursor: IterCursor,
+    chunk: vec::IntoIter<Vec<u8>>,
+}
+
+impl Iterator for KeysIter {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        loop {
+            if let Some(key) = self.chunk.next() {
+                return Some(key);
+            }
+
+            let chunk = self.cursor.next_chunk(|inner, key| {
+                Ok(if inner.index.get(&key).is_some() {
+                    Some(key)
+                } else {
+                    None
+                })
+            });
+
+            match chunk {
+                Some(Ok(chunk)) => self.chunk = chunk.into_iter(),
+                _ => return None,
+            }
+        }
+    }
+}
+
+/// A consistent, read-only view of a `Cask` as of the moment it was created, created by
+/// `Cask::snapshot`.
+///
+/// Writes made after the snapshot was created are not visible through it. The versions it can
+/// see are retained in memory and survive compaction until the snapshot is dropped, so
+/// long-lived snapshots prevent the space used by overwritten and deleted keys from being
+/// reclaimed.
+pub struct Snapshot {
+    inner: Arc<RwLock<CaskInner>>,
+    sequence: SequenceNumber,
+}
+
+impl Snapshot {
+    /// Returns the sequence number of the snapshot. Only writes with a lower sequence number are
+    /// visible.
+    pub fn sequence(&self) -> SequenceNumber {
+        self.sequence
+    }
+
+    /// Returns the value corresponding to the key as of the snapshot, if any.
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>> {
+        self.inner.read().unwrap().get_at(
+            key.as_ref(),
+            self.sequence,
+        )
+    }
+
+    /// Returns an iterator over all key-value pairs stored in the map as of the snapshot.
+    pub fn iter(&self) -> Iter {
+        Iter {
+            cursor: IterCursor::new(&self.inner, Some(self.sequence)),
+            chunk: Vec::new().into_iter(),
+        }
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.inner.write().unwrap().index.release_snapshot(
+            self.sequence,
+        );
+    }
+}
+
+/// A set of reads and writes that is committed atomically, created by `Cask::transaction`.
+///
+/// Writes are buffered until the transaction commits and are visible to its own reads. The
+/// sequence number of every key read is recorded, and the transaction fails with
+/// `Error::TransactionConflict` if any of them was modified by the time it commits.
+pub struct Transaction {
+    inner: Arc<RwLock<CaskInner>>,
+    reads: HashMap<Vec<u8>, Option<SequenceNumber>>,
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl Transaction {
+    /// Returns the value corresponding to the key, if any.
+    pub fn get<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Option<Vec<u8>>> {
+        let key = key.as_ref();
+
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+
+        let inner = self.inner.read().unwrap();
+        let (sequence, value) = match inner.index.get(key) {
+            Some(index_entry) => (Some(index_entry.sequence), inner.read_value(index_entry)?),
+            None => (None, None),
+        };
+
+        // keep the first version read, so that a key modified between two reads conflicts
+        self.reads.entry(key.to_vec()).or_insert(sequence);
+
+        Ok(value)
+    }
+
+    /// Inserts a key-value pair into the map when the transaction commits.
+    pub fn put<K: Into<Vec<u8>>, V: Into<Vec<u8>>>(&mut self, key: K, value: V) {
+        self.writes.insert(key.into(), Some(value.into()));
+    }
+
+    /// Removes a key from the map when the transaction commits.
+    pub fn delete<K: Into<Vec<u8>>>(&mut self, key: K) {
+        self.writes.insert(key.into(), None);
+    }
+
+    fn commit(self) -> Result<()> {
+        let reads = self.reads;
+        let writes = self.writes;
+
+        write_durably(&self.inner, false, |inner| {
+            for (key, &sequence) in &reads {
+                if inner.index.get(key).map(|e| e.sequence) != sequence {
+                    return Err(Error::TransactionConflict);
+                }
+            }
+
+            let mut batch = WriteBatch::new();
+            for (key, value) in writes {
+                match value {
+                    Some(value) => batch.put(key, value),
+                    None => batch.delete(key),
+                };
+            }
+
+            inner.write(batch)
+        })
+    }
+}
+
+/// Runs `f` with exclusive access to `inner` and, if writes are synchronized or `sync` is set,
+/// waits until the writes it appended are durable. The wait happens after releasing `inner`, so
+/// that concurrent writes can be synchronized together.
+fn write_durably<T, F>(inner: &RwLock<CaskInner>, sync: bool, f: F) -> Result<T>
+where
+    F: FnOnce(&mut CaskInner) -> Result<T>,
+{
+    let (res, commit) = {
+        let mut inner = inner.write().unwrap();
+        let res = f(&mut inner)?;
+        (res, inner.log.last_commit(sync))
+    };
+
+    if let Some(commit) = commit {
+        commit.wait()?;
+    }
+
+    Ok(res)
+}
+
+/// An handle to a `Cask` database.
+///
+/// This handle can be "cheaply" cloned and safely shared between threads. `Cask`s cannot be used
+/// concurrently by separate processes and this is ensured by using a file lock in the `Cask` dir.
+#[derive(Clone)]
+pub struct Cask {
+    path: PathBuf,
+    options: CaskOptions,
+    dropped: Arc<AtomicBool>,
+    report: Arc<RecoveryReport>,
+    inner: Arc<RwLock<CaskInner>>,
+    compaction: Arc<Mutex<()>>,
+}
+
+/// `Cask` configuration. Provides control over the properties and behavior of the `Cask` instance.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use cask::{CaskOptions, SyncStrategy};
+///
+/// let cask = CaskOptions::default()
+///     .compaction_check_frequency(1200)
+///     .sync(SyncStrategy::Never)
+///     .max_file_size(1024 * 1024 * 1024)
+///     .open("cask.db")
+///     .unwrap();
+/// ```
+#[derive(Clone)]
+pub struct CaskOptions {
+    create: bool,
     sync: SyncStrategy,
-    max_file_size: usize,
-    file_pool_size: usize,
+    pub(crate) max_file_size: usize,
+    pub(crate) checkpoint_interval: u64,
+    pub(crate) file_pool_size: usize,
     compaction: bool,
     compaction_check_frequency: u64,
     compaction_window: (usize, usize),
@@ -204,25 +1102,102 @@ pub struct CaskOptions {
     fragmentation_threshold: f64,
     dead_bytes_threshold: u64,
     small_file_threshold: u64,
+    corruption_policy: CorruptionPolicy,
+    ordered_index: bool,
+    compression: Compression,
+    compression_threshold: usize,
+    blob_threshold: usize,
+    verify_range_reads: bool,
+    mmap: bool,
+    cache_size: usize,
+    pub(crate) keyring: Keyring,
+}
+
+/// Returns the sequence numbers of the blobs referenced by the entries at `blob_entries`.
+fn referenced_blobs(log: &Log, blob_entries: &[(u32, u64)]) -> Result<HashSet<SequenceNumber>> {
+    blob_entries
+        .iter()
+        .map(|&(file_id, entry_pos)| {
+            Ok(log.read_entry(file_id, entry_pos)?.blob_sequence())
+        })
+        .collect()
 }
 
 /// Strategy used to synchronize writes to disk.
 #[derive(Clone, PartialEq)]
 pub enum SyncStrategy {
-    /// Never explicitly synchronize writes (the OS manages it).
+    /// Never explicitly synchronize writes (the OS manages it), other than when a data file is
+    /// closed or a checkpoint of its hints is written, see `CaskOptions::checkpoint_interval`.
     Never,
-    /// Always synchronize writes.
+    /// Always synchronize writes, each write returns once it's durable. Concurrent writes are
+    /// synchronized together by a single `fsync`.
     Always,
     /// Synchronize writes in the background every `n` milliseconds.
     Interval(usize),
 }
 
+/// Options for a single write.
+#[derive(Clone, Debug, Default)]
+pub struct WriteOptions {
+    /// Wait until the write is durable before returning, regardless of the `SyncStrategy`.
+    /// Defaults to `false`.
+    pub sync: bool,
+}
+
+/// Algorithm used to compress values.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Compression {
+    /// Store values uncompressed.
+    None,
+    /// Compress values with LZ4, which is fast but has a lower compression ratio.
+    Lz4,
+    /// Compress values with Zstandard, which is slower but has a higher compression ratio.
+    Zstd,
+}
+
+/// Policy applied when corrupt entries are found while rebuilding the index of a data file with a
+/// missing or invalid hint file.
+///
+/// A torn write at the tail of the data file that was still being written when the process
+/// crashed is always truncated, regardless of this policy. Corrupt data anywhere else, including
+/// at the tail of any other data file, is never truncated. Only the entries past the last valid
+/// checkpoint of the hint file are read again, see `verify` for checking the whole file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CorruptionPolicy {
+    /// Fail to open the `Cask`.
+    Fail,
+    /// Skip the corrupt data and resume at the next valid entry in the file.
+    SkipEntry,
+    /// Move the whole data file to the `corrupt` directory inside the `Cask` dir and ignore it.
+    QuarantineFile,
+}
+
+/// Report of the corrupt data that was discarded while opening a `Cask`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RecoveryReport {
+    /// Data files that had a torn write at their tail truncated, along with the number of bytes
+    /// discarded.
+    pub truncated: Vec<(u32, u64)>,
+    /// Corrupt byte ranges `(file_id, start, end)` skipped with `CorruptionPolicy::SkipEntry`.
+    pub skipped: Vec<(u32, u64, u64)>,
+    /// Data files moved to the `corrupt` directory with `CorruptionPolicy::QuarantineFile`.
+    pub quarantined: Vec<u32>,
+}
+
+impl RecoveryReport {
+    /// Returns `true` if no data was discarded.
+    pub fn is_clean(&self) -> bool {
+        self.truncated.is_empty() && self.skipped.is_empty() && self.quarantined.is_empty()
+    }
+}
+
 impl Default for CaskOptions {
     fn default() -> CaskOptions {
         CaskOptions {
             create: true,
             sync: SyncStrategy::Interval(1000),
             max_file_size: 2 * 1024 * 1024 * 1024,
+            checkpoint_interval: 1024 * 1024,
             file_pool_size: 2048,
             compaction: true,
             compaction_check_frequency: 3600,
@@ -232,6 +1207,15 @@ impl Default for CaskOptions {
             fragmentation_threshold: 0.4,
             dead_bytes_threshold: 128 * 1024 * 1024,
             small_file_threshold: 10 * 1024 * 1024,
+            corruption_policy: CorruptionPolicy::Fail,
+            ordered_index: false,
+            compression: Compression::None,
+            compression_threshold: 1024,
+            blob_threshold: 1024 * 1024,
+            verify_range_reads: true,
+            mmap: false,
+            cache_size: 0,
+            keyring: Keyring::default(),
         }
     }
 }
@@ -257,6 +1241,18 @@ impl CaskOptions {
         self
     }
 
+    /// Sets the number of bytes written to a data file between the checkpoints of its hint file.
+    /// Defaults to `1MB`.
+    ///
+    /// Only the entries written after the last checkpoint of the data file that was being
+    /// written need to be read again when recovering from a crash. Each checkpoint synchronizes
+    /// the data file to disk, also with `SyncStrategy::Never`, and the hints written since the
+    /// last one are kept in memory until then.
+    pub fn checkpoint_interval(&mut self, checkpoint_interval: u64) -> &mut CaskOptions {
+        self.checkpoint_interval = checkpoint_interval;
+        self
+    }
+
     /// Sets the maximum size of the file descriptor cache. Defaults to `2048`.
     pub fn file_pool_size(&mut self, file_pool_size: usize) -> &mut CaskOptions {
         self.file_pool_size = file_pool_size;
@@ -324,6 +1320,82 @@ impl CaskOptions {
         self
     }
 
+    /// Sets the policy applied when corrupt entries are found while opening the `Cask`. Defaults
+    /// to `CorruptionPolicy::Fail`.
+    pub fn corruption_policy(&mut self, corruption_policy: CorruptionPolicy) -> &mut CaskOptions {
+        self.corruption_policy = corruption_policy;
+        self
+    }
+
+    /// Keep the keys in the index sorted, which makes `Cask::range` and `Cask::prefix` efficient
+    /// at the cost of slower lookups. Defaults to `false`.
+    pub fn ordered_index(&mut self, ordered_index: bool) -> &mut CaskOptions {
+        self.ordered_index = ordered_index;
+        self
+    }
+
+    /// Sets the algorithm used to compress values on write. Values are always decompressed
+    /// according to how they were written, so the algorithm can be changed between opens.
+    /// Defaults to `Compression::None`.
+    pub fn compression(&mut self, compression: Compression) -> &mut CaskOptions {
+        self.compression = compression;
+        self
+    }
+
+    /// Sets the minimum size of values that are compressed. Defaults to `1KB`.
+    pub fn compression_threshold(&mut self, compression_threshold: usize) -> &mut CaskOptions {
+        self.compression_threshold = compression_threshold;
+        self
+    }
+
+    /// Sets the minimum size of values that are stored in their own blob file instead of the log,
+    /// which keeps compaction from copying them and allows streaming them with `Cask::get_reader`
+    /// and `Cask::put_reader`. Defaults to `1MB`.
+    pub fn blob_threshold(&mut self, blob_threshold: usize) -> &mut CaskOptions {
+        self.blob_threshold = blob_threshold;
+        self
+    }
+
+    /// Validate the checksum of the whole entry when reading part of a value stored in the log
+    /// with `Cask::get_range`. When disabled only the requested range is read, unless the value is
+    /// compressed or encrypted. Values stored in blob files are always verified one chunk at a
+    /// time. Defaults to `true`.
+    pub fn verify_range_reads(&mut self, verify_range_reads: bool) -> &mut CaskOptions {
+        self.verify_range_reads = verify_range_reads;
+        self
+    }
+
+    /// Memory-map the data files that are no longer written to and serve reads from the mappings
+    /// instead of the file descriptor cache. Defaults to `false`.
+    pub fn mmap(&mut self, mmap: bool) -> &mut CaskOptions {
+        self.mmap = mmap;
+        self
+    }
+
+    /// Sets the maximum number of bytes of values kept in memory to serve repeated reads of the
+    /// same keys, evicting the least recently read ones first. Defaults to `0`, which disables the
+    /// cache.
+    pub fn cache_size(&mut self, cache_size: usize) -> &mut CaskOptions {
+        self.cache_size = cache_size;
+        self
+    }
+
+    /// Encrypts new data and hint files with the 256-bit `key` using XChaCha20-Poly1305. The
+    /// `key_id` is stored in the header of each encrypted file to find the key needed to read it.
+    /// Files that aren't encrypted with this key, including the ones written before encryption
+    /// was enabled, trigger a compaction which rewrites them with it. Disabled by default.
+    pub fn encryption_key(&mut self, key_id: u32, key: [u8; 32]) -> &mut CaskOptions {
+        self.keyring.add(key_id, key, true);
+        self
+    }
+
+    /// Adds a key that is only used to read the files that were encrypted with it, e.g. after
+    /// rotating to a new key with `encryption_key`.
+    pub fn decryption_key(&mut self, key_id: u32, key: [u8; 32]) -> &mut CaskOptions {
+        self.keyring.add(key_id, key, false);
+        self
+    }
+
     /// Opens/creates a `Cask` at `path`.
     pub fn open(&self, path: &str) -> Result<Cask> {
         Cask::open(path, self.clone())
@@ -340,46 +1412,199 @@ impl Cask {
             options.sync == SyncStrategy::Always,
             options.max_file_size,
             options.file_pool_size,
+            options.keyring.clone(),
+            options.mmap,
         )?;
-        let mut index = Index::new();
+        log.set_checkpoint_interval(options.checkpoint_interval);
+        let mut index = Index::new(options.ordered_index);
 
         let mut sequence = 0;
+        let mut report = RecoveryReport::default();
+
+        let files = log.files();
+
+        let quarantine = options.corruption_policy == CorruptionPolicy::QuarantineFile;
+
+        // data files are synchronized before their hint file is completed, so the only data file
+        // whose last write may have been torn by a crash is the one that was still being written,
+        // i.e. the newest one that wasn't closed. Compaction output is only moved in place once
+        // it's closed. Data files written before checkpoints are older than any written since,
+        // and only the newest of them can have been left open.
+        let mut active_file_id = None;
+        for &file_id in files.iter().rev() {
+            if !log.is_sealed(file_id)? {
+                active_file_id = Some(file_id);
+                break;
+            }
+
+            if log.file_header(file_id)?.version < CHECKPOINTS_VERSION {
+                break;
+            }
+        }
 
-        for file_id in log.files() {
-            let mut f = |hint: Hint| {
+        // positions of the entries whose values are stored in blob files
+        let mut blob_entries = Vec::new();
+
+        for file_id in files {
+            let mut apply = |hint: Hint| {
                 if hint.sequence > sequence {
                     sequence = hint.sequence;
                 }
 
+                if hint.blob {
+                    blob_entries.push((file_id, hint.entry_pos));
+                }
+
                 index.update(hint, file_id);
             };
 
-            match log.hints(file_id)? {
-                Some(hints) => {
-                    for hint in hints {
-                        f(hint?);
+            // with `QuarantineFile` the hints are only applied once the whole file is known to be
+            // valid, they're applied as they're read otherwise
+            let mut staged = Vec::new();
+            let mut quarantined = false;
+
+            {
+                let mut f = |hint| if quarantine {
+                    staged.push(hint);
+                } else {
+                    apply(hint);
+                };
+
+                // the data file is recovered from the last valid checkpoint of its hint file, if
+                // the hint file turns out to be invalid, or from its start if there's none
+                let recover_from = match log.hints(file_id)? {
+                    Some(mut hints) => {
+                        // hint files without checkpoints are only validated once fully read
+                        let legacy = !hints.has_checkpoints();
+                        let mut legacy_hints = Vec::new();
+                        let mut error = None;
+                        for hint in hints.by_ref() {
+                            match hint {
+                                Ok(hint) if legacy => legacy_hints.push(hint),
+                                Ok(hint) => f(hint),
+                                Err(err) => {
+                                    error = Some(err);
+                                    break;
+                                }
+                            }
+                        }
+
+                        match error {
+                            Some(err) => {
+                                warn!("Found invalid hint file of data file {}: {}", file_id, err);
+                                Some(hints.into_checkpoint())
+                            }
+                            None => {
+                                for hint in legacy_hints {
+                                    f(hint);
+                                }
+                                None
+                            }
+                        }
+                    }
+                    None => Some(None),
+                };
+
+                if let Some(checkpoint) = recover_from {
+                    // corruption anywhere else is left to the corruption policy. The entries
+                    // covered by the last valid checkpoint of the hint file were synchronized
+                    // before it was written and aren't read again.
+                    if active_file_id == Some(file_id) {
+                        let discarded = log.recover_tail(file_id, checkpoint.as_ref())?;
+                        if discarded > 0 {
+                            report.truncated.push((file_id, discarded));
+                        }
                     }
-                }
-                None => {
-                    for hint in log.recreate_hints(file_id)? {
-                        f(hint?);
+
+                    match options.corruption_policy {
+                        CorruptionPolicy::Fail => {
+                            for hint in log.recreate_hints(file_id, checkpoint)? {
+                                f(hint?);
+                            }
+                        }
+                        CorruptionPolicy::SkipEntry => {
+                            let mut hints = log.recreate_hints(file_id, checkpoint)?;
+                            while let Some(hint) = hints.next() {
+                                match hint {
+                                    Ok(hint) => f(hint),
+                                    Err(err) => {
+                                        let (start, end) = hints.skip_corrupt()?;
+                                        warn!(
+                                            "Skipped {} bytes of corrupt data at offset {} of \
+                                             data file {}: {}",
+                                            end - start,
+                                            start,
+                                            file_id,
+                                            err
+                                        );
+                                        report.skipped.push((file_id, start, end));
+                                    }
+                                }
+                            }
+                        }
+                        CorruptionPolicy::QuarantineFile => {
+                            for hint in log.recreate_hints(file_id, checkpoint)? {
+                                match hint {
+                                    Ok(hint) => f(hint),
+                                    Err(err) => {
+                                        warn!("Found corrupt data file {}: {}", file_id, err);
+                                        quarantined = true;
+                                    }
+                                }
+                            }
+
+                            if quarantined {
+                                log.quarantine(file_id)?;
+                                report.quarantined.push(file_id);
+                            }
+                        }
                     }
                 }
-            };
+            }
+
+            if !quarantined {
+                for hint in staged {
+                    apply(hint);
+                }
+            }
         }
 
+        // blobs of writes that didn't make it to the log, i.e. that no entry references. The
+        // entries of quarantined files can't all be read, so no blob is known to be unreferenced.
+        let referenced_blobs = if report.quarantined.is_empty() {
+            match referenced_blobs(&log, &blob_entries) {
+                Ok(referenced_blobs) => Some(referenced_blobs),
+                Err(err) => {
+                    warn!("Failed to read the entries referencing blobs: {}", err);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        remove_orphan_blobs(&log.path, sequence + 1, referenced_blobs.as_ref())?;
+        log.remove_unpublished_files()?;
+
         info!("Opened database: {:?}", &path);
         info!("Current sequence number: {:?}", sequence);
 
+        let inner = CaskInner {
+            current_sequence: sequence + 1,
+            log: log,
+            index: index,
+            compression: options.compression,
+            compression_threshold: options.compression_threshold,
+            blob_threshold: options.blob_threshold,
+            verify_range_reads: options.verify_range_reads,
+            cache: ValueCache::new(options.cache_size),
+        };
+
         let cask = Cask {
-            path: log.path.clone(),
+            path: inner.log.path.clone(),
             options: options,
             dropped: Arc::new(AtomicBool::new(false)),
-            inner: Arc::new(RwLock::new(CaskInner {
-                current_sequence: sequence + 1,
-                log: log,
-                index: index,
-            })),
+            report: Arc::new(report),
+            inner: Arc::new(RwLock::new(inner)),
             compaction: Arc::new(Mutex::new(())),
         };
 
@@ -448,7 +1673,12 @@ impl Cask {
         Ok(cask)
     }
 
-    fn compact_files_aux(&self, files: &[u32]) -> Result<(Vec<u32>, Vec<u32>)> {
+    /// Rewrites the live entries of `files`. Returns the compacted files, the new files and the
+    /// sequence numbers of the dropped entries whose blob files can be removed.
+    fn compact_files_aux(
+        &self,
+        files: &[u32],
+    ) -> Result<(Vec<u32>, Vec<u32>, Vec<SequenceNumber>)> {
         let active_file_id = {
             self.inner.read().unwrap().log.active_file_id
         };
@@ -470,10 +1700,11 @@ impl Cask {
         let mut compacted_files = Vec::new();
         let mut new_files = Vec::new();
         let mut deletes = HashMap::new();
+        // blobs of the entries that are dropped
+        let mut blobs = Vec::new();
 
         let mut log_writer = {
-            // FIXME: turn into error
-            self.inner.read().unwrap().log.writer()
+            self.inner.read().unwrap().log.writer()?
         };
 
         for (file_id, hints) in compacted_files_hints {
@@ -482,10 +1713,15 @@ impl Cask {
             for hint in hints {
                 let hint = hint?;
                 let inner = self.inner.read().unwrap();
-                let index_entry = inner.index.get(&*hint.key);
 
-                if hint.deleted {
-                    if index_entry.is_none() {
+                // expired entries are dropped like tombstones, a tombstone is still needed to
+                // shadow older versions of the key in files that aren't being compacted
+                if hint.deleted || hint.is_expired() {
+                    if hint.blob {
+                        blobs.push(inner.log.read_entry(file_id, hint.entry_pos)?.blob_sequence());
+                    }
+
+                    if inner.index.get(&hint.key).is_none() {
                         match deletes.entry(hint.key.to_vec()) {
                             HashMapEntry::Occupied(mut o) => {
                                 if *o.get() < hint.sequence {
@@ -497,17 +1733,36 @@ impl Cask {
                             }
                         }
                     }
-                } else if index_entry.is_some() && index_entry.unwrap().sequence == hint.sequence {
+                } else if inner.index.is_live(
+                    &hint.key,
+                    hint.sequence,
+                    file_id,
+                    hint.entry_pos,
+                )
+                {
+                    // also keeps superseded versions that are still visible to a snapshot
                     inserts.push(hint)
+                } else if hint.blob && !inner.index.has_version(&hint.key, hint.sequence) {
+                    // the blob is still used by the live copy of an entry duplicated by a crash
+                    // during compaction. It may be bound to a sequence number other than the
+                    // entry's.
+                    blobs.push(inner.log.read_entry(file_id, hint.entry_pos)?.blob_sequence());
                 }
             }
 
             for hint in inserts {
                 // FIXME: turn into error
                 let log = &self.inner.read().unwrap().log;
-                let log_write = log_writer.write(&log.read_entry(file_id, hint.entry_pos)?)?;
+                let entry = log.read_entry(file_id, hint.entry_pos)?;
+
+                // blobs aren't copied, but must be encrypted with the same key as their entry
+                if let Some(len) = entry.blob_size() {
+                    log.rotate_blob(entry.blob_sequence(), len)?;
+                }
 
-                if let LogWrite::NewFile(file_id) = log_write {
+                let log_write = log_writer.write(&entry)?;
+
+                if let LogWrite::NewFile(file_id, _) = log_write {
                     new_files.push(file_id);
                 }
             }
@@ -516,16 +1771,26 @@ impl Cask {
         }
 
         for (key, sequence) in deletes {
-            log_writer.write(&Entry::deleted(sequence, key))?;
+            let log_write = log_writer.write(&Entry::deleted(sequence, key))?;
+
+            if let LogWrite::NewFile(file_id, _) = log_write {
+                new_files.push(file_id);
+            }
         }
 
-        Ok((compacted_files, new_files))
+        log_writer.finish()?;
+
+        Ok((compacted_files, new_files, blobs))
     }
 
     fn compact_files(&self, files: &[u32]) -> Result<()> {
         info!("Compacting data files: {:?}", files);
 
-        let (ref compacted_files, ref new_files) = self.compact_files_aux(files)?;
+        let (ref compacted_files, ref new_files, ref blobs) = self.compact_files_aux(files)?;
+
+        // the new files must be complete before the compacted ones are removed, and both are
+        // found when the log is opened again in the meantime, which only duplicates live entries
+        self.inner.read().unwrap().log.publish_files(new_files)?;
 
         for &file_id in new_files {
             let hints = {
@@ -535,7 +1800,11 @@ impl Cask {
             if let Some(hints) = hints {
                 for hint in hints {
                     let hint = hint?;
-                    self.inner.write().unwrap().index.update(hint, file_id);
+                    self.inner.write().unwrap().index.relocate(
+                        hint,
+                        file_id,
+                        compacted_files,
+                    );
                 }
             };
         }
@@ -544,10 +1813,15 @@ impl Cask {
             compacted_files,
         );
 
-        self.inner.write().unwrap().log.swap_files(
-            compacted_files,
-            new_files,
-        )?;
+        {
+            let mut inner = self.inner.write().unwrap();
+            inner.log.swap_files(compacted_files, new_files)?;
+            inner.cache.remove_files(compacted_files);
+        }
+
+        for &sequence in blobs {
+            self.inner.read().unwrap().log.remove_blob(sequence)?;
+        }
 
         // FIXME: print files not compacted
         info!(
@@ -567,6 +1841,8 @@ impl Cask {
             self.inner.read().unwrap().log.active_file_id
         };
 
+        self.inner.write().unwrap().index.expire();
+
         let file_stats = {
             self.inner.read().unwrap().index.stats.file_stats()
         };
@@ -617,6 +1893,19 @@ impl Cask {
                 files.insert(file_id);
             }
 
+            let needs_rotation = {
+                self.inner.read().unwrap().log.needs_rotation(file_id)?
+            };
+
+            if needs_rotation {
+                info!(
+                    "File {} isn't encrypted with the current key, triggered compaction",
+                    file_id
+                );
+                triggered = true;
+                files.insert(file_id);
+            }
+
             if !files.contains(&file_id) {
                 let file_size = {
                     self.inner.read().unwrap().log.file_size(file_id).ok()
@@ -657,18 +1946,330 @@ impl Cask {
 
     /// Inserts a key-value pair into the map.
     pub fn put<K: Into<Vec<u8>>, V: AsRef<[u8]>>(&self, key: K, value: V) -> Result<()> {
-        self.inner.write().unwrap().put(key.into(), value.as_ref())
+        write_durably(&self.inner, false, |inner| {
+            inner.put(key.into(), value.as_ref(), None)
+        })
+    }
+
+    /// Inserts a key-value pair into the map with the given `WriteOptions`.
+    pub fn put_with<K: Into<Vec<u8>>, V: AsRef<[u8]>>(
+        &self,
+        key: K,
+        value: V,
+        options: WriteOptions,
+    ) -> Result<()> {
+        write_durably(&self.inner, options.sync, |inner| {
+            inner.put(key.into(), value.as_ref(), None)
+        })
+    }
+
+    /// Returns up to `len` bytes of the value corresponding to the key starting at `offset`, if
+    /// any. The result is shorter than `len` if the value ends before.
+    pub fn get_range<K: AsRef<[u8]>>(
+        &self,
+        key: K,
+        offset: u64,
+        len: u64,
+    ) -> Result<Option<Vec<u8>>> {
+        self.inner.read().unwrap().get_range(key.as_ref(), offset, len)
+    }
+
+    /// Returns a reader over the value corresponding to the key, if any. Values stored in blob
+    /// files are streamed rather than read into memory at once.
+    pub fn get_reader<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<ValueReader>> {
+        self.inner.read().unwrap().get_reader(key.as_ref())
+    }
+
+    /// Inserts a key-value pair into the map, reading the `len` bytes of the value from `reader`.
+    /// Values of at least `blob_threshold` bytes are streamed to a blob file without being
+    /// buffered in memory, and without blocking other operations until the value is fully read.
+    pub fn put_reader<K: Into<Vec<u8>>, R: Read>(
+        &self,
+        key: K,
+        mut reader: R,
+        len: u64,
+    ) -> Result<()> {
+        if len < self.options.blob_threshold as u64 {
+            let mut value = vec![0u8; len as usize];
+            reader.read_exact(&mut value)?;
+            return self.put(key, value);
+        }
+
+        // the blob is bound to its sequence number, which is reserved before streaming it since
+        // the entry is only appended once the blob is complete
+        let (blob_sequence, mut blob_writer) = {
+            let mut inner = self.inner.write().unwrap();
+            let blob_sequence = inner.current_sequence;
+            let blob_writer = inner.log.blob_writer(blob_sequence)?;
+            inner.current_sequence += 1;
+            (blob_sequence, blob_writer)
+        };
+        blob_writer.copy_from(&mut reader, len)?;
+
+        let blob_file_path = blob_writer.path().to_path_buf();
+        blob_writer.finish()?;
+
+        write_durably(&self.inner, false, |inner| {
+            inner.put_blob(key.into(), &blob_file_path, len, blob_sequence, None)
+        })
+    }
+
+    /// Inserts a key-value pair into the map which expires after `ttl`. Expired keys are treated
+    /// as missing and their space is reclaimed by compaction.
+    pub fn put_with_ttl<K: Into<Vec<u8>>, V: AsRef<[u8]>>(
+        &self,
+        key: K,
+        value: V,
+        ttl: Duration,
+    ) -> Result<()> {
+        let ttl = ttl.as_secs() * 1000 + ttl.subsec_millis() as u64;
+        let expires = now_millis().saturating_add(ttl);
+        write_durably(&self.inner, false, |inner| {
+            inner.put(key.into(), value.as_ref(), Some(expires))
+        })
     }
 
     /// Removes a key from the map.
     pub fn delete<K: AsRef<[u8]>>(&self, key: K) -> Result<()> {
-        self.inner.write().unwrap().delete(key.as_ref())
+        write_durably(&self.inner, false, |inner| inner.delete(key.as_ref()))
+    }
+
+    /// Runs `f` in a transaction and commits its writes atomically if it succeeds. Fails with
+    /// `Error::TransactionConflict`, without applying any writes, if a key read by the
+    /// transaction was modified concurrently, in which case the transaction can be retried.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// use cask::CaskOptions;
+    /// use cask::errors::Error;
+    ///
+    /// let cask = CaskOptions::default().open("cask.db").unwrap();
+    ///
+    /// loop {
+    ///     let res = cask.transaction(|txn| {
+    ///         let from = txn.get("from")?.unwrap_or_default();
+    ///         txn.put("to", from);
+    ///         txn.delete("from");
+    ///         Ok(())
+    ///     });
+    ///
+    ///     match res {
+    ///         Err(Error::TransactionConflict) => continue,
+    ///         res => break res.unwrap(),
+    ///     }
+    /// }
+    /// ```
+    pub fn transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Transaction) -> Result<T>,
+    {
+        let mut txn = Transaction {
+            inner: self.inner.clone(),
+            reads: HashMap::new(),
+            writes: BTreeMap::new(),
+        };
+
+        let res = f(&mut txn)?;
+        txn.commit()?;
+
+        Ok(res)
+    }
+
+    /// Atomically replaces the value of `key` with `new` if its current value is `expected`,
+    /// where `None` stands for a missing key on either side. Returns `false`, leaving the map
+    /// unchanged, if the current value didn't match.
+    pub fn compare_and_swap<K: AsRef<[u8]>>(
+        &self,
+        key: K,
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool> {
+        write_durably(&self.inner, false, |inner| {
+            inner.compare_and_swap(key.as_ref(), expected, new)
+        })
+    }
+
+    /// Inserts a key-value pair into the map unless the key already exists. Returns `false` if the
+    /// key already existed.
+    pub fn put_if_absent<K: Into<Vec<u8>>, V: AsRef<[u8]>>(
+        &self,
+        key: K,
+        value: V,
+    ) -> Result<bool> {
+        let key = key.into();
+
+        write_durably(&self.inner, false, |inner| {
+            if inner.index.get(&key).is_some() {
+                return Ok(false);
+            }
+
+            inner.put(key, value.as_ref(), None)?;
+            Ok(true)
+        })
+    }
+
+    /// Removes a key from the map if its current value is `expected`. Returns `false` otherwise.
+    pub fn delete_if_equals<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+        &self,
+        key: K,
+        expected: V,
+    ) -> Result<bool> {
+        self.compare_and_swap(key, Some(expected.as_ref()), None)
+    }
+
+    /// Applies all writes in `batch` atomically. Concurrent readers see either none or all of the
+    /// writes, and so does the `Cask` when it's reopened after a crash.
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        write_durably(&self.inner, false, |inner| inner.write(batch))
+    }
+
+    /// Waits until all writes so far are durable, regardless of the `SyncStrategy`. Concurrent
+    /// calls and synchronized writes share a single `fsync`.
+    pub fn sync(&self) -> Result<()> {
+        let commit = self.inner.read().unwrap().log.last_commit(true);
+
+        if let Some(commit) = commit {
+            commit.wait()?;
+        }
+
+        Ok(())
+    }
+
+    /// Waits until all writes so far are durable like `sync`, and also writes the hints of the
+    /// active data file to disk, so that its entries aren't read again when opening the `Cask`
+    /// after a crash.
+    pub fn flush(&self) -> Result<()> {
+        self.inner.write().unwrap().log.flush()
     }
 
     /// Returns all keys stored in the map.
     pub fn keys(&self) -> Vec<Vec<u8>> {
         self.inner.read().unwrap().keys().cloned().collect()
     }
+
+    /// Returns an iterator over all key-value pairs stored in the map. See `Iter` for the
+    /// semantics of concurrent writes during the iteration.
+    pub fn iter(&self) -> Iter {
+        Iter {
+            cursor: IterCursor::new(&self.inner, None),
+            chunk: Vec::new().into_iter(),
+        }
+    }
+
+    /// Returns an iterator over all keys stored in the map. See `Iter` for the semantics of
+    /// concurrent writes during the iteration.
+    pub fn keys_iter(&self) -> KeysIter {
+        KeysIter {
+            cursor: IterCursor::new(&self.inner, None),
+            chunk: Vec::new().into_iter(),
+        }
+    }
+
+    /// Creates a snapshot of the current state of the map, which is unaffected by later writes.
+    pub fn snapshot(&self) -> Snapshot {
+        let mut inner = self.inner.write().unwrap();
+        let sequence = inner.current_sequence;
+        inner.index.add_snapshot(sequence);
+
+        Snapshot {
+            inner: self.inner.clone(),
+            sequence: sequence,
+        }
+    }
+
+    /// Returns the keys within `range`, in lexicographic order.
+    ///
+    /// Unless the `Cask` was opened with an ordered index all keys have to be scanned and sorted.
+    pub fn range<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> Vec<Vec<u8>> {
+        let range = key_range(&range);
+        self.inner
+            .read()
+            .unwrap()
+            .index
+            .range(range)
+            .into_iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the key-value pairs within `range`, in lexicographic order of the keys.
+    ///
+    /// Unless the `Cask` was opened with an ordered index all keys have to be scanned and sorted.
+    pub fn range_values<K: AsRef<[u8]>, R: RangeBounds<K>>(
+        &self,
+        range: R,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let range = key_range(&range);
+        self.inner.read().unwrap().range_values(range)
+    }
+
+    /// Returns the keys starting with `prefix`, in lexicographic order.
+    ///
+    /// Unless the `Cask` was opened with an ordered index all keys have to be scanned and sorted.
+    pub fn prefix<P: AsRef<[u8]>>(&self, prefix: P) -> Vec<Vec<u8>> {
+        let end = prefix_end(prefix.as_ref());
+        self.inner
+            .read()
+            .unwrap()
+            .index
+            .range(prefix_range(prefix.as_ref(), &end))
+            .into_iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the key-value pairs whose keys start with `prefix`, in lexicographic order of the
+    /// keys.
+    ///
+    /// Unless the `Cask` was opened with an ordered index all keys have to be scanned and sorted.
+    pub fn prefix_values<P: AsRef<[u8]>>(&self, prefix: P) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let end = prefix_end(prefix.as_ref());
+        self.inner.read().unwrap().range_values(
+            prefix_range(prefix.as_ref(), &end),
+        )
+    }
+
+    /// Returns the report of the corrupt data that was discarded when this `Cask` was opened.
+    pub fn recovery_report(&self) -> &RecoveryReport {
+        &self.report
+    }
+}
+
+fn key_range<'a, K: AsRef<[u8]> + 'a, R: RangeBounds<K>>(range: &'a R) -> KeyRange<'a> {
+    fn as_bytes<K: AsRef<[u8]>>(bound: Bound<&K>) -> Bound<&[u8]> {
+        match bound {
+            Bound::Included(key) => Bound::Included(key.as_ref()),
+            Bound::Excluded(key) => Bound::Excluded(key.as_ref()),
+            Bound::Unbounded => Bound::Unbounded,
+        }
+    }
+
+    (as_bytes(range.start_bound()), as_bytes(range.end_bound()))
+}
+
+/// Returns the smallest key that is greater than all keys starting with `prefix`, if any.
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+
+    None
+}
+
+fn prefix_range<'a>(prefix: &'a [u8], end: &'a Option<Vec<u8>>) -> KeyRange<'a> {
+    (
+        Bound::Included(prefix),
+        match *end {
+            Some(ref end) => Bound::Excluded(end),
+            None => Bound::Unbounded,
+        },
+    )
 }
 
 impl Drop for Cask {
@@ -680,8 +2281,44 @@ impl Drop for Cask {
 
 #[cfg(test)]
 mod tests {
-    use cask::CaskOptions;
+    use batch::WriteBatch;
+    use byteorder::{LittleEndian, ReadBytesExt};
+    use cask::{Cask, CaskOptions, Compression, CorruptionPolicy, RecoveryReport, Snapshot,
+               SyncStrategy, WriteOptions};
+    use errors::{Error, Result};
+    use header::{FileHeader, HEADER_SIZE};
     use std::fs;
+    use std::fs::OpenOptions;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::path::Path;
+    use std::thread;
+    use std::time::Duration;
+    use verify::{VerifyError, verify};
+
+    fn corrupt_middle_entry(path: &str) {
+        {
+            let cask = CaskOptions::default()
+                .sync(SyncStrategy::Never)
+                .compaction(false)
+                .open(path)
+                .unwrap();
+
+            cask.put("key1", "value1").unwrap();
+            cask.put("key2", "value2").unwrap();
+            cask.put("key3", "value3").unwrap();
+        }
+
+        // every entry takes 30 bytes after the file header, flip a byte in the value of the second
+        // one
+        let mut data_file = OpenOptions::new()
+            .write(true)
+            .open(format!("{}/0000000001.cask.data", path))
+            .unwrap();
+        data_file.seek(SeekFrom::Start(HEADER_SIZE + 30 + 25)).unwrap();
+        data_file.write_all(&[0]).unwrap();
+
+        fs::remove_file(format!("{}/0000000001.cask.hint", path)).unwrap();
+    }
 
     #[test]
     fn test_keys() {
@@ -717,4 +2354,1792 @@ mod tests {
 
         assert!(fs::remove_dir_all("test.db").is_ok());
     }
+
+    #[test]
+    fn test_large_key() {
+        let path = "test-large-key.db";
+        let _ = fs::remove_dir_all(path);
+
+        let key = vec![7u8; 100 * 1024];
+
+        for _ in 0..2 {
+            let cask = CaskOptions::default()
+                .sync(SyncStrategy::Never)
+                .compaction(false)
+                .open(path)
+                .unwrap();
+
+            cask.put(&*key, "value").unwrap();
+            assert_eq!(cask.get(&*key).unwrap(), Some(b"value".to_vec()));
+            assert_eq!(cask.keys(), vec![key.clone()]);
+        }
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_recover_torn_tail() {
+        let path = "test-torn-tail.db";
+        let _ = fs::remove_dir_all(path);
+
+        {
+            let cask = CaskOptions::default()
+                .sync(SyncStrategy::Never)
+                .compaction(false)
+                .open(path)
+                .unwrap();
+
+            cask.put("key1", "value1").unwrap();
+            cask.put("key2", "value2").unwrap();
+        }
+
+        let data_file_path = format!("{}/0000000001.cask.data", path);
+        let hint_file_path = format!("{}/0000000001.cask.hint", path);
+        let data_file_size = fs::metadata(&data_file_path).unwrap().len();
+
+        // simulate a crash in the middle of writing an entry: a partial header and no hint
+        // file trailer
+        OpenOptions::new()
+            .append(true)
+            .open(&data_file_path)
+            .unwrap()
+            .write_all(&[1, 2, 3, 4, 5, 6, 7])
+            .unwrap();
+        let hint_file_size = fs::metadata(&hint_file_path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&hint_file_path)
+            .unwrap()
+            .set_len(hint_file_size - 4)
+            .unwrap();
+
+        {
+            let cask = CaskOptions::default()
+                .sync(SyncStrategy::Never)
+                .compaction(false)
+                .open(path)
+                .unwrap();
+
+            assert_eq!(cask.get("key1").unwrap(), Some(b"value1".to_vec()));
+            assert_eq!(cask.get("key2").unwrap(), Some(b"value2".to_vec()));
+        }
+
+        assert_eq!(fs::metadata(&data_file_path).unwrap().len(), data_file_size);
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_corruption_policy_fail() {
+        let path = "test-corruption-fail.db";
+        let _ = fs::remove_dir_all(path);
+
+        corrupt_middle_entry(path);
+
+        for _ in 0..2 {
+            assert!(
+                CaskOptions::default()
+                    .sync(SyncStrategy::Never)
+                    .compaction(false)
+                    .open(path)
+                    .is_err()
+            );
+        }
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_corruption_policy_skip_entry() {
+        let path = "test-corruption-skip.db";
+        let _ = fs::remove_dir_all(path);
+
+        corrupt_middle_entry(path);
+
+        {
+            let cask = CaskOptions::default()
+                .sync(SyncStrategy::Never)
+                .compaction(false)
+                .corruption_policy(CorruptionPolicy::SkipEntry)
+                .open(path)
+                .unwrap();
+
+            assert_eq!(
+                cask.recovery_report().skipped,
+                vec![(1, HEADER_SIZE + 30, HEADER_SIZE + 60)]
+            );
+            assert_eq!(cask.get("key1").unwrap(), Some(b"value1".to_vec()));
+            assert_eq!(cask.get("key2").unwrap(), None);
+            assert_eq!(cask.get("key3").unwrap(), Some(b"value3".to_vec()));
+        }
+
+        // the recreated hint file no longer references the corrupt entry
+        {
+            let cask = CaskOptions::default()
+                .sync(SyncStrategy::Never)
+                .compaction(false)
+                .open(path)
+                .unwrap();
+
+            assert!(cask.recovery_report().is_clean());
+            assert_eq!(cask.get("key3").unwrap(), Some(b"value3".to_vec()));
+        }
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_corruption_policy_quarantine_file() {
+        let path = "test-corruption-quarantine.db";
+        let _ = fs::remove_dir_all(path);
+
+        corrupt_middle_entry(path);
+
+        {
+            let cask = CaskOptions::default()
+                .sync(SyncStrategy::Never)
+                .compaction(false)
+                .corruption_policy(CorruptionPolicy::QuarantineFile)
+                .open(path)
+                .unwrap();
+
+            assert_eq!(cask.recovery_report().quarantined, vec![1]);
+            assert_eq!(cask.get("key1").unwrap(), None);
+            assert!(cask.keys().is_empty());
+        }
+
+        assert!(
+            Path::new(path)
+                .join("corrupt/0000000001.cask.data")
+                .is_file()
+        );
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_corrupt_value_size() {
+        let policies = [
+            CorruptionPolicy::Fail,
+            CorruptionPolicy::SkipEntry,
+            CorruptionPolicy::QuarantineFile,
+        ];
+
+        for &policy in &policies {
+            let path = format!("test-corrupt-value-size-{:?}.db", policy);
+            let _ = fs::remove_dir_all(&path);
+
+            {
+                let cask = CaskOptions::default()
+                    .sync(SyncStrategy::Never)
+                    .compaction(false)
+                    .open(&path)
+                    .unwrap();
+
+                cask.put("key1", "value1").unwrap();
+                cask.put("key2", "value2").unwrap();
+                cask.put("key3", "value3").unwrap();
+            }
+
+            // flip the highest byte of the value size of the second entry, which then appears to
+            // run past the end of the data file
+            let mut data_file = OpenOptions::new()
+                .write(true)
+                .open(format!("{}/0000000001.cask.data", path))
+                .unwrap();
+            data_file.seek(SeekFrom::Start(HEADER_SIZE + 30 + 19)).unwrap();
+            data_file.write_all(&[0x7f]).unwrap();
+
+            fs::remove_file(format!("{}/0000000001.cask.hint", path)).unwrap();
+
+            let res = CaskOptions::default()
+                .sync(SyncStrategy::Never)
+                .compaction(false)
+                .corruption_policy(policy)
+                .open(&path);
+
+            let data_file_path = match policy {
+                CorruptionPolicy::Fail => {
+                    assert!(res.is_err());
+                    format!("{}/0000000001.cask.data", path)
+                }
+                CorruptionPolicy::SkipEntry => {
+                    let cask = res.unwrap();
+                    assert!(cask.recovery_report().truncated.is_empty());
+                    assert_eq!(
+                        cask.recovery_report().skipped,
+                        vec![(1, HEADER_SIZE + 30, HEADER_SIZE + 60)]
+                    );
+                    assert_eq!(cask.get("key1").unwrap(), Some(b"value1".to_vec()));
+                    assert_eq!(cask.get("key2").unwrap(), None);
+                    assert_eq!(cask.get("key3").unwrap(), Some(b"value3".to_vec()));
+                    format!("{}/0000000001.cask.data", path)
+                }
+                CorruptionPolicy::QuarantineFile => {
+                    let cask = res.unwrap();
+                    assert!(cask.recovery_report().truncated.is_empty());
+                    assert_eq!(cask.recovery_report().quarantined, vec![1]);
+                    format!("{}/corrupt/0000000001.cask.data", path)
+                }
+            };
+
+            // nothing after the corrupt entry was discarded
+            assert_eq!(fs::metadata(&data_file_path).unwrap().len(), HEADER_SIZE + 90);
+
+            assert!(fs::remove_dir_all(&path).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_corrupt_region() {
+        let path = "test-corrupt-region.db";
+        let _ = fs::remove_dir_all(path);
+
+        let value_size = 1024 * 1024;
+
+        {
+            let cask = CaskOptions::default()
+                .sync(SyncStrategy::Never)
+                .compaction(false)
+                .blob_threshold(2 * value_size as usize)
+                .open(path)
+                .unwrap();
+
+            cask.put("key1", "value1").unwrap();
+            cask.put("key2", vec![0u8; value_size as usize]).unwrap();
+            cask.put("key3", "value3").unwrap();
+        }
+
+        // overwrite the second entry with a pattern that makes most offsets look like the header
+        // of an entry of 512 KiB
+        let garbage: Vec<u8> = [0, 0, 4, 0]
+            .iter()
+            .cycle()
+            .take(24 + value_size as usize)
+            .cloned()
+            .collect();
+
+        let mut data_file = OpenOptions::new()
+            .write(true)
+            .open(format!("{}/0000000001.cask.data", path))
+            .unwrap();
+        data_file.seek(SeekFrom::Start(HEADER_SIZE + 30)).unwrap();
+        data_file.write_all(&garbage).unwrap();
+
+        fs::remove_file(format!("{}/0000000001.cask.hint", path)).unwrap();
+
+        {
+            let cask = CaskOptions::default()
+                .sync(SyncStrategy::Never)
+                .compaction(false)
+                .corruption_policy(CorruptionPolicy::SkipEntry)
+                .open(path)
+                .unwrap();
+
+            assert_eq!(
+                cask.recovery_report().skipped,
+                vec![(1, HEADER_SIZE + 30, HEADER_SIZE + 54 + value_size)]
+            );
+            assert_eq!(cask.get("key1").unwrap(), Some(b"value1".to_vec()));
+            assert_eq!(cask.get("key2").unwrap(), None);
+            assert_eq!(cask.get("key3").unwrap(), Some(b"value3".to_vec()));
+        }
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_range_and_prefix() {
+        for &ordered in &[true, false] {
+            let path = format!("test-range-{}.db", ordered);
+            let _ = fs::remove_dir_all(&path);
+
+            let cask = CaskOptions::default()
+                .sync(SyncStrategy::Never)
+                .compaction(false)
+                .ordered_index(ordered)
+                .open(&path)
+                .unwrap();
+
+            for key in &["user:3", "user:1", "group:1", "user:2", "user;", "user"] {
+                cask.put(*key, key.to_uppercase()).unwrap();
+            }
+            cask.delete("user:2").unwrap();
+
+            assert_eq!(
+                cask.prefix("user:"),
+                vec![b"user:1".to_vec(), b"user:3".to_vec()]
+            );
+            assert_eq!(
+                cask.range("user".."user:3"),
+                vec![b"user".to_vec(), b"user:1".to_vec()]
+            );
+            assert_eq!(
+                cask.range_values("user:1"..).unwrap(),
+                vec![
+                    (b"user:1".to_vec(), b"USER:1".to_vec()),
+                    (b"user:3".to_vec(), b"USER:3".to_vec()),
+                    (b"user;".to_vec(), b"USER;".to_vec()),
+                ]
+            );
+            assert_eq!(
+                cask.prefix_values("group").unwrap(),
+                vec![(b"group:1".to_vec(), b"GROUP:1".to_vec())]
+            );
+
+            if ordered {
+                assert_eq!(cask.keys().len(), 5);
+                assert_eq!(cask.keys()[0], b"group:1".to_vec());
+            }
+
+            drop(cask);
+            assert!(fs::remove_dir_all(&path).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_iter() {
+        for &ordered in &[true, false] {
+            let path = format!("test-iter-{}.db", ordered);
+            let _ = fs::remove_dir_all(&path);
+
+            let cask = CaskOptions::default()
+                .sync(SyncStrategy::Never)
+                .compaction(false)
+                .ordered_index(ordered)
+                .open(&path)
+                .unwrap();
+
+            for i in 0..3000 {
+                cask.put(format!("{:04}", i), format!("{}", i)).unwrap();
+            }
+
+            let mut iter = cask.iter();
+            let (key, value) = iter.next().unwrap().unwrap();
+
+            // deleting a key that wasn't visited yet, nor read as part of the current chunk
+            let deleted = (0..3000)
+                .map(|i| format!("{:04}", i))
+                .find(|k| {
+                    k.as_bytes() != key.as_slice() &&
+                        iter.chunk.as_slice().iter().all(|e| e.0 != k.as_bytes())
+                })
+                .unwrap();
+            cask.delete(&deleted).unwrap();
+
+            let mut items = vec![(key, value)];
+            items.extend(iter.map(|e| e.unwrap()));
+            assert_eq!(items.len(), 2999);
+            for (key, value) in items {
+                let value: usize = String::from_utf8(value).unwrap().parse().unwrap();
+                assert_eq!(key, format!("{:04}", value).into_bytes());
+                assert_ne!(key, deleted.as_bytes());
+            }
+
+            let mut keys: Vec<_> = cask.keys_iter().collect();
+            assert_eq!(keys.len(), 2999);
+            if ordered {
+                assert!(keys.windows(2).all(|w| w[0] < w[1]));
+            }
+            keys.dedup();
+            assert_eq!(keys.len(), 2999);
+
+            drop(cask);
+            assert!(fs::remove_dir_all(&path).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_snapshot() {
+        for &ordered in &[true, false] {
+            let path = format!("test-snapshot-{}.db", ordered);
+            let _ = fs::remove_dir_all(&path);
+
+            // every entry ends up in its own data file
+            let cask = CaskOptions::default()
+                .sync(SyncStrategy::Never)
+                .compaction(false)
+                .ordered_index(ordered)
+                .max_file_size(32)
+                .open(&path)
+                .unwrap();
+
+            cask.put("a", "a1").unwrap();
+            cask.put("b", "b1").unwrap();
+
+            let snapshot = cask.snapshot();
+
+            cask.put("a", "a2").unwrap();
+            cask.delete("b").unwrap();
+            cask.put("c", "c1").unwrap();
+
+            let check = |snapshot: &Snapshot| {
+                assert_eq!(snapshot.get("a").unwrap(), Some(b"a1".to_vec()));
+                assert_eq!(snapshot.get("b").unwrap(), Some(b"b1".to_vec()));
+                assert_eq!(snapshot.get("c").unwrap(), None);
+
+                let mut items: Vec<_> = snapshot.iter().map(|e| e.unwrap()).collect();
+                items.sort();
+                assert_eq!(
+                    items,
+                    vec![
+                        (b"a".to_vec(), b"a1".to_vec()),
+                        (b"b".to_vec(), b"b1".to_vec()),
+                    ]
+                );
+            };
+
+            check(&snapshot);
+            assert_eq!(cask.get("a").unwrap(), Some(b"a2".to_vec()));
+            assert_eq!(cask.get("b").unwrap(), None);
+
+            // the versions visible to the snapshot survive compaction
+            let files = {
+                let inner = cask.inner.read().unwrap();
+                let mut files = inner.log.files();
+                files.retain(|&file_id| Some(file_id) != inner.log.active_file_id);
+                files
+            };
+            cask.compact_files(&files).unwrap();
+
+            check(&snapshot);
+            assert_eq!(cask.get("a").unwrap(), Some(b"a2".to_vec()));
+            assert_eq!(cask.get("b").unwrap(), None);
+            assert_eq!(cask.get("c").unwrap(), Some(b"c1".to_vec()));
+
+            drop(snapshot);
+            assert!(cask.inner.read().unwrap().index.history.is_empty());
+
+            drop(cask);
+
+            let cask = CaskOptions::default()
+                .sync(SyncStrategy::Never)
+                .compaction(false)
+                .open(&path)
+                .unwrap();
+
+            assert_eq!(cask.get("a").unwrap(), Some(b"a2".to_vec()));
+            assert_eq!(cask.get("b").unwrap(), None);
+            assert_eq!(cask.get("c").unwrap(), Some(b"c1".to_vec()));
+
+            drop(cask);
+            assert!(fs::remove_dir_all(&path).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_write_batch() {
+        let path = "test-write-batch.db";
+        let _ = fs::remove_dir_all(path);
+
+        let open = || {
+            CaskOptions::default()
+                .sync(SyncStrategy::Never)
+                .compaction(false)
+                .open(path)
+                .unwrap()
+        };
+
+        {
+            let cask = open();
+            cask.put("a", "1").unwrap();
+
+            let mut batch = WriteBatch::new();
+            batch.put("b", "2").delete("a").put("c", "3");
+            cask.write(batch).unwrap();
+
+            assert_eq!(cask.get("a").unwrap(), None);
+            assert_eq!(cask.get("b").unwrap(), Some(b"2".to_vec()));
+            assert_eq!(cask.get("c").unwrap(), Some(b"3".to_vec()));
+        }
+
+        {
+            let cask = open();
+            assert_eq!(cask.get("a").unwrap(), None);
+            assert_eq!(cask.get("b").unwrap(), Some(b"2".to_vec()));
+            assert_eq!(cask.get("c").unwrap(), Some(b"3".to_vec()));
+        }
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_write_batch_torn() {
+        // entries after the file header: put a (0..22), batch marker (22..46), put b (46..68),
+        // delete a (68..89), put c (89..111)
+        for &len in &[HEADER_SIZE + 89, HEADER_SIZE + 98] {
+            let path = format!("test-write-batch-torn-{}.db", len);
+            let _ = fs::remove_dir_all(&path);
+
+            let open = || {
+                CaskOptions::default()
+                    .sync(SyncStrategy::Never)
+                    .compaction(false)
+                    .open(&path)
+                    .unwrap()
+            };
+
+            {
+                let cask = open();
+                cask.put("a", "1").unwrap();
+
+                let mut batch = WriteBatch::new();
+                batch.put("b", "2").delete("a").put("c", "3");
+                cask.write(batch).unwrap();
+            }
+
+            // simulate a crash in the middle of writing the batch
+            let data_file_path = format!("{}/0000000001.cask.data", path);
+            let hint_file_path = format!("{}/0000000001.cask.hint", path);
+            assert_eq!(
+                fs::metadata(&data_file_path).unwrap().len(),
+                HEADER_SIZE + 111
+            );
+            OpenOptions::new()
+                .write(true)
+                .open(&data_file_path)
+                .unwrap()
+                .set_len(len)
+                .unwrap();
+            fs::remove_file(&hint_file_path).unwrap();
+
+            {
+                let cask = open();
+                assert_eq!(cask.recovery_report().truncated, vec![(1, len - HEADER_SIZE - 22)]);
+                assert_eq!(cask.get("a").unwrap(), Some(b"1".to_vec()));
+                assert_eq!(cask.get("b").unwrap(), None);
+                assert_eq!(cask.get("c").unwrap(), None);
+
+                cask.put("d", "4").unwrap();
+            }
+
+            {
+                let cask = open();
+                assert_eq!(cask.get("a").unwrap(), Some(b"1".to_vec()));
+                assert_eq!(cask.get("b").unwrap(), None);
+                assert_eq!(cask.get("d").unwrap(), Some(b"4".to_vec()));
+            }
+
+            assert!(fs::remove_dir_all(&path).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        let path = "test-compare-and-swap.db";
+        let _ = fs::remove_dir_all(path);
+
+        let cask = CaskOptions::default()
+            .sync(SyncStrategy::Never)
+            .compaction(false)
+            .open(path)
+            .unwrap();
+
+        assert!(cask.put_if_absent("lease", "a").unwrap());
+        assert!(!cask.put_if_absent("lease", "b").unwrap());
+        assert!(!cask.delete_if_equals("lease", "b").unwrap());
+        assert!(cask.delete_if_equals("lease", "a").unwrap());
+        assert_eq!(cask.get("lease").unwrap(), None);
+
+        assert!(!cask.compare_and_swap("counter", Some(b"0"), Some(b"1")).unwrap());
+        assert!(cask.compare_and_swap("counter", None, Some(b"0")).unwrap());
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let cask = cask.clone();
+                thread::spawn(move || for _ in 0..100 {
+                    loop {
+                        let current = cask.get("counter").unwrap().unwrap();
+                        let n: u32 = String::from_utf8(current.clone()).unwrap().parse().unwrap();
+                        let new = format!("{}", n + 1);
+                        if cask.compare_and_swap("counter", Some(&current), Some(new.as_bytes()))
+                            .unwrap()
+                        {
+                            break;
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        assert_eq!(cask.get("counter").unwrap(), Some(b"400".to_vec()));
+
+        drop(cask);
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_transaction() {
+        let path = "test-transaction.db";
+        let _ = fs::remove_dir_all(path);
+
+        let cask = CaskOptions::default()
+            .sync(SyncStrategy::Never)
+            .compaction(false)
+            .blob_threshold(1024)
+            .open(path)
+            .unwrap();
+
+        cask.put("a", "1").unwrap();
+
+        // buffered writes are visible to the transaction's own reads
+        let res = cask.transaction(|txn| {
+            txn.put("b", "2");
+            txn.delete("a");
+            assert_eq!(txn.get("b")?, Some(b"2".to_vec()));
+            assert_eq!(txn.get("a")?, None);
+            Ok(7)
+        });
+        assert_eq!(res.unwrap(), 7);
+        assert_eq!(cask.get("a").unwrap(), None);
+        assert_eq!(cask.get("b").unwrap(), Some(b"2".to_vec()));
+
+        // a concurrent write to a key that was read causes a conflict
+        let res = cask.transaction(|txn| {
+            txn.get("b")?;
+            txn.get("missing")?;
+            cask.put("b", "3")?;
+            txn.put("c", "3");
+            Ok(())
+        });
+        match res {
+            Err(Error::TransactionConflict) => {}
+            res => panic!("unexpected result: {:?}", res),
+        }
+        assert_eq!(cask.get("c").unwrap(), None);
+
+        let res = cask.transaction(|txn| {
+            txn.get("missing")?;
+            cask.put("missing", "1")?;
+            Ok(())
+        });
+        match res {
+            Err(Error::TransactionConflict) => {}
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        // failed transactions don't apply any writes
+        let res: Result<()> = cask.transaction(|txn| {
+            txn.put("d", "4");
+            Err(Error::InvalidPath("".to_string()))
+        });
+        assert!(res.is_err());
+        assert_eq!(cask.get("d").unwrap(), None);
+
+        // large values are stored in blob files, as with any other write
+        let value = vec![7u8; 2048];
+        cask.transaction(|txn| {
+            txn.put("large", value.clone());
+            Ok(())
+        }).unwrap();
+        let sequence = cask.inner.read().unwrap().index.get(b"large").unwrap().sequence;
+        assert!(Path::new(&format!("{}/{:020}.cask.blob", path, sequence)).is_file());
+        assert_eq!(cask.get("large").unwrap(), Some(value));
+
+        drop(cask);
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_ttl() {
+        let path = "test-ttl.db";
+        let _ = fs::remove_dir_all(path);
+
+        let open = || {
+            CaskOptions::default()
+                .sync(SyncStrategy::Never)
+                .compaction(false)
+                .max_file_size(32)
+                .open(path)
+                .unwrap()
+        };
+
+        {
+            let cask = open();
+
+            // every entry ends up in its own data file
+            cask.put("a", "1").unwrap();
+            cask.put_with_ttl("a", "2", Duration::from_millis(20)).unwrap();
+            cask.put_with_ttl("b", "3", Duration::from_secs(3600)).unwrap();
+            cask.put("c", "4").unwrap();
+
+            assert_eq!(cask.get("a").unwrap(), Some(b"2".to_vec()));
+
+            thread::sleep(Duration::from_millis(50));
+
+            assert_eq!(cask.get("a").unwrap(), None);
+            assert_eq!(cask.get("b").unwrap(), Some(b"3".to_vec()));
+
+            let mut keys = cask.keys();
+            keys.sort();
+            assert_eq!(keys, vec![b"b".to_vec(), b"c".to_vec()]);
+            assert_eq!(cask.iter().count(), 2);
+            assert_eq!(cask.keys_iter().count(), 2);
+
+            // expired bytes are accounted as dead
+            cask.inner.write().unwrap().index.expire();
+            let stats = cask.inner.read().unwrap().index.stats.file_stats();
+            let (_, fragmentation, _) = stats.into_iter().find(|s| s.0 == 2).unwrap();
+            assert_eq!(fragmentation, 1.0);
+
+            // compacting only the file with the expired entry must not resurrect the older
+            // version of the key
+            cask.compact_files(&[2]).unwrap();
+            assert_eq!(cask.get("a").unwrap(), None);
+        }
+
+        {
+            let cask = open();
+            assert_eq!(cask.get("a").unwrap(), None);
+            assert_eq!(cask.get("b").unwrap(), Some(b"3".to_vec()));
+            assert_eq!(cask.get("c").unwrap(), Some(b"4".to_vec()));
+        }
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_compression() {
+        let path = "test-compression.db";
+        let _ = fs::remove_dir_all(path);
+
+        let open = |compression| {
+            CaskOptions::default()
+                .sync(SyncStrategy::Never)
+                .compaction(false)
+                .compression(compression)
+                .compression_threshold(64)
+                .open(path)
+                .unwrap()
+        };
+
+        let value = "{\"name\": \"cask\"}".repeat(100);
+
+        // files written with every setting are readable side by side
+        for &compression in &[Compression::Lz4, Compression::Zstd, Compression::None] {
+            let cask = open(compression);
+            cask.put(format!("{:?}", compression), &value).unwrap();
+            cask.put(format!("{:?}-small", compression), "small").unwrap();
+
+            let mut batch = WriteBatch::new();
+            batch.put(format!("{:?}-batch", compression), value.clone());
+            cask.write(batch).unwrap();
+        }
+
+        let cask = open(Compression::None);
+        for compression in &["Lz4", "Zstd", "None"] {
+            assert_eq!(
+                cask.get(compression).unwrap(),
+                Some(value.clone().into_bytes())
+            );
+            assert_eq!(
+                cask.get(format!("{}-batch", compression)).unwrap(),
+                Some(value.clone().into_bytes())
+            );
+            assert_eq!(
+                cask.get(format!("{}-small", compression)).unwrap(),
+                Some(b"small".to_vec())
+            );
+        }
+
+        // the compressed values take less space on disk
+        let sizes: Vec<_> = cask.inner
+            .read()
+            .unwrap()
+            .log
+            .files()
+            .into_iter()
+            .map(|file_id| cask.inner.read().unwrap().log.file_size(file_id).unwrap())
+            .collect();
+        assert!(sizes[0] < sizes[2] / 5);
+        assert!(sizes[1] < sizes[2] / 5);
+
+        drop(cask);
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_encryption() {
+        let path = "test-encryption.db";
+        let _ = fs::remove_dir_all(path);
+
+        let open = |keys: &[(u32, [u8; 32])]| {
+            let mut options = CaskOptions::default();
+            options.sync(SyncStrategy::Never).compaction(false);
+            for (i, &(key_id, key)) in keys.iter().enumerate() {
+                if i == 0 {
+                    options.encryption_key(key_id, key);
+                } else {
+                    options.decryption_key(key_id, key);
+                }
+            }
+            options.open(path)
+        };
+
+        let read_files = || {
+            let mut contents = Vec::new();
+            for file in fs::read_dir(path).unwrap() {
+                contents.extend(fs::read(file.unwrap().path()).unwrap());
+            }
+            contents
+        };
+
+        {
+            // data written before encryption is enabled stays readable
+            let cask = open(&[]).unwrap();
+            cask.put("plain", "plain-value").unwrap();
+        }
+
+        {
+            let cask = open(&[(1, [1; 32])]).unwrap();
+            cask.put("secret", "secret-value").unwrap();
+            cask.put("deleted", "deleted-value").unwrap();
+            cask.delete("deleted").unwrap();
+
+            let mut batch = WriteBatch::new();
+            batch.put("batch", "batch-value");
+            cask.write(batch).unwrap();
+        }
+
+        let contents = read_files();
+        assert!(contents.windows(6).any(|w| w == b"plain-"));
+        assert!(!contents.windows(6).any(|w| w == b"secret"));
+        assert!(!contents.windows(6).any(|w| w == b"batch-"));
+
+        match open(&[]) {
+            Err(Error::MissingEncryptionKey(1)) => {}
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("opened an encrypted cask without its key"),
+        }
+
+        match open(&[(1, [2; 32])]) {
+            Err(Error::AuthenticationFailed) => {}
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("opened an encrypted cask with the wrong key"),
+        }
+
+        // the index is rebuilt from the encrypted data files without the hint files
+        for file in fs::read_dir(path).unwrap() {
+            let file = file.unwrap().path();
+            if file.extension().unwrap() == "hint" {
+                fs::remove_file(file).unwrap();
+            }
+        }
+
+        {
+            let cask = open(&[(1, [1; 32])]).unwrap();
+            assert_eq!(cask.get("plain").unwrap(), Some(b"plain-value".to_vec()));
+            assert_eq!(cask.get("secret").unwrap(), Some(b"secret-value".to_vec()));
+            assert_eq!(cask.get("deleted").unwrap(), None);
+            assert_eq!(cask.get("batch").unwrap(), Some(b"batch-value".to_vec()));
+        }
+
+        {
+            // compaction rewrites every file with the new key
+            let cask = open(&[(2, [2; 32]), (1, [1; 32])]).unwrap();
+            cask.compact().unwrap();
+        }
+
+        let contents = read_files();
+        assert!(!contents.windows(6).any(|w| w == b"plain-"));
+
+        let cask = open(&[(2, [2; 32])]).unwrap();
+        assert_eq!(cask.get("plain").unwrap(), Some(b"plain-value".to_vec()));
+        assert_eq!(cask.get("secret").unwrap(), Some(b"secret-value".to_vec()));
+        assert_eq!(cask.get("deleted").unwrap(), None);
+        assert_eq!(cask.get("batch").unwrap(), Some(b"batch-value".to_vec()));
+
+        drop(cask);
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_blob() {
+        let path = "test-blob.db";
+        let _ = fs::remove_dir_all(path);
+
+        let open = || {
+            CaskOptions::default()
+                .sync(SyncStrategy::Never)
+                .compaction(false)
+                .blob_threshold(1024)
+                .open(path)
+                .unwrap()
+        };
+
+        let blob_files = || {
+            fs::read_dir(path)
+                .unwrap()
+                .filter(|f| {
+                    f.as_ref().unwrap().file_name().to_str().unwrap().ends_with(".cask.blob")
+                })
+                .count()
+        };
+
+        let value: Vec<u8> = (0..200 * 1024).map(|i| i as u8).collect();
+
+        {
+            let cask = open();
+
+            cask.put("small", "value").unwrap();
+            cask.put("large", &value).unwrap();
+            cask.put_reader("streamed", &value[..], value.len() as u64)
+                .unwrap();
+            cask.put_reader("small-streamed", &b"value"[..], 5).unwrap();
+            assert_eq!(blob_files(), 2);
+
+            assert_eq!(cask.get("large").unwrap(), Some(value.clone()));
+
+            let mut reader = cask.get_reader("streamed").unwrap().unwrap();
+            assert_eq!(reader.len(), value.len() as u64);
+            let mut read = Vec::new();
+            reader.read_to_end(&mut read).unwrap();
+            assert_eq!(read, value);
+
+            let mut read = Vec::new();
+            let mut reader = cask.get_reader("small-streamed").unwrap().unwrap();
+            reader.read_to_end(&mut read).unwrap();
+            assert_eq!(read, b"value");
+
+            assert!(cask.get_reader("missing").unwrap().is_none());
+
+            // a reader that ends early fails the write without leaving its blob behind
+            assert!(
+                cask.put_reader("short", &value[..10], value.len() as u64)
+                    .is_err()
+            );
+            assert_eq!(cask.get("short").unwrap(), None);
+            assert_eq!(blob_files(), 2);
+        }
+
+        // a blob whose entry never made it to the log is removed on open, also if its sequence
+        // number was reserved before the last entry was written, as `put_reader` does
+        fs::write(format!("{}/{:020}.cask.blob", path, 100), b"orphan").unwrap();
+        fs::write(format!("{}/{:020}.cask.blob", path, 1), b"orphan").unwrap();
+
+        {
+            let cask = open();
+            assert_eq!(blob_files(), 2);
+            assert_eq!(cask.get("streamed").unwrap(), Some(value.clone()));
+
+            // the blobs of dropped entries are removed by compaction
+            cask.put("large", "value").unwrap();
+            cask.delete("streamed").unwrap();
+            cask.compact_files(&[1]).unwrap();
+            assert_eq!(blob_files(), 0);
+
+            assert_eq!(cask.get("large").unwrap(), Some(b"value".to_vec()));
+            assert_eq!(cask.get("small").unwrap(), Some(b"value".to_vec()));
+        }
+
+        assert!(verify(path).unwrap().is_ok());
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_write_batch_blob() {
+        let path = "test-write-batch-blob.db";
+        let _ = fs::remove_dir_all(path);
+
+        let open = || {
+            CaskOptions::default()
+                .sync(SyncStrategy::Never)
+                .compaction(false)
+                .blob_threshold(1024)
+                .open(path)
+                .unwrap()
+        };
+
+        let value: Vec<u8> = (0..200 * 1024).map(|i| i as u8).collect();
+
+        {
+            let cask = open();
+
+            let mut batch = WriteBatch::new();
+            batch.put("small", "value").put("large", value.clone());
+            cask.write(batch).unwrap();
+
+            // the large value is stored in the blob file of its entry, the second of the batch
+            assert!(Path::new(&format!("{}/{:020}.cask.blob", path, 2)).is_file());
+            assert_eq!(cask.get("large").unwrap(), Some(value.clone()));
+        }
+
+        {
+            let cask = open();
+            assert_eq!(cask.get("small").unwrap(), Some(b"value".to_vec()));
+            assert_eq!(cask.get("large").unwrap(), Some(value));
+        }
+
+        assert!(verify(path).unwrap().is_ok());
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_blob_encryption() {
+        let path = "test-blob-encryption.db";
+        let _ = fs::remove_dir_all(path);
+
+        let value: Vec<u8> = (0..100 * 1024).map(|i| (i % 10) as u8 + b'0').collect();
+
+        {
+            let cask = CaskOptions::default()
+                .sync(SyncStrategy::Never)
+                .compaction(false)
+                .blob_threshold(1024)
+                .encryption_key(1, [1; 32])
+                .open(path)
+                .unwrap();
+
+            cask.put("key", &value).unwrap();
+            cask.put_reader("streamed", &value[..], value.len() as u64)
+                .unwrap();
+        }
+
+        let blob_file = format!("{}/{:020}.cask.blob", path, 1);
+        let contents = fs::read(&blob_file).unwrap();
+        assert!(!contents.windows(10).any(|w| w == b"0123456789"));
+
+        // the blob of the streamed value is bound to the sequence number reserved for it, so a
+        // blob file moved in its place can't be read
+        let streamed_blob_file = format!("{}/{:020}.cask.blob", path, 2);
+        let streamed_contents = fs::read(&streamed_blob_file).unwrap();
+        fs::write(&streamed_blob_file, &contents).unwrap();
+
+        {
+            let cask = CaskOptions::default()
+                .sync(SyncStrategy::Never)
+                .compaction(false)
+                .encryption_key(1, [1; 32])
+                .open(path)
+                .unwrap();
+
+            assert!(cask.get("streamed").is_err());
+        }
+
+        fs::write(&streamed_blob_file, &streamed_contents).unwrap();
+
+        {
+            // blobs are rotated to the new key along with their entries
+            let cask = CaskOptions::default()
+                .sync(SyncStrategy::Never)
+                .compaction(false)
+                .blob_threshold(1024)
+                .encryption_key(2, [2; 32])
+                .decryption_key(1, [1; 32])
+                .open(path)
+                .unwrap();
+
+            cask.compact_files(&[1]).unwrap();
+            assert!(fs::read(&blob_file).unwrap() != contents);
+        }
+
+        {
+            let cask = CaskOptions::default()
+                .sync(SyncStrategy::Never)
+                .compaction(false)
+                .encryption_key(2, [2; 32])
+                .open(path)
+                .unwrap();
+
+            assert_eq!(cask.get("key").unwrap(), Some(value.clone()));
+            assert_eq!(cask.get("streamed").unwrap(), Some(value));
+        }
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_get_range() {
+        let path = "test-get-range.db";
+        let _ = fs::remove_dir_all(path);
+
+        let open = |verify_range_reads| {
+            CaskOptions::default()
+                .sync(SyncStrategy::Never)
+                .compaction(false)
+                .blob_threshold(100 * 1024)
+                .verify_range_reads(verify_range_reads)
+                .open(path)
+                .unwrap()
+        };
+
+        let value: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let blob: Vec<u8> = (0..300 * 1024).map(|i| (i / 7) as u8).collect();
+
+        {
+            let cask = open(true);
+            cask.put("key", &value).unwrap();
+            cask.put("blob", &blob).unwrap();
+
+            assert_eq!(
+                cask.get_range("key", 10, 20).unwrap(),
+                Some(value[10..30].to_vec())
+            );
+            assert_eq!(
+                cask.get_range("key", 990, 20).unwrap(),
+                Some(value[990..].to_vec())
+            );
+            assert_eq!(cask.get_range("key", 2000, 20).unwrap(), Some(Vec::new()));
+            assert_eq!(cask.get_range("missing", 0, 20).unwrap(), None);
+
+            assert_eq!(
+                cask.get_range("blob", 100 * 1024, 100).unwrap(),
+                Some(blob[100 * 1024..100 * 1024 + 100].to_vec())
+            );
+
+            let mut reader = cask.get_reader("blob").unwrap().unwrap();
+            reader.seek(SeekFrom::End(-10)).unwrap();
+            let mut read = Vec::new();
+            reader.read_to_end(&mut read).unwrap();
+            assert_eq!(read, &blob[blob.len() - 10..]);
+        }
+
+        // flip the last byte of the value and the last byte of the blob
+        let mut data_file = OpenOptions::new()
+            .write(true)
+            .open(format!("{}/0000000001.cask.data", path))
+            .unwrap();
+        data_file
+            .seek(SeekFrom::Start(HEADER_SIZE + 20 + 3 + 999))
+            .unwrap();
+        data_file.write_all(&[0]).unwrap();
+
+        let mut blob_file = OpenOptions::new()
+            .write(true)
+            .open(format!("{}/{:020}.cask.blob", path, 2))
+            .unwrap();
+        blob_file.seek(SeekFrom::End(-5)).unwrap();
+        blob_file.write_all(&[0]).unwrap();
+
+        {
+            let cask = open(true);
+            assert!(cask.get_range("key", 0, 10).is_err());
+            assert!(cask.get("blob").is_err());
+            assert!(cask.get_range("blob", 0, 10).is_ok());
+        }
+
+        {
+            // only the requested range is read
+            let cask = open(false);
+            assert_eq!(
+                cask.get_range("key", 0, 10).unwrap(),
+                Some(value[..10].to_vec())
+            );
+            assert!(cask.get("key").is_err());
+        }
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_mmap() {
+        let path = "test-mmap.db";
+        let _ = fs::remove_dir_all(path);
+
+        let open = || {
+            CaskOptions::default()
+                .sync(SyncStrategy::Never)
+                .compaction(false)
+                .max_file_size(64)
+                .mmap(true)
+                .open(path)
+                .unwrap()
+        };
+
+        let get = |cask: &Cask, key: &str| {
+            cask.get(key).unwrap().map(|v| String::from_utf8(v).unwrap())
+        };
+
+        {
+            let cask = open();
+
+            // every entry ends up in its own data file, all but the active one are read from
+            // their map
+            for i in 0..10 {
+                cask.put(format!("key{}", i), format!("value{}", i)).unwrap();
+            }
+
+            for i in 0..10 {
+                assert_eq!(get(&cask, &format!("key{}", i)), Some(format!("value{}", i)));
+            }
+
+            // the maps of compacted files are retired
+            cask.delete("key0").unwrap();
+            cask.compact_files(&[1, 2]).unwrap();
+
+            assert_eq!(get(&cask, "key0"), None);
+            for i in 1..10 {
+                assert_eq!(get(&cask, &format!("key{}", i)), Some(format!("value{}", i)));
+            }
+        }
+
+        {
+            let cask = open();
+            for i in 1..10 {
+                assert_eq!(get(&cask, &format!("key{}", i)), Some(format!("value{}", i)));
+            }
+        }
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_cache() {
+        let path = "test-cache.db";
+        let _ = fs::remove_dir_all(path);
+
+        let cask = CaskOptions::default()
+            .sync(SyncStrategy::Never)
+            .compaction(false)
+            .max_file_size(64)
+            .cache_size(1024)
+            .open(path)
+            .unwrap();
+
+        let cached = |key: &str| {
+            let inner = cask.inner.read().unwrap();
+            let index_entry = inner.index.get(key.as_bytes()).unwrap();
+            inner.cache.get(index_entry.file_id, index_entry.entry_pos)
+        };
+
+        cask.put("key1", "value1").unwrap();
+        cask.put("key2", "value2").unwrap();
+
+        // values are only cached once they're read
+        assert_eq!(cached("key1"), None);
+        assert_eq!(cask.get("key1").unwrap(), Some(b"value1".to_vec()));
+        assert_eq!(cached("key1"), Some(b"value1".to_vec()));
+
+        cask.put("key1", "value3").unwrap();
+        assert_eq!(cask.get("key1").unwrap(), Some(b"value3".to_vec()));
+
+        cask.delete("key1").unwrap();
+        assert_eq!(cask.get("key1").unwrap(), None);
+
+        // the values of compacted files are dropped and read again from their new location
+        assert_eq!(cask.get("key2").unwrap(), Some(b"value2".to_vec()));
+        cask.compact_files(&[1, 2, 3]).unwrap();
+        assert_eq!(cached("key2"), None);
+        assert_eq!(cask.get("key2").unwrap(), Some(b"value2".to_vec()));
+        assert_eq!(cached("key2"), Some(b"value2".to_vec()));
+
+        drop(cask);
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_group_commit() {
+        let path = "test-group-commit.db";
+        let _ = fs::remove_dir_all(path);
+
+        let open = || {
+            CaskOptions::default()
+                .sync(SyncStrategy::Always)
+                .compaction(false)
+                .max_file_size(1024)
+                .open(path)
+                .unwrap()
+        };
+
+        {
+            let cask = open();
+
+            let threads: Vec<_> = (0..8)
+                .map(|t| {
+                    let cask = cask.clone();
+                    thread::spawn(move || for i in 0..50 {
+                        let key = format!("key{}-{}", t, i);
+                        cask.put(key.clone(), format!("value{}-{}", t, i)).unwrap();
+                        if i % 5 == 0 {
+                            cask.delete(key).unwrap();
+                        }
+                    })
+                })
+                .collect();
+
+            for thread in threads {
+                thread.join().unwrap();
+            }
+
+            let mut batch = WriteBatch::new();
+            batch.put("batch", "value");
+            cask.write(batch).unwrap();
+        }
+
+        let cask = open();
+        for t in 0..8 {
+            for i in 0..50 {
+                let value = cask.get(format!("key{}-{}", t, i)).unwrap();
+                if i % 5 == 0 {
+                    assert_eq!(value, None);
+                } else {
+                    assert_eq!(value, Some(format!("value{}-{}", t, i).into_bytes()));
+                }
+            }
+        }
+        assert_eq!(cask.get("batch").unwrap(), Some(b"value".to_vec()));
+
+        drop(cask);
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_write_options() {
+        let path = "test-write-options.db";
+        let _ = fs::remove_dir_all(path);
+
+        let open = || {
+            CaskOptions::default()
+                .sync(SyncStrategy::Never)
+                .compaction(false)
+                .open(path)
+                .unwrap()
+        };
+
+        {
+            let cask = open();
+
+            // nothing to synchronize yet
+            cask.sync().unwrap();
+            cask.flush().unwrap();
+
+            cask.put("key1", "value1").unwrap();
+            cask.put_with("key2", "value2", WriteOptions { sync: true })
+                .unwrap();
+            cask.put_with("key3", "value3", WriteOptions::default())
+                .unwrap();
+
+            cask.sync().unwrap();
+            cask.put("key4", "value4").unwrap();
+            cask.flush().unwrap();
+        }
+
+        let cask = open();
+        for i in 1..5 {
+            assert_eq!(
+                cask.get(format!("key{}", i)).unwrap(),
+                Some(format!("value{}", i).into_bytes())
+            );
+        }
+
+        drop(cask);
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_crash_recovery() {
+        let open = |path: &str| {
+            CaskOptions::default()
+                .sync(SyncStrategy::Always)
+                .compaction(false)
+                .max_file_size(64)
+                .open(path)
+                .unwrap()
+        };
+
+        let check = |cask: &Cask| for i in 0..20 {
+            let expected = match i {
+                0..=9 => Some(format!("new{}", i).into_bytes()),
+                10..=14 => None,
+                _ => Some(format!("old{}", i).into_bytes()),
+            };
+            assert_eq!(cask.get(format!("key{}", i)).unwrap(), expected);
+        };
+
+        let strip_hint_trailer = |path: &str, file_id: u32| {
+            let hint_file_path = format!("{}/{:010}.cask.hint", path, file_id);
+            let hint_file_size = fs::metadata(&hint_file_path).unwrap().len();
+            OpenOptions::new()
+                .write(true)
+                .open(&hint_file_path)
+                .unwrap()
+                .set_len(hint_file_size - 4)
+                .unwrap();
+        };
+
+        // every step of compacting the data files, and a crash while closing a data file
+        for crash_point in 0..5 {
+            let path = format!("test-crash-{}.db", crash_point);
+            let _ = fs::remove_dir_all(&path);
+
+            let active_file_id = {
+                let cask = open(&path);
+
+                // every entry ends up in its own data file
+                for i in 0..20 {
+                    cask.put(format!("key{}", i), format!("old{}", i)).unwrap();
+                }
+                for i in 0..10 {
+                    cask.put(format!("key{}", i), format!("new{}", i)).unwrap();
+                }
+                for i in 10..15 {
+                    cask.delete(format!("key{}", i)).unwrap();
+                }
+
+                let active_file_id = cask.inner.read().unwrap().log.active_file_id.unwrap();
+                let files: Vec<_> = (1..active_file_id).collect();
+
+                match crash_point {
+                    0..=2 => {
+                        let (compacted_files, new_files, _) =
+                            cask.compact_files_aux(&files).unwrap();
+                        let inner = cask.inner.read().unwrap();
+
+                        // the compaction output is complete but wasn't moved in place
+                        if crash_point >= 1 {
+                            inner.log.publish_files(&new_files).unwrap();
+                        }
+
+                        // only part of the compacted files were removed
+                        if crash_point == 2 {
+                            for file_id in &compacted_files[..10] {
+                                fs::remove_file(format!("{}/{:010}.cask.hint", path, file_id))
+                                    .unwrap();
+                                fs::remove_file(format!("{}/{:010}.cask.data", path, file_id))
+                                    .unwrap();
+                            }
+                        }
+                    }
+                    3 => cask.compact_files(&files).unwrap(),
+                    _ => strip_hint_trailer(&path, 1),
+                }
+
+                check(&cask);
+                active_file_id
+            };
+
+            // the active data file was never closed, and its last write was torn
+            strip_hint_trailer(&path, active_file_id);
+            OpenOptions::new()
+                .append(true)
+                .open(format!("{}/{:010}.cask.data", path, active_file_id))
+                .unwrap()
+                .write_all(&[1, 2, 3])
+                .unwrap();
+
+            {
+                let cask = open(&path);
+                assert_eq!(cask.recovery_report().truncated, vec![(active_file_id, 3)]);
+                assert!(!Path::new(&path).join("compact").exists());
+                check(&cask);
+
+                // copies of entries duplicated by the crash are dropped by the next compaction
+                let files = cask.inner.read().unwrap().log.files();
+                cask.compact_files(&files).unwrap();
+                check(&cask);
+            }
+
+            check(&open(&path));
+
+            // the live entries and the tombstones of the deleted keys, without duplicates
+            let report = verify(&path).unwrap();
+            assert!(report.is_ok());
+            assert_eq!(report.files.iter().map(|f| f.entries).sum::<u64>(), 20);
+
+            assert!(fs::remove_dir_all(&path).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_crash_recovery_blob() {
+        let path = "test-crash-recovery-blob.db";
+        let _ = fs::remove_dir_all(path);
+
+        let open = || {
+            CaskOptions::default()
+                .sync(SyncStrategy::Always)
+                .compaction(false)
+                .blob_threshold(10)
+                .max_file_size(64)
+                .open(path)
+                .unwrap()
+        };
+
+        let value = b"a value stored in a blob file".to_vec();
+
+        {
+            let cask = open();
+            cask.put("a", &value).unwrap();
+            cask.put("b", "value").unwrap();
+
+            // the compaction output was moved in place but the compacted files weren't removed
+            let active_file_id = cask.inner.read().unwrap().log.active_file_id.unwrap();
+            let files: Vec<_> = (1..active_file_id).collect();
+            let (_, new_files, _) = cask.compact_files_aux(&files).unwrap();
+            cask.inner.read().unwrap().log.publish_files(&new_files).unwrap();
+        }
+
+        {
+            // the blob is kept for the copy of its entry that is still live
+            let cask = open();
+            let files = cask.inner.read().unwrap().log.files();
+            cask.compact_files(&files).unwrap();
+            assert_eq!(cask.get("a").unwrap(), Some(value.clone()));
+        }
+
+        assert_eq!(open().get("a").unwrap(), Some(value));
+        assert!(verify(path).unwrap().is_ok());
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_partial_header() {
+        let path = "test-partial-header.db";
+        let _ = fs::remove_dir_all(path);
+
+        let open = || {
+            CaskOptions::default()
+                .sync(SyncStrategy::Never)
+                .compaction(false)
+                .open(path)
+                .unwrap()
+        };
+
+        {
+            let cask = open();
+            cask.put("key", "value").unwrap();
+        }
+
+        // crashes while writing the headers of new data files, before and after the magic
+        let mut header = Vec::new();
+        FileHeader::new(None).write(&mut header).unwrap();
+        fs::write(format!("{}/{:010}.cask.data", path, 2), &header[..5]).unwrap();
+        fs::write(format!("{}/{:010}.cask.data", path, 3), &header[..10]).unwrap();
+
+        {
+            let cask = open();
+            assert_eq!(cask.inner.read().unwrap().log.files(), vec![1]);
+            assert!(!Path::new(&format!("{}/{:010}.cask.data", path, 3)).exists());
+            assert_eq!(cask.get("key").unwrap(), Some(b"value".to_vec()));
+
+            cask.put("other", "value").unwrap();
+        }
+
+        {
+            let cask = open();
+            assert_eq!(cask.get("key").unwrap(), Some(b"value".to_vec()));
+            assert_eq!(cask.get("other").unwrap(), Some(b"value".to_vec()));
+        }
+
+        assert!(verify(path).unwrap().is_ok());
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_hint_checkpoints() {
+        let path = "test-hint-checkpoints.db";
+        let _ = fs::remove_dir_all(path);
+
+        let open = || {
+            CaskOptions::default()
+                .sync(SyncStrategy::Never)
+                .compaction(false)
+                .open(path)
+                .unwrap()
+        };
+
+        let value = vec![0u8; 4096];
+
+        {
+            let cask = open();
+
+            // enough entries for a checkpoint in the middle of the hint file
+            for i in 0..300 {
+                cask.put(format!("key{:03}", i), &value[..]).unwrap();
+            }
+        }
+
+        let data_file_path = format!("{}/0000000001.cask.data", path);
+        let hint_file_path = format!("{}/0000000001.cask.hint", path);
+
+        // simulate a crash: the last checkpoint of the hint file is lost and the last write was
+        // torn
+        let hint_file_size = fs::metadata(&hint_file_path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&hint_file_path)
+            .unwrap()
+            .set_len(hint_file_size - 4)
+            .unwrap();
+        let mut data_file = OpenOptions::new().write(true).open(&data_file_path).unwrap();
+        data_file.seek(SeekFrom::End(0)).unwrap();
+        data_file.write_all(&[1, 2, 3]).unwrap();
+
+        // flip a byte in the value of the first entry, which is covered by the checkpoint and
+        // therefore not read again
+        data_file.seek(SeekFrom::Start(HEADER_SIZE + 30)).unwrap();
+        data_file.write_all(&[1]).unwrap();
+
+        {
+            let cask = open();
+            assert_eq!(cask.recovery_report().truncated, vec![(1, 3)]);
+
+            assert!(cask.get("key000").is_err());
+            for i in 1..300 {
+                assert_eq!(cask.get(format!("key{:03}", i)).unwrap(), Some(value.clone()));
+            }
+
+            // the hints of the active data file are complete once flushed
+            cask.put("key300", &value[..]).unwrap();
+            cask.flush().unwrap();
+            let inner = cask.inner.read().unwrap();
+            let active_file_id = inner.log.active_file_id.unwrap();
+            assert_eq!(inner.log.hints(active_file_id).unwrap().unwrap().count(), 1);
+        }
+
+        // the hint file is complete again
+        assert!(open().recovery_report().truncated.is_empty());
+        assert_eq!(verify(path).unwrap().files[0].errors.len(), 3);
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_checkpoint_interval() {
+        let path = "test-checkpoint-interval.db";
+        let _ = fs::remove_dir_all(path);
+
+        let value = vec![0u8; 4096];
+
+        let intervals = [(1024 * 1024, true), (16 * 1024 * 1024, false)];
+        for &(checkpoint_interval, checkpointed) in &intervals {
+            let cask = CaskOptions::default()
+                .sync(SyncStrategy::Never)
+                .compaction(false)
+                .checkpoint_interval(checkpoint_interval)
+                .open(path)
+                .unwrap();
+
+            for i in 0..300 {
+                cask.put(format!("key{:03}", i), &value[..]).unwrap();
+            }
+
+            // the hints of the active data file are only written along with a checkpoint
+            let inner = cask.inner.read().unwrap();
+            let active_file_id = inner.log.active_file_id.unwrap();
+            let hints = inner.log.hints(active_file_id).unwrap().unwrap();
+            assert_eq!(hints.take_while(|h| h.is_ok()).count() > 0, checkpointed);
+
+            drop(inner);
+            drop(cask);
+            assert!(fs::remove_dir_all(path).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_recreate_hints_checkpoint_interval() {
+        let path = "test-recreate-hints-checkpoint-interval.db";
+        let _ = fs::remove_dir_all(path);
+
+        let open = || {
+            CaskOptions::default()
+                .sync(SyncStrategy::Never)
+                .compaction(false)
+                .checkpoint_interval(8192)
+                .open(path)
+                .unwrap()
+        };
+
+        // every entry is 4122 bytes long
+        let value = vec![0u8; 4096];
+
+        {
+            let cask = open();
+            for i in 0..10 {
+                cask.put(format!("key{:03}", i), &value[..]).unwrap();
+            }
+        }
+
+        let hint_file_path = format!("{}/0000000001.cask.hint", path);
+        fs::remove_file(&hint_file_path).unwrap();
+
+        {
+            let cask = open();
+            for i in 0..10 {
+                assert_eq!(cask.get(format!("key{:03}", i)).unwrap(), Some(value.clone()));
+            }
+        }
+
+        // a checkpoint after every other entry and the one closing the hint file, every block
+        // is made of its size, its hints and a checkpoint of 12 bytes
+        let hints = fs::read(&hint_file_path).unwrap();
+        let mut pos = HEADER_SIZE as usize;
+        let mut checkpoints = 0;
+        while pos < hints.len() {
+            let block_size = (&hints[pos..]).read_u32::<LittleEndian>().unwrap() as usize;
+            pos += 4 + block_size + 12;
+            checkpoints += 1;
+        }
+        assert_eq!(pos, hints.len());
+        assert_eq!(checkpoints, 6);
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_hint_file_validation() {
+        let path = "test-hint-file-validation.db";
+        let _ = fs::remove_dir_all(path);
+
+        let open = |corruption_policy| {
+            CaskOptions::default()
+                .sync(SyncStrategy::Never)
+                .compaction(false)
+                .corruption_policy(corruption_policy)
+                .open(path)
+                .unwrap()
+        };
+
+        let value = vec![0u8; 4096];
+
+        {
+            let cask = open(CorruptionPolicy::Fail);
+            for i in 0..300 {
+                cask.put(format!("key{:03}", i), &value[..]).unwrap();
+            }
+        }
+
+        let data_file_path = format!("{}/0000000001.cask.data", path);
+        let hint_file_path = format!("{}/0000000001.cask.hint", path);
+
+        // flip a byte in the first block of hints, none of its hints are used
+        let mut hint_file = OpenOptions::new().write(true).open(&hint_file_path).unwrap();
+        hint_file.seek(SeekFrom::Start(HEADER_SIZE + 10)).unwrap();
+        hint_file.write_all(&[1]).unwrap();
+
+        assert_eq!(
+            verify(path).unwrap().files[0].errors,
+            vec![VerifyError::InvalidHintFile]
+        );
+
+        {
+            let cask = open(CorruptionPolicy::Fail);
+            assert_eq!(cask.recovery_report(), &RecoveryReport::default());
+            for i in 0..300 {
+                assert_eq!(cask.get(format!("key{:03}", i)).unwrap(), Some(value.clone()));
+            }
+        }
+
+        assert!(verify(path).unwrap().is_ok());
+
+        // lose the last checkpoint and flip a byte in an entry past the first one, every entry
+        // is 4122 bytes long
+        let hint_file_size = fs::metadata(&hint_file_path).unwrap().len();
+        hint_file.set_len(hint_file_size - 4).unwrap();
+        let mut data_file = OpenOptions::new().write(true).open(&data_file_path).unwrap();
+        data_file.seek(SeekFrom::Start(HEADER_SIZE + 280 * 4122 + 30)).unwrap();
+        data_file.write_all(&[1]).unwrap();
+
+        // the hints covered by the first checkpoint aren't applied either
+        let cask = open(CorruptionPolicy::QuarantineFile);
+        assert_eq!(cask.recovery_report().quarantined, vec![1]);
+        assert!(cask.keys().is_empty());
+        drop(cask);
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
 }
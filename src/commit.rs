@@ -0,0 +1,161 @@
+use std::cmp;
+use std::fs::File;
+use std::io::Result;
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Coordinates the synchronization of writes to disk so that a single `fsync` covers all the
+/// writes appended since the previous one.
+///
+/// Writes are numbered in the order they're appended to the log. A writer waiting for its write
+/// to be durable either finds that a sync already covered it, waits for the sync in progress, or
+/// syncs the data file itself on behalf of every write appended so far. Since the sync happens
+/// after the writer has released the `Cask`, other writers keep appending in the meantime and are
+/// covered by the next sync.
+pub struct GroupCommit {
+    state: Mutex<State>,
+    synced: Condvar,
+}
+
+struct State {
+    // number of writes appended so far
+    written: u64,
+    // number of writes known to be durable
+    synced: u64,
+    // data file the last write was appended to, previous data files are synced when closed
+    data_file: Option<Arc<File>>,
+    syncing: bool,
+}
+
+/// A write that was appended to the log but may not be durable yet.
+pub struct Commit {
+    group_commit: Arc<GroupCommit>,
+    ticket: u64,
+}
+
+impl GroupCommit {
+    pub fn new() -> GroupCommit {
+        GroupCommit {
+            state: Mutex::new(State {
+                written: 0,
+                synced: 0,
+                data_file: None,
+                syncing: false,
+            }),
+            synced: Condvar::new(),
+        }
+    }
+
+    /// Records a write appended to `data_file`. Must be called in the order writes are appended.
+    pub fn append(&self, data_file: &Arc<File>) {
+        let mut state = self.state.lock().unwrap();
+        state.written += 1;
+
+        if !state.data_file.as_ref().is_some_and(|f| Arc::ptr_eq(f, data_file)) {
+            state.data_file = Some(data_file.clone());
+        }
+    }
+
+    /// Returns the last write appended so far.
+    pub fn last(group_commit: &Arc<GroupCommit>) -> Commit {
+        Commit {
+            group_commit: group_commit.clone(),
+            ticket: group_commit.state.lock().unwrap().written,
+        }
+    }
+
+    fn wait(&self, ticket: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+
+        loop {
+            if state.synced >= ticket {
+                return Ok(());
+            }
+
+            if state.syncing {
+                state = self.synced.wait(state).unwrap();
+                continue;
+            }
+
+            // lead a new group with every write appended so far
+            state.syncing = true;
+            let target = state.written;
+            let data_file = state.data_file.clone();
+            drop(state);
+
+            let res = match data_file {
+                Some(data_file) => data_file.sync_data(),
+                None => Ok(()),
+            };
+
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+            if res.is_ok() {
+                state.synced = cmp::max(state.synced, target);
+            }
+            // on failure one of the waiting writers leads another attempt
+            self.synced.notify_all();
+
+            res?;
+        }
+    }
+}
+
+impl Commit {
+    /// Blocks until the write is durable.
+    pub fn wait(self) -> Result<()> {
+        self.group_commit.wait(self.ticket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::fs::File;
+    use std::sync::Arc;
+    use std::thread;
+
+    use commit::GroupCommit;
+
+    #[test]
+    fn test_group_commit() {
+        let path = "test-group-commit";
+        let _ = fs::remove_file(path);
+
+        let data_file = Arc::new(File::create(path).unwrap());
+        let group_commit = Arc::new(GroupCommit::new());
+
+        // nothing to sync yet
+        GroupCommit::last(&group_commit).wait().unwrap();
+
+        group_commit.append(&data_file);
+        let first = GroupCommit::last(&group_commit);
+        group_commit.append(&data_file);
+
+        // a single sync covers both writes
+        first.wait().unwrap();
+        assert_eq!(group_commit.state.lock().unwrap().synced, 2);
+        GroupCommit::last(&group_commit).wait().unwrap();
+
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let group_commit = group_commit.clone();
+                let data_file = data_file.clone();
+                thread::spawn(move || for _ in 0..10 {
+                    group_commit.append(&data_file);
+                    GroupCommit::last(&group_commit).wait().unwrap();
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let state = group_commit.state.lock().unwrap();
+        assert_eq!(state.written, 82);
+        assert_eq!(state.synced, 82);
+        assert!(!state.syncing);
+
+        assert!(fs::remove_file(path).is_ok());
+    }
+}
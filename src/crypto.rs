@@ -0,0 +1,382 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use chacha20poly1305::{Key, Tag, XChaCha20Poly1305, XNonce};
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use getrandom::getrandom;
+
+use cask::Compression;
+use data::{Entry, Hint, MAX_KEY_SIZE, SequenceNumber};
+use errors::{Error, Result};
+use header::FileHeader;
+
+pub const KEY_SIZE: usize = 32;
+pub const NONCE_PREFIX_SIZE: usize = 16;
+// the tag is stored after the encrypted key of every entry and hint
+pub const TAG_SIZE: usize = 16;
+
+/// The keys used to encrypt and decrypt data and hint files, indexed by the key id stored in the
+/// file headers.
+#[derive(Clone, Default)]
+pub struct Keyring {
+    current: Option<u32>,
+    keys: HashMap<u32, [u8; KEY_SIZE]>,
+}
+
+impl Keyring {
+    /// Adds `key`, making it the key used to encrypt new files if `current` is `true`.
+    pub fn add(&mut self, key_id: u32, key: [u8; KEY_SIZE], current: bool) {
+        self.keys.insert(key_id, key);
+        if current {
+            self.current = Some(key_id);
+        }
+    }
+
+    /// Returns the id of the key used to encrypt new files, if encryption is enabled.
+    pub fn current_key_id(&self) -> Option<u32> {
+        self.current
+    }
+
+    /// Creates the cipher for a new file, encrypted with the current key.
+    pub fn new_cipher(&self) -> Result<Option<FileCipher>> {
+        match self.current {
+            Some(key_id) => Ok(Some(self.cipher(key_id, random_nonce_prefix()?)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the cipher of a file with `header`, or `None` if the file isn't encrypted.
+    pub fn file_cipher(&self, header: &FileHeader) -> Result<Option<FileCipher>> {
+        match header.encryption {
+            Some((key_id, nonce_prefix)) => Ok(Some(self.cipher(key_id, nonce_prefix)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn cipher(&self, key_id: u32, nonce_prefix: [u8; NONCE_PREFIX_SIZE]) -> Result<FileCipher> {
+        let key = self.keys.get(&key_id).ok_or(
+            Error::MissingEncryptionKey(key_id),
+        )?;
+
+        Ok(FileCipher {
+            key_id: key_id,
+            nonce_prefix: nonce_prefix,
+            aead: XChaCha20Poly1305::new(Key::from_slice(key)),
+        })
+    }
+}
+
+/// Encrypts and decrypts the entries of a data file, the hints of a hint file, or the chunks of a
+/// blob file, with XChaCha20-Poly1305.
+///
+/// The nonce of each entry is made of the random prefix stored in the file header followed by the
+/// position of the entry (or the index of the chunk), so it's never reused as long as the prefix is
+/// unique.
+#[derive(Clone)]
+pub struct FileCipher {
+    key_id: u32,
+    nonce_prefix: [u8; NONCE_PREFIX_SIZE],
+    aead: XChaCha20Poly1305,
+}
+
+impl FileCipher {
+    pub fn key_id(&self) -> u32 {
+        self.key_id
+    }
+
+    /// Returns a cipher with the same key and a new nonce prefix, for use in another file.
+    pub fn renew(&self) -> Result<FileCipher> {
+        Ok(FileCipher {
+            key_id: self.key_id,
+            nonce_prefix: random_nonce_prefix()?,
+            aead: self.aead.clone(),
+        })
+    }
+
+    /// Returns the header of a file encrypted with this cipher.
+    pub fn header(&self) -> FileHeader {
+        FileHeader::new(Some((self.key_id, self.nonce_prefix)))
+    }
+
+    /// Encrypts the key and value of `entry`, which is going to be written at `entry_pos`.
+    pub fn encrypt_entry<'a>(&self, entry: &Entry, entry_pos: u64) -> Result<Entry<'a>> {
+        let aad = aad(
+            entry.sequence,
+            entry.deleted,
+            entry.batch,
+            entry.blob,
+            entry.expires,
+            entry.compression,
+        );
+        let (key, value) = self.seal(entry_pos, &aad, &entry.key, &entry.value)?;
+
+        Ok(Entry {
+            key: Cow::from(key),
+            value: Cow::from(value),
+            sequence: entry.sequence,
+            deleted: entry.deleted,
+            batch: entry.batch,
+            blob: entry.blob,
+            expires: entry.expires,
+            compression: entry.compression,
+        })
+    }
+
+    /// Decrypts the key and value of the `entry` read at `entry_pos`, failing if it wasn't
+    /// encrypted with this cipher or was tampered with.
+    pub fn decrypt_entry<'a>(&self, entry: Entry, entry_pos: u64) -> Result<Entry<'a>> {
+        let aad = aad(
+            entry.sequence,
+            entry.deleted,
+            entry.batch,
+            entry.blob,
+            entry.expires,
+            entry.compression,
+        );
+        let (key, value) = self.open(entry_pos, &aad, &entry.key, &entry.value)?;
+
+        Ok(Entry {
+            key: Cow::from(key),
+            value: Cow::from(value),
+            sequence: entry.sequence,
+            deleted: entry.deleted,
+            batch: entry.batch,
+            blob: entry.blob,
+            expires: entry.expires,
+            compression: entry.compression,
+        })
+    }
+
+    pub fn encrypt_hint<'a>(&self, hint: &Hint) -> Result<Hint<'a>> {
+        let aad = aad(
+            hint.sequence,
+            hint.deleted,
+            false,
+            hint.blob,
+            hint.expires,
+            hint.compression,
+        );
+        let (key, _) = self.seal(hint.entry_pos, &aad, &hint.key, &[])?;
+
+        Ok(Hint {
+            key: Cow::from(key),
+            entry_pos: hint.entry_pos,
+            value_size: hint.value_size,
+            sequence: hint.sequence,
+            deleted: hint.deleted,
+            blob: hint.blob,
+            expires: hint.expires,
+            compression: hint.compression,
+        })
+    }
+
+    pub fn decrypt_hint<'a>(&self, hint: Hint) -> Result<Hint<'a>> {
+        let aad = aad(
+            hint.sequence,
+            hint.deleted,
+            false,
+            hint.blob,
+            hint.expires,
+            hint.compression,
+        );
+        let (key, _) = self.open(hint.entry_pos, &aad, &hint.key, &[])?;
+
+        Ok(Hint {
+            key: Cow::from(key),
+            entry_pos: hint.entry_pos,
+            value_size: hint.value_size,
+            sequence: hint.sequence,
+            deleted: hint.deleted,
+            blob: hint.blob,
+            expires: hint.expires,
+            compression: hint.compression,
+        })
+    }
+
+    /// Encrypts the chunk `index` of the blob written for `sequence` in place, appending its tag.
+    pub fn seal_chunk(
+        &self,
+        sequence: SequenceNumber,
+        index: u64,
+        chunk: &mut Vec<u8>,
+    ) -> Result<()> {
+        let tag = self.aead
+            .encrypt_in_place_detached(&self.nonce(index), &chunk_aad(sequence, index), chunk)
+            .map_err(|_| Error::AuthenticationFailed)?;
+        chunk.extend_from_slice(&tag);
+        Ok(())
+    }
+
+    /// Decrypts the chunk `index` of the blob written for `sequence` in place, removing its tag.
+    pub fn open_chunk(
+        &self,
+        sequence: SequenceNumber,
+        index: u64,
+        chunk: &mut Vec<u8>,
+    ) -> Result<()> {
+        if chunk.len() < TAG_SIZE {
+            return Err(Error::AuthenticationFailed);
+        }
+
+        let size = chunk.len() - TAG_SIZE;
+        let tag = Tag::clone_from_slice(&chunk[size..]);
+        chunk.truncate(size);
+
+        self.aead
+            .decrypt_in_place_detached(&self.nonce(index), &chunk_aad(sequence, index), chunk, &tag)
+            .map_err(|_| Error::AuthenticationFailed)
+    }
+
+    fn nonce(&self, pos: u64) -> XNonce {
+        let mut nonce = [0u8; 24];
+        nonce[..NONCE_PREFIX_SIZE].copy_from_slice(&self.nonce_prefix);
+        (&mut nonce[NONCE_PREFIX_SIZE..])
+            .write_u64::<LittleEndian>(pos)
+            .unwrap();
+        *XNonce::from_slice(&nonce)
+    }
+
+    /// Encrypts `key` and `value` as a single message. Returns the encrypted key followed by the
+    /// tag, and the encrypted value.
+    fn seal(&self, pos: u64, aad: &[u8], key: &[u8], value: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+        check_key_size(key.len())?;
+
+        let mut buf = Vec::with_capacity(key.len() + value.len() + TAG_SIZE);
+        buf.extend_from_slice(key);
+        buf.extend_from_slice(value);
+
+        let tag = self.aead
+            .encrypt_in_place_detached(&self.nonce(pos), aad, &mut buf)
+            .map_err(|_| Error::AuthenticationFailed)?;
+
+        let value = buf.split_off(key.len());
+        buf.extend_from_slice(&tag);
+
+        Ok((buf, value))
+    }
+
+    fn open(&self, pos: u64, aad: &[u8], key: &[u8], value: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+        if key.len() < TAG_SIZE {
+            return Err(Error::AuthenticationFailed);
+        }
+
+        let key_size = key.len() - TAG_SIZE;
+        let tag = Tag::clone_from_slice(&key[key_size..]);
+
+        let mut buf = Vec::with_capacity(key_size + value.len());
+        buf.extend_from_slice(&key[..key_size]);
+        buf.extend_from_slice(value);
+
+        self.aead
+            .decrypt_in_place_detached(&self.nonce(pos), aad, &mut buf, &tag)
+            .map_err(|_| Error::AuthenticationFailed)?;
+
+        let value = buf.split_off(key_size);
+
+        Ok((buf, value))
+    }
+}
+
+/// Checks that an encrypted key of `key_size` bytes, which is followed by the tag, still fits the
+/// maximum key size.
+pub fn check_key_size(key_size: usize) -> Result<()> {
+    if key_size + TAG_SIZE > MAX_KEY_SIZE as usize {
+        Err(Error::InvalidKeySize(key_size))
+    } else {
+        Ok(())
+    }
+}
+
+// binds the encrypted payload to the metadata of the entry, which is only covered by the checksum
+fn aad(
+    sequence: SequenceNumber,
+    deleted: bool,
+    batch: bool,
+    blob: bool,
+    expires: Option<u64>,
+    compression: Compression,
+) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(18);
+    aad.write_u64::<LittleEndian>(sequence).unwrap();
+    aad.write_u8(if deleted {
+        1
+    } else if batch {
+        2
+    } else if blob {
+        3
+    } else {
+        0
+    }).unwrap();
+    aad.write_u64::<LittleEndian>(expires.unwrap_or(0)).unwrap();
+    aad.write_u8(compression as u8).unwrap();
+    aad
+}
+
+// binds a chunk of a blob to the sequence number it was written for, so that blob files can't be
+// swapped between entries
+fn chunk_aad(sequence: SequenceNumber, index: u64) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(16);
+    aad.write_u64::<LittleEndian>(sequence).unwrap();
+    aad.write_u64::<LittleEndian>(index).unwrap();
+    aad
+}
+
+fn random_nonce_prefix() -> Result<[u8; NONCE_PREFIX_SIZE]> {
+    let mut nonce_prefix = [0u8; NONCE_PREFIX_SIZE];
+    getrandom(&mut nonce_prefix).map_err(|err| {
+        io::Error::other(err.to_string())
+    })?;
+    Ok(nonce_prefix)
+}
+
+#[cfg(test)]
+mod tests {
+    use crypto::{Keyring, TAG_SIZE};
+    use data::{Entry, Hint};
+    use errors::Error;
+    use header::FileHeader;
+
+    #[test]
+    fn test_encryption() {
+        let mut keyring = Keyring::default();
+        keyring.add(1, [1; 32], true);
+
+        let cipher = keyring.new_cipher().unwrap().unwrap();
+        assert_eq!(cipher.key_id(), 1);
+
+        let header = cipher.header();
+        let read = keyring.file_cipher(&header).unwrap().unwrap();
+
+        let entry = Entry::new(1, &b"key"[..], &b"value"[..]).unwrap();
+        let encrypted = cipher.encrypt_entry(&entry, 32).unwrap();
+        assert_eq!(encrypted.size(), entry.size() + TAG_SIZE as u64);
+        assert!(encrypted.key != entry.key && encrypted.value != entry.value);
+
+        let bytes = encrypted.to_bytes().unwrap();
+        let decrypted = read.decrypt_entry(Entry::from_bytes(&bytes).unwrap(), 32)
+            .unwrap();
+        assert_eq!(decrypted, entry);
+
+        // the entry is bound to its position and metadata
+        match read.decrypt_entry(Entry::from_bytes(&bytes).unwrap(), 64) {
+            Err(Error::AuthenticationFailed) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        let mut tampered = Entry::from_bytes(&bytes).unwrap();
+        tampered.sequence = 2;
+        assert!(read.decrypt_entry(tampered, 32).is_err());
+
+        let hint = Hint::new(&entry, 32);
+        let decrypted = read.decrypt_hint(cipher.encrypt_hint(&hint).unwrap()).unwrap();
+        assert_eq!(decrypted.key, hint.key);
+
+        assert!(keyring.file_cipher(&FileHeader::new(None)).unwrap().is_none());
+
+        match Keyring::default().file_cipher(&header) {
+            Err(Error::MissingEncryptionKey(1)) => {}
+            _ => panic!("expected missing key"),
+        }
+    }
+}
@@ -1,17 +1,43 @@
 use std::borrow::Cow;
+use std::io;
 use std::io::prelude::*;
 use std::io::Cursor;
 use std::result::Result::{Err, Ok};
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use lz4_flex;
+use zstd;
+
+use cask::Compression;
 
 use errors::{Error, Result};
-use util::{XxHash32, xxhash32};
+use header::FORMAT_VERSION;
+use util::{XxHash32, now_millis, xxhash32};
 
-const ENTRY_STATIC_SIZE: usize = 18; // checksum(4) + sequence(8) + key_size(2) + value_size(4)
+const ENTRY_STATIC_SIZE: usize = 20; // checksum(4) + sequence(8) + key_size(4) + value_size(4)
+// format version from which key sizes are stored as u32 instead of u16
+const WIDE_KEYS_VERSION: u16 = 2;
 const ENTRY_TOMBSTONE: u32 = !0;
+const ENTRY_BATCH: u32 = !0 - 1;
+// format version from which a value size of `ENTRY_BATCH` marks a batch, it's a valid value size
+// in files written before
+const BATCH_VERSION: u16 = 1;
+// set on the value size of entries and hints that are followed by an extension: flags(1) and the
+// optional fields enabled by the flags
+const ENTRY_EXTENSION: u32 = 1 << 31;
+// format version from which `ENTRY_EXTENSION` is set on the value size, it's part of the value
+// size in files written before
+const EXTENSION_VERSION: u16 = 1;
+const FLAG_EXPIRES: u8 = 1; // expiry(8), in milliseconds since the unix epoch
+const FLAG_LZ4: u8 = 1 << 1; // the value is compressed with lz4
+const FLAG_ZSTD: u8 = 1 << 2; // the value is compressed with zstd
+const FLAG_BLOB: u8 = 1 << 3; // the value is the length of the value stored in a blob file
+const KNOWN_FLAGS: u8 = FLAG_EXPIRES | FLAG_LZ4 | FLAG_ZSTD | FLAG_BLOB;
 pub const MAX_VALUE_SIZE: u32 = !0 - 1;
-pub const MAX_KEY_SIZE: u16 = !0;
+// the value size of an entry must not overlap with `ENTRY_EXTENSION` and, once it's set, be
+// mistaken for a tombstone or a batch marker. Larger values are stored in blob files.
+pub const MAX_INLINE_VALUE_SIZE: u32 = ENTRY_EXTENSION - 3;
+pub const MAX_KEY_SIZE: u32 = !0;
 
 pub type SequenceNumber = u64;
 
@@ -21,6 +47,10 @@ pub struct Entry<'a> {
     pub value: Cow<'a, [u8]>,
     pub sequence: SequenceNumber,
     pub deleted: bool,
+    pub batch: bool,
+    pub blob: bool,
+    pub expires: Option<u64>,
+    pub compression: Compression,
 }
 
 impl<'a> Entry<'a> {
@@ -36,7 +66,7 @@ impl<'a> Entry<'a> {
             return Err(Error::InvalidKeySize(k.len()));
         }
 
-        if v.len() > MAX_VALUE_SIZE as usize {
+        if v.len() > MAX_INLINE_VALUE_SIZE as usize {
             return Err(Error::InvalidValueSize(v.len()));
         }
 
@@ -45,6 +75,10 @@ impl<'a> Entry<'a> {
             value: v,
             sequence: sequence,
             deleted: false,
+            batch: false,
+            blob: false,
+            expires: None,
+            compression: Compression::None,
         })
     }
 
@@ -57,28 +91,166 @@ impl<'a> Entry<'a> {
             value: Cow::Borrowed(&[]),
             sequence: sequence,
             deleted: true,
+            batch: false,
+            blob: false,
+            expires: None,
+            compression: Compression::None,
+        }
+    }
+
+    /// Creates a batch marker, which precedes the `count` entries of an atomic batch. The count
+    /// is stored as the key of the marker.
+    pub fn batch(sequence: SequenceNumber, count: u32) -> Entry<'a> {
+        let mut key = Vec::with_capacity(4);
+        key.write_u32::<LittleEndian>(count).unwrap();
+
+        Entry {
+            key: Cow::from(key),
+            value: Cow::Borrowed(&[]),
+            sequence: sequence,
+            deleted: false,
+            batch: true,
+            blob: false,
+            expires: None,
+            compression: Compression::None,
+        }
+    }
+
+    /// Creates an entry referencing a value of `len` bytes stored in the blob file of
+    /// `blob_sequence`, the sequence number reserved for the blob when it was written. The length
+    /// is stored as the value of the entry, followed by `blob_sequence` if it isn't the sequence
+    /// number of the entry.
+    pub fn blob<K>(
+        sequence: SequenceNumber,
+        key: K,
+        len: u64,
+        blob_sequence: SequenceNumber,
+    ) -> Result<Entry<'a>>
+    where
+        Cow<'a, [u8]>: From<K>,
+    {
+        let key = Cow::from(key);
+
+        if key.len() > MAX_KEY_SIZE as usize {
+            return Err(Error::InvalidKeySize(key.len()));
+        }
+
+        let mut value = Vec::with_capacity(16);
+        value.write_u64::<LittleEndian>(len)?;
+        if blob_sequence != sequence {
+            value.write_u64::<LittleEndian>(blob_sequence)?;
+        }
+
+        Ok(Entry {
+            key: key,
+            value: Cow::Owned(value),
+            sequence: sequence,
+            deleted: false,
+            batch: false,
+            blob: true,
+            expires: None,
+            compression: Compression::None,
+        })
+    }
+
+    /// Returns the length of the value if it's stored in a blob file.
+    pub fn blob_size(&self) -> Option<u64> {
+        if self.blob {
+            Cursor::new(&*self.value).read_u64::<LittleEndian>().ok()
+        } else {
+            None
+        }
+    }
+
+    /// Returns the sequence number of the blob file holding the value of a blob entry, which is
+    /// the sequence number of the entry unless another one was reserved for the blob.
+    pub fn blob_sequence(&self) -> SequenceNumber {
+        let mut cursor = Cursor::new(&*self.value);
+        cursor.set_position(8);
+        cursor.read_u64::<LittleEndian>().unwrap_or(self.sequence)
+    }
+
+    /// Returns the number of entries in the batch if this entry is a batch marker.
+    pub fn batch_size(&self) -> Option<u32> {
+        if self.batch {
+            Cursor::new(&*self.key).read_u32::<LittleEndian>().ok()
+        } else {
+            None
         }
     }
 
+    /// Compresses the value with `compression` if it's at least `threshold` bytes long, unless
+    /// compression doesn't make it smaller.
+    pub fn compress(&mut self, compression: Compression, threshold: usize) -> Result<()> {
+        if compression == Compression::None || self.compression != Compression::None ||
+            self.blob || self.value.len() < threshold
+        {
+            return Ok(());
+        }
+
+        let compressed = match compression {
+            Compression::Lz4 => lz4_flex::compress_prepend_size(&self.value),
+            Compression::Zstd => zstd::encode_all(&*self.value, 0)?,
+            Compression::None => unreachable!(),
+        };
+
+        if compressed.len() < self.value.len() {
+            self.value = Cow::from(compressed);
+            self.compression = compression;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the value, decompressing it if needed.
+    pub fn into_value(self) -> Result<Vec<u8>> {
+        Ok(match self.compression {
+            Compression::None => self.value.into_owned(),
+            Compression::Lz4 => {
+                lz4_flex::decompress_size_prepended(&self.value).map_err(|err| {
+                    io::Error::new(io::ErrorKind::InvalidData, err)
+                })?
+            }
+            Compression::Zstd => zstd::decode_all(&*self.value)?,
+        })
+    }
+
+    fn flags(&self) -> u8 {
+        flags(self.expires, self.compression, self.blob)
+    }
+
+    fn write_header<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_u64::<LittleEndian>(self.sequence)?;
+        writer.write_u32::<LittleEndian>(self.key.len() as u32)?;
+
+        if self.deleted {
+            writer.write_u32::<LittleEndian>(ENTRY_TOMBSTONE)?;
+        } else if self.batch {
+            writer.write_u32::<LittleEndian>(ENTRY_BATCH)?;
+        } else {
+            write_value_size(writer, self.value.len() as u32, self.flags(), self.expires)?;
+        }
+
+        Ok(())
+    }
+
     pub fn size(&self) -> u64 {
-        ENTRY_STATIC_SIZE as u64 + self.key.len() as u64 + self.value.len() as u64
+        ENTRY_STATIC_SIZE as u64 + extension_size(self.flags()) + self.key.len() as u64 +
+            self.value.len() as u64
+    }
+
+    /// Returns the size of the entry in a file written in format `version`.
+    pub fn size_in(&self, version: u16) -> u64 {
+        self.size() - (ENTRY_STATIC_SIZE - static_size(version)) as u64
     }
 
     #[allow(dead_code)]
     pub fn to_bytes(&self) -> Result<Vec<u8>> {
         let mut cursor = Cursor::new(Vec::with_capacity(self.size() as usize));
         cursor.set_position(4);
-        cursor.write_u64::<LittleEndian>(self.sequence)?;
-        cursor.write_u16::<LittleEndian>(self.key.len() as u16)?;
-
-        if self.deleted {
-            cursor.write_u32::<LittleEndian>(ENTRY_TOMBSTONE)?;
-            cursor.write_all(&self.key)?;
-        } else {
-            cursor.write_u32::<LittleEndian>(self.value.len() as u32)?;
-            cursor.write_all(&self.key)?;
-            cursor.write_all(&self.value)?;
-        }
+        self.write_header(&mut cursor)?;
+        cursor.write_all(&self.key)?;
+        cursor.write_all(&self.value)?;
 
         let checksum = xxhash32(&cursor.get_ref()[4..]);
         cursor.set_position(0);
@@ -90,14 +262,7 @@ impl<'a> Entry<'a> {
     pub fn write_bytes<W: Write>(&self, writer: &mut W) -> Result<()> {
         let mut cursor = Cursor::new(Vec::with_capacity(ENTRY_STATIC_SIZE));
         cursor.set_position(4);
-        cursor.write_u64::<LittleEndian>(self.sequence)?;
-        cursor.write_u16::<LittleEndian>(self.key.len() as u16)?;
-
-        if self.deleted {
-            cursor.write_u32::<LittleEndian>(ENTRY_TOMBSTONE)?;
-        } else {
-            cursor.write_u32::<LittleEndian>(self.value.len() as u32)?;
-        }
+        self.write_header(&mut cursor)?;
 
         let checksum = {
             let mut hasher = XxHash32::new();
@@ -112,10 +277,7 @@ impl<'a> Entry<'a> {
 
         writer.write_all(&cursor.into_inner())?;
         writer.write_all(&self.key)?;
-
-        if !self.deleted {
-            writer.write_all(&self.value)?;
-        }
+        writer.write_all(&self.value)?;
 
         Ok(())
     }
@@ -136,74 +298,168 @@ impl<'a> Entry<'a> {
         }
 
         let sequence = cursor.read_u64::<LittleEndian>()?;
-        let key_size = cursor.read_u16::<LittleEndian>()?;
+        let key_size = cursor.read_u32::<LittleEndian>()?;
         let value_size = cursor.read_u32::<LittleEndian>()?;
 
         let deleted = value_size == ENTRY_TOMBSTONE;
+        let batch = value_size == ENTRY_BATCH;
+        let (_, flags, expires) =
+            read_extension(&mut cursor, value_size, FORMAT_VERSION, &mut Vec::new())?;
+        let compression = check_flags(flags)?;
 
-        let value = if deleted {
+        let key_pos = cursor.position() as usize;
+        let value_pos = key_pos + key_size as usize;
+
+        let value = if deleted || batch {
             let empty: &[u8] = &[];
             Cow::from(empty)
         } else {
-            Cow::from(&bytes[ENTRY_STATIC_SIZE + key_size as usize..])
+            Cow::from(&bytes[value_pos..])
         };
 
         Ok(Entry {
-            key: Cow::from(
-                &bytes[ENTRY_STATIC_SIZE..ENTRY_STATIC_SIZE + key_size as usize],
-            ),
+            key: Cow::from(&bytes[key_pos..value_pos]),
             value: value,
             sequence: sequence,
             deleted: value_size == ENTRY_TOMBSTONE,
+            batch: batch,
+            blob: flags & FLAG_BLOB != 0,
+            expires: expires,
+            compression: compression,
         })
     }
 
-    pub fn from_read<R: Read>(reader: &mut R) -> Result<Entry<'a>> {
-        let mut header = vec![0u8; ENTRY_STATIC_SIZE as usize];
-        reader.read_exact(&mut header)?;
+    /// Reads an entry from a file written in format `version`.
+    pub fn from_read<R: Read>(reader: &mut R, version: u16) -> Result<Entry<'a>> {
+        Entry::from_read_bounded(reader, u64::MAX, version)
+    }
 
-        let mut cursor = Cursor::new(header);
-        let checksum = cursor.read_u32::<LittleEndian>()?;
-        let sequence = cursor.read_u64::<LittleEndian>()?;
-        let key_size = cursor.read_u16::<LittleEndian>()?;
-        let value_size = cursor.read_u32::<LittleEndian>()?;
+    /// Reads an entry that is known to fit in `limit` bytes. Entries whose header claims a larger
+    /// size (e.g. a torn or corrupt header) fail with `UnexpectedEof` before any key or value
+    /// buffers are allocated.
+    pub fn from_read_bounded<R: Read>(
+        reader: &mut R,
+        limit: u64,
+        version: u16,
+    ) -> Result<Entry<'a>> {
+        let header = EntryHeader::read(reader, version)?;
+
+        if header.entry_size() > limit {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "entry extends past the end of the file",
+            )));
+        }
 
-        let mut key = vec![0u8; key_size as usize];
+        let mut key = vec![0u8; header.key_size as usize];
         reader.read_exact(&mut key)?;
 
-        let deleted = value_size == ENTRY_TOMBSTONE;
-
-        let value = if deleted {
-            let empty: &[u8] = &[];
-            Cow::from(empty)
-        } else {
-            let mut value = vec![0u8; value_size as usize];
-            reader.read_exact(&mut value)?;
-            Cow::from(value)
-        };
+        let mut value = vec![0u8; header.stored_value_size() as usize];
+        reader.read_exact(&mut value)?;
 
         let hash = {
             let mut hasher = XxHash32::new();
-            hasher.update(&cursor.get_ref()[4..]);
+            hasher.update(&header.bytes);
             hasher.update(&key);
             hasher.update(&value);
             hasher.get()
         };
 
-        if hash != checksum {
+        if hash != header.checksum {
             return Err(Error::InvalidChecksum {
-                expected: checksum,
+                expected: header.checksum,
                 found: hash,
             });
         }
 
+        let compression = check_flags(header.flags)?;
+
         Ok(Entry {
             key: Cow::from(key),
-            value: value,
+            value: Cow::from(value),
+            sequence: header.sequence,
+            deleted: header.deleted,
+            batch: header.batch,
+            blob: header.flags & FLAG_BLOB != 0,
+            expires: header.expires,
+            compression: compression,
+        })
+    }
+}
+
+/// The fixed part of an entry and its extension, which precede the key and the value.
+pub struct EntryHeader {
+    checksum: u32,
+    // the bytes covered by the checksum
+    bytes: Vec<u8>,
+    pub sequence: SequenceNumber,
+    pub key_size: u32,
+    pub value_size: u32,
+    pub deleted: bool,
+    pub batch: bool,
+    flags: u8,
+    pub expires: Option<u64>,
+}
+
+impl EntryHeader {
+    /// Reads the header of an entry from a file written in format `version`.
+    pub fn read<R: Read>(reader: &mut R, version: u16) -> Result<EntryHeader> {
+        let mut header = vec![0u8; static_size(version)];
+        reader.read_exact(&mut header)?;
+
+        let mut cursor = Cursor::new(header);
+        let checksum = cursor.read_u32::<LittleEndian>()?;
+        let sequence = cursor.read_u64::<LittleEndian>()?;
+        let key_size = read_key_size(&mut cursor, version)?;
+        let value_size = cursor.read_u32::<LittleEndian>()?;
+
+        let mut bytes = cursor.into_inner().split_off(4);
+        let (value_size, flags, expires) = read_extension(reader, value_size, version, &mut bytes)?;
+
+        Ok(EntryHeader {
+            checksum: checksum,
+            bytes: bytes,
             sequence: sequence,
-            deleted: deleted,
+            key_size: key_size,
+            value_size: value_size,
+            deleted: value_size == ENTRY_TOMBSTONE,
+            batch: version >= BATCH_VERSION && value_size == ENTRY_BATCH,
+            flags: flags,
+            expires: expires,
         })
     }
+
+    /// Returns the size of the header, i.e. the offset of the key from the start of the entry.
+    pub fn size(&self) -> u64 {
+        4 + self.bytes.len() as u64
+    }
+
+    /// Returns the size of the whole entry, including its key and value.
+    pub fn entry_size(&self) -> u64 {
+        self.size() + self.key_size as u64 + self.stored_value_size() as u64
+    }
+
+    /// Returns `true` if the header could be the one of a valid entry of at most `limit` bytes,
+    /// which is cheaper to check than reading the whole entry to validate its checksum.
+    pub fn is_plausible(&self, limit: u64) -> bool {
+        self.entry_size() <= limit && check_flags(self.flags).is_ok()
+    }
+
+    /// Returns the size of the value as stored after the key, which tombstones and batch markers
+    /// don't have.
+    pub fn stored_value_size(&self) -> u32 {
+        if self.deleted || self.batch {
+            0
+        } else {
+            self.value_size
+        }
+    }
+
+    /// Returns `true` if the value is stored as is after the key, i.e. it isn't compressed nor a
+    /// reference to a blob file, so that it can be read partially.
+    pub fn is_plain(&self) -> bool {
+        !self.deleted && !self.batch && self.flags & (FLAG_LZ4 | FLAG_ZSTD | FLAG_BLOB) == 0
+    }
 }
 
 pub struct Hint<'a> {
@@ -212,6 +468,9 @@ pub struct Hint<'a> {
     pub value_size: u32,
     pub sequence: SequenceNumber,
     pub deleted: bool,
+    pub blob: bool,
+    pub expires: Option<u64>,
+    pub compression: Compression,
 }
 
 impl<'a> Hint<'a> {
@@ -222,6 +481,9 @@ impl<'a> Hint<'a> {
             value_size: e.value.len() as u32,
             sequence: e.sequence,
             deleted: e.deleted,
+            blob: e.blob,
+            expires: e.expires,
+            compression: e.compression,
         }
     }
 
@@ -232,21 +494,32 @@ impl<'a> Hint<'a> {
             value_size: e.value.len() as u32,
             sequence: e.sequence,
             deleted: e.deleted,
+            blob: e.blob,
+            expires: e.expires,
+            compression: e.compression,
         }
     }
 
+    /// Returns `true` if the entry has an expiry time which has passed.
+    pub fn is_expired(&self) -> bool {
+        is_expired(self.expires)
+    }
+
     pub fn entry_size(&self) -> u64 {
-        ENTRY_STATIC_SIZE as u64 + self.key.len() as u64 + self.value_size as u64
+        let flags = flags(self.expires, self.compression, self.blob);
+        ENTRY_STATIC_SIZE as u64 + extension_size(flags) + self.key.len() as u64 +
+            self.value_size as u64
     }
 
     pub fn write_bytes<W: Write>(&self, writer: &mut W) -> Result<()> {
         writer.write_u64::<LittleEndian>(self.sequence)?;
-        writer.write_u16::<LittleEndian>(self.key.len() as u16)?;
+        writer.write_u32::<LittleEndian>(self.key.len() as u32)?;
 
         if self.deleted {
             writer.write_u32::<LittleEndian>(ENTRY_TOMBSTONE)?;
         } else {
-            writer.write_u32::<LittleEndian>(self.value_size)?;
+            let flags = flags(self.expires, self.compression, self.blob);
+            write_value_size(writer, self.value_size, flags, self.expires)?;
         }
 
         writer.write_u64::<LittleEndian>(self.entry_pos)?;
@@ -255,10 +528,14 @@ impl<'a> Hint<'a> {
         Ok(())
     }
 
-    pub fn from_read<R: Read>(reader: &mut R) -> Result<Hint<'a>> {
+    /// Reads a hint from a file written in format `version`.
+    pub fn from_read<R: Read>(reader: &mut R, version: u16) -> Result<Hint<'a>> {
         let sequence = reader.read_u64::<LittleEndian>()?;
-        let key_size = reader.read_u16::<LittleEndian>()?;
+        let key_size = read_key_size(reader, version)?;
         let value_size = reader.read_u32::<LittleEndian>()?;
+        let (value_size, flags, expires) =
+            read_extension(reader, value_size, version, &mut Vec::new())?;
+        let compression = check_flags(flags)?;
         let entry_pos = reader.read_u64::<LittleEndian>()?;
 
         let mut key = vec![0u8; key_size as usize];
@@ -271,16 +548,147 @@ impl<'a> Hint<'a> {
             entry_pos: entry_pos,
             value_size: if deleted { 0 } else { value_size },
             sequence: sequence,
-            deleted: value_size == ENTRY_TOMBSTONE,
+            deleted: deleted,
+            blob: flags & FLAG_BLOB != 0,
+            expires: expires,
+            compression: compression,
         })
     }
 }
 
+/// Returns `true` if the expiry time `expires` has passed.
+pub fn is_expired(expires: Option<u64>) -> bool {
+    match expires {
+        Some(expires) => expires <= now_millis(),
+        None => false,
+    }
+}
+
+/// Returns the size of the fixed part of an entry in format `version`.
+fn static_size(version: u16) -> usize {
+    if version >= WIDE_KEYS_VERSION {
+        ENTRY_STATIC_SIZE
+    } else {
+        ENTRY_STATIC_SIZE - 2
+    }
+}
+
+fn read_key_size<R: Read>(reader: &mut R, version: u16) -> Result<u32> {
+    Ok(if version >= WIDE_KEYS_VERSION {
+        reader.read_u32::<LittleEndian>()?
+    } else {
+        reader.read_u16::<LittleEndian>()? as u32
+    })
+}
+
+fn flags(expires: Option<u64>, compression: Compression, blob: bool) -> u8 {
+    let mut flags = if expires.is_some() { FLAG_EXPIRES } else { 0 };
+
+    if blob {
+        flags |= FLAG_BLOB;
+    }
+
+    flags |= match compression {
+        Compression::None => 0,
+        Compression::Lz4 => FLAG_LZ4,
+        Compression::Zstd => FLAG_ZSTD,
+    };
+
+    flags
+}
+
+fn extension_size(flags: u8) -> u64 {
+    if flags == 0 {
+        0
+    } else {
+        1 + if flags & FLAG_EXPIRES != 0 { 8 } else { 0 }
+    }
+}
+
+/// Writes the value size of a live entry, followed by the extension if any flags are set.
+fn write_value_size<W: Write>(
+    writer: &mut W,
+    value_size: u32,
+    flags: u8,
+    expires: Option<u64>,
+) -> Result<()> {
+    // values this large can only be found in files written before extensions
+    if value_size > MAX_INLINE_VALUE_SIZE {
+        return Err(Error::InvalidValueSize(value_size as usize));
+    }
+
+    if flags == 0 {
+        writer.write_u32::<LittleEndian>(value_size)?;
+    } else {
+        writer.write_u32::<LittleEndian>(value_size | ENTRY_EXTENSION)?;
+        writer.write_u8(flags)?;
+        if let Some(expires) = expires {
+            writer.write_u64::<LittleEndian>(expires)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads the extension following `value_size` in a file written in format `version`, if any,
+/// into `buf`. Returns the actual value size along with the flags and expiry time found in the
+/// extension.
+fn read_extension<R: Read>(
+    reader: &mut R,
+    value_size: u32,
+    version: u16,
+    buf: &mut Vec<u8>,
+) -> Result<(u32, u8, Option<u64>)> {
+    if version < EXTENSION_VERSION || value_size == ENTRY_TOMBSTONE ||
+        value_size == ENTRY_BATCH || value_size & ENTRY_EXTENSION == 0
+    {
+        return Ok((value_size, 0, None));
+    }
+
+    let flags = reader.read_u8()?;
+    buf.push(flags);
+
+    let expires = if flags & FLAG_EXPIRES != 0 {
+        let expires = reader.read_u64::<LittleEndian>()?;
+        buf.write_u64::<LittleEndian>(expires)?;
+        Some(expires)
+    } else {
+        None
+    };
+
+    Ok((value_size & !ENTRY_EXTENSION, flags, expires))
+}
+
+/// Validates the extension flags, returning the compression of the value.
+fn check_flags(flags: u8) -> Result<Compression> {
+    let compression = match flags & (FLAG_LZ4 | FLAG_ZSTD) {
+        0 => Some(Compression::None),
+        FLAG_LZ4 => Some(Compression::Lz4),
+        FLAG_ZSTD => Some(Compression::Zstd),
+        _ => None,
+    };
+
+    match compression {
+        Some(compression) if flags & !KNOWN_FLAGS == 0 => Ok(compression),
+        _ => {
+            Err(Error::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported entry flags: {:#x}", flags),
+            )))
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::io::Cursor;
 
-    use data::Entry;
+    use byteorder::{LittleEndian, WriteBytesExt};
+
+    use cask::Compression;
+    use data::{Entry, EntryHeader, Hint, is_expired};
+    use header::FORMAT_VERSION;
+    use util::xxhash32;
 
     #[test]
     fn test_serialization() {
@@ -290,7 +698,7 @@ mod tests {
         let entry = Entry::new(sequence, key, value).unwrap();
         let deleted_entry = Entry::deleted(sequence, key);
 
-        assert_eq!(entry.to_bytes().unwrap().len(), 24);
+        assert_eq!(entry.to_bytes().unwrap().len(), 26);
 
         assert_eq!(
             entry,
@@ -298,7 +706,7 @@ mod tests {
         );
         assert_eq!(
             entry,
-            Entry::from_read(&mut Cursor::new(entry.to_bytes().unwrap())).unwrap()
+            Entry::from_read(&mut Cursor::new(entry.to_bytes().unwrap()), FORMAT_VERSION).unwrap()
         );
         let mut v = Vec::new();
         entry.write_bytes(&mut v).unwrap();
@@ -310,7 +718,10 @@ mod tests {
         );
         assert_eq!(
             deleted_entry,
-            Entry::from_read(&mut Cursor::new(deleted_entry.to_bytes().unwrap())).unwrap()
+            Entry::from_read(
+                &mut Cursor::new(deleted_entry.to_bytes().unwrap()),
+                FORMAT_VERSION,
+            ).unwrap()
         );
         v.clear();
         deleted_entry.write_bytes(&mut v).unwrap();
@@ -325,4 +736,154 @@ mod tests {
         assert!(Entry::deleted(sequence, key).deleted);
         assert_eq!(Entry::deleted(sequence, key).value.len(), 0);
     }
+
+    #[test]
+    fn test_expires() {
+        let key: &[u8] = &[0, 0, 0];
+        let value: &[u8] = &[0, 0, 0];
+        let mut entry = Entry::new(0, key, value).unwrap();
+        entry.expires = Some(1);
+
+        assert_eq!(entry.to_bytes().unwrap().len(), 35);
+        assert!(is_expired(entry.expires));
+        assert_eq!(
+            entry,
+            Entry::from_bytes(&entry.to_bytes().unwrap()).unwrap()
+        );
+        assert_eq!(
+            entry,
+            Entry::from_read(&mut Cursor::new(entry.to_bytes().unwrap()), FORMAT_VERSION).unwrap()
+        );
+
+        let hint = Hint::new(&entry, 0);
+        assert_eq!(hint.entry_size(), 35);
+
+        let mut v = Vec::new();
+        hint.write_bytes(&mut v).unwrap();
+        let read = Hint::from_read(&mut Cursor::new(v), FORMAT_VERSION).unwrap();
+        assert_eq!(read.expires, Some(1));
+        assert_eq!(read.value_size, 3);
+        assert_eq!(read.key, hint.key);
+    }
+
+    #[test]
+    fn test_compression() {
+        let key: &[u8] = &[0, 0, 0];
+        let value = vec![1u8; 1000];
+
+        for &compression in &[Compression::Lz4, Compression::Zstd] {
+            let mut entry = Entry::new(0, key, &*value).unwrap();
+            entry.compress(compression, 2000).unwrap();
+            assert_eq!(entry.compression, Compression::None);
+
+            entry.compress(compression, 1000).unwrap();
+            assert_eq!(entry.compression, compression);
+            assert!(entry.value.len() < 100);
+
+            let bytes = entry.to_bytes().unwrap();
+            let read = Entry::from_read(&mut Cursor::new(bytes), FORMAT_VERSION).unwrap();
+            assert_eq!(read, entry);
+            assert_eq!(Hint::new(&entry, 0).entry_size(), entry.size());
+            assert_eq!(read.into_value().unwrap(), value);
+        }
+
+        // incompressible values are stored as is
+        let mut entry = Entry::new(0, key, &[1, 2, 3][..]).unwrap();
+        entry.compress(Compression::Zstd, 0).unwrap();
+        assert_eq!(entry.compression, Compression::None);
+    }
+
+    #[test]
+    fn test_batch() {
+        let marker = Entry::batch(0, 3);
+
+        assert_eq!(marker.batch_size(), Some(3));
+        assert_eq!(marker.size(), 24);
+        assert_eq!(
+            marker,
+            Entry::from_read(&mut Cursor::new(marker.to_bytes().unwrap()), FORMAT_VERSION).unwrap()
+        );
+        assert_eq!(Entry::new(0, &[0][..], &[0][..]).unwrap().batch_size(), None);
+
+        // files written before batches were supported can have values of that size
+        let mut bytes = vec![0u8; 4 + 8];
+        bytes.write_u16::<LittleEndian>(0).unwrap();
+        bytes.write_u32::<LittleEndian>(!0 - 1).unwrap();
+
+        let header = EntryHeader::read(&mut Cursor::new(&bytes), 0).unwrap();
+        assert!(!header.batch);
+        assert_eq!(header.stored_value_size(), !0 - 1);
+        assert!(EntryHeader::read(&mut Cursor::new(&bytes), 1).unwrap().batch);
+    }
+
+    #[test]
+    fn test_legacy_value_size() {
+        // files written before extensions can have values with the highest bit of their size set
+        let value_size = (1 << 31) | 5;
+
+        let mut bytes = vec![0u8; 4 + 8];
+        bytes.write_u16::<LittleEndian>(0).unwrap();
+        bytes.write_u32::<LittleEndian>(value_size).unwrap();
+
+        let header = EntryHeader::read(&mut Cursor::new(&bytes), 0).unwrap();
+        assert!(header.is_plain());
+        assert_eq!(header.stored_value_size(), value_size);
+        assert_eq!(header.size(), 18);
+
+        let mut bytes = vec![0u8; 8];
+        bytes.write_u16::<LittleEndian>(1).unwrap();
+        bytes.write_u32::<LittleEndian>(value_size).unwrap();
+        bytes.write_u64::<LittleEndian>(7).unwrap();
+        bytes.push(b'k');
+
+        let hint = Hint::from_read(&mut Cursor::new(&bytes), 0).unwrap();
+        assert_eq!(hint.value_size, value_size);
+        assert_eq!(hint.entry_pos, 7);
+        assert_eq!(hint.key, &b"k"[..]);
+
+        // but they can't be written to files with extensions
+        let mut v = Vec::new();
+        assert!(hint.write_bytes(&mut v).is_err());
+    }
+
+    #[test]
+    fn test_key_size() {
+        let key = vec![1u8; 70000];
+        let value: &[u8] = &[0, 0, 0];
+        let entry = Entry::new(0, &*key, value).unwrap();
+
+        let read = Entry::from_read(&mut Cursor::new(entry.to_bytes().unwrap()), FORMAT_VERSION)
+            .unwrap();
+        assert_eq!(read, entry);
+
+        let mut v = Vec::new();
+        Hint::new(&entry, 0).write_bytes(&mut v).unwrap();
+        let read = Hint::from_read(&mut Cursor::new(v), FORMAT_VERSION).unwrap();
+        assert_eq!(read.key, entry.key);
+    }
+
+    #[test]
+    fn test_legacy_layout() {
+        let key: &[u8] = &[0, 0, 0];
+        let value: &[u8] = &[1, 1, 1];
+        let entry = Entry::new(0, key, value).unwrap();
+
+        // files written before format version 2 store the key size as a u16
+        let mut bytes = entry.to_bytes().unwrap();
+        bytes.drain(14..16);
+        let checksum = xxhash32(&bytes[4..]);
+        (&mut bytes[..4]).write_u32::<LittleEndian>(checksum).unwrap();
+
+        assert_eq!(Entry::from_read(&mut Cursor::new(&bytes), 1).unwrap(), entry);
+        assert!(Entry::from_read(&mut Cursor::new(&bytes), FORMAT_VERSION).is_err());
+
+        let mut v = Vec::new();
+        Hint::new(&entry, 7).write_bytes(&mut v).unwrap();
+        v.drain(10..12);
+
+        let read = Hint::from_read(&mut Cursor::new(v), 0).unwrap();
+        assert_eq!(read.key, entry.key);
+        assert_eq!(read.entry_pos, 7);
+        assert_eq!(read.value_size, 3);
+    }
 }
@@ -22,6 +22,14 @@ pub enum Error {
     InvalidChecksum { expected: u32, found: u32 },
     /// Invalid path provided.
     InvalidPath(String),
+    /// A key read by a transaction was modified before the transaction committed.
+    TransactionConflict,
+    /// A file is encrypted with a key that wasn't provided.
+    MissingEncryptionKey(u32),
+    /// Encrypted data failed authentication, i.e. it was tampered with or the wrong key was used.
+    AuthenticationFailed,
+    /// A file was written in a format version that isn't supported.
+    UnsupportedVersion(u16),
 }
 
 /// Value returned from potentially-error operations.
@@ -57,6 +65,14 @@ impl Display for Error {
                 )
             }
             Error::InvalidPath(ref path) => write!(f, "Invalid path provided: {}", path),
+            Error::TransactionConflict => write!(f, "Transaction conflict"),
+            Error::MissingEncryptionKey(key_id) => {
+                write!(f, "Missing encryption key with id: {}", key_id)
+            }
+            Error::AuthenticationFailed => write!(f, "Failed to authenticate encrypted data"),
+            Error::UnsupportedVersion(version) => {
+                write!(f, "Unsupported file format version: {}", version)
+            }
         }
     }
 }
@@ -77,6 +93,10 @@ impl error::Error for Error {
             Error::InvalidKeySize(..) => "Invalid key size",
             Error::InvalidValueSize(..) => "Invalid value size",
             Error::InvalidPath(..) => "Invalid path",
+            Error::TransactionConflict => "Transaction conflict",
+            Error::MissingEncryptionKey(..) => "Missing encryption key",
+            Error::AuthenticationFailed => "Failed to authenticate encrypted data",
+            Error::UnsupportedVersion(..) => "Unsupported file format version",
         }
     }
 
@@ -1,86 +1,176 @@
-use std::collections::{HashMap, VecDeque};
-use std::collections::hash_map::Entry;
+use std::collections::HashMap;
 use std::fs::File;
-
+use std::io::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+/// A bounded cache of read-only file handles, one per file.
+///
+/// The handles are shared by all readers, which must use positional reads since the handles have
+/// no cursor of their own. Lookups only take a shared lock, so that concurrent reads never wait on
+/// each other. Handles are evicted with the CLOCK approximation of LRU: a lookup marks the handle
+/// as recently used, and eviction skips (and unmarks) the handles marked since it last passed
+/// them, which keeps every operation O(1) amortized without reordering anything on lookups.
 pub struct FilePool {
-    queue: VecDeque<u32>,
-    files: HashMap<u32, Vec<File>>,
     capacity: usize,
-    size: usize,
+    handles: RwLock<Handles>,
+}
+
+struct Handles {
+    slots: Vec<Slot>,
+    index: HashMap<u32, usize>,
+    // next slot considered for eviction
+    hand: usize,
+}
+
+struct Slot {
+    file_id: u32,
+    file: Arc<File>,
+    referenced: AtomicBool,
 }
 
 impl FilePool {
     pub fn new(capacity: usize) -> FilePool {
         FilePool {
-            queue: VecDeque::new(),
-            files: HashMap::new(),
             capacity: capacity,
-            size: 0,
+            handles: RwLock::new(Handles {
+                slots: Vec::new(),
+                index: HashMap::new(),
+                hand: 0,
+            }),
         }
     }
 
-    pub fn get(&mut self, file_id: u32) -> Option<File> {
-        let mut remove = false;
-
-        let f = self.files.get_mut(&file_id).and_then(|v| {
-            let f = v.pop();
-            if v.is_empty() {
-                remove = true;
+    /// Returns the handle of `file_id`, opening it with `open` if it isn't cached.
+    pub fn get<F>(&self, file_id: u32, open: F) -> Result<Arc<File>>
+    where
+        F: FnOnce() -> Result<File>,
+    {
+        {
+            let handles = self.handles.read().unwrap();
+            if let Some(&i) = handles.index.get(&file_id) {
+                let slot = &handles.slots[i];
+                slot.referenced.store(true, Ordering::Relaxed);
+                return Ok(slot.file.clone());
             }
-            f
-        });
+        }
 
-        if f.is_some() {
-            if remove {
-                self.files.remove(&file_id);
-            }
+        let file = Arc::new(open()?);
 
-            if let Some(index) = self.queue.iter().position(|&f| f == file_id) {
-                self.queue.remove(index);
+        if self.capacity > 0 {
+            let mut handles = self.handles.write().unwrap();
+
+            // another reader may have opened the file in the meantime
+            if let Some(&i) = handles.index.get(&file_id) {
+                return Ok(handles.slots[i].file.clone());
             }
 
-            self.size -= 1;
+            handles.insert(file_id, file.clone(), self.capacity);
         }
 
-        f
+        Ok(file)
     }
 
-    pub fn put(&mut self, file_id: u32, file: File) {
-        self.queue.push_back(file_id);
+    /// Removes the handle of `file_id`, e.g. after the file was removed or replaced. Readers still
+    /// holding the handle can keep using it.
+    pub fn remove(&self, file_id: u32) {
+        self.handles.write().unwrap().remove(file_id);
+    }
+}
 
-        match self.files.entry(file_id) {
-            Entry::Occupied(mut o) => {
-                o.get_mut().push(file);
-            }
-            Entry::Vacant(e) => {
-                e.insert(vec![file]);
-            }
+impl Handles {
+    fn insert(&mut self, file_id: u32, file: Arc<File>, capacity: usize) {
+        let slot = Slot {
+            file_id: file_id,
+            file: file,
+            referenced: AtomicBool::new(false),
+        };
+
+        if self.slots.len() < capacity {
+            self.index.insert(file_id, self.slots.len());
+            self.slots.push(slot);
+            return;
         }
 
-        self.size += 1;
+        loop {
+            let hand = self.hand;
+            self.hand = (self.hand + 1) % self.slots.len();
 
-        if self.size > self.capacity {
-            self.remove_lru();
+            // give a second chance to the handles used since the hand last passed them
+            if !self.slots[hand].referenced.swap(false, Ordering::Relaxed) {
+                self.index.remove(&self.slots[hand].file_id);
+                self.index.insert(file_id, hand);
+                self.slots[hand] = slot;
+                return;
+            }
         }
     }
 
-    fn remove_lru(&mut self) {
-        if let Some(file_id) = self.queue.pop_front() {
-            let mut remove = false;
-
-            if let Some(files) = self.files.get_mut(&file_id) {
-                files.pop();
+    fn remove(&mut self, file_id: u32) {
+        if let Some(i) = self.index.remove(&file_id) {
+            self.slots.swap_remove(i);
 
-                if files.is_empty() {
-                    remove = true;
-                }
-
-                self.size -= 1;
+            if i < self.slots.len() {
+                self.index.insert(self.slots[i].file_id, i);
             }
 
-            if remove {
-                self.files.remove(&file_id);
+            if self.hand >= self.slots.len() {
+                self.hand = 0;
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::fs::File;
+    use std::io::ErrorKind;
+    use std::sync::Arc;
+
+    use file_pool::FilePool;
+
+    #[test]
+    fn test_file_pool() {
+        let path = "test-file-pool";
+        let _ = fs::remove_file(path);
+        File::create(path).unwrap();
+
+        let pool = FilePool::new(2);
+        let open = || File::open(path);
+        let cached = |pool: &FilePool, file_id| {
+            pool.get(file_id, || Err(ErrorKind::NotFound.into())).is_ok()
+        };
+
+        // the same handle is shared between readers
+        let file = pool.get(1, open).unwrap();
+        assert!(Arc::ptr_eq(&file, &pool.get(1, open).unwrap()));
+
+        // 1 was used since it was opened, so it gets a second chance and 2 is evicted instead
+        pool.get(2, open).unwrap();
+        pool.get(3, open).unwrap();
+        assert!(!cached(&pool, 2));
+        assert!(cached(&pool, 1));
+        assert!(cached(&pool, 3));
+
+        // both were used, the hand goes around once and evicts 1
+        pool.get(4, open).unwrap();
+        assert!(!cached(&pool, 1));
+        assert!(cached(&pool, 3));
+        assert!(cached(&pool, 4));
+
+        // removed handles are opened again
+        pool.remove(3);
+        assert!(!cached(&pool, 3));
+        pool.get(5, open).unwrap();
+        assert!(cached(&pool, 4));
+        assert!(cached(&pool, 5));
+
+        // a pool without capacity doesn't cache anything
+        let pool = FilePool::new(0);
+        pool.get(1, open).unwrap();
+        assert!(!cached(&pool, 1));
+
+        assert!(fs::remove_file(path).is_ok());
+    }
+}
@@ -0,0 +1,298 @@
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crypto::NONCE_PREFIX_SIZE;
+use errors::{Error, Result};
+use util::xxhash32;
+
+/// Version of the format of the data and hint files written by this version of `Cask`. Files
+/// written before file headers were introduced are read as version `0`.
+///
+/// - `1`: adds the file header, batch markers and entry extensions (flags, expiry time).
+/// - `2`: stores key sizes as a u32 instead of a u16.
+/// - `3`: writes hint files in blocks, each ending with a checkpoint.
+pub const FORMAT_VERSION: u16 = 3;
+pub const HEADER_SIZE: u64 = 16; // magic(8) + version(2) + flags(2) + checksum(4)
+const ENCRYPTION_HEADER_SIZE: u64 = 20; // key_id(4) + nonce_prefix(16)
+// a non-ascii first byte and line endings that are mangled by text mode transfers, as in png
+const MAGIC: &'static [u8; 8] = b"\x89CASK\r\n\x1a";
+const FLAG_ENCRYPTED: u16 = 1;
+
+/// The header at the start of every data and hint file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileHeader {
+    pub version: u16,
+    /// Id of the encryption key and nonce prefix of an encrypted file.
+    pub encryption: Option<(u32, [u8; NONCE_PREFIX_SIZE])>,
+}
+
+impl FileHeader {
+    pub fn new(encryption: Option<(u32, [u8; NONCE_PREFIX_SIZE])>) -> FileHeader {
+        FileHeader {
+            version: FORMAT_VERSION,
+            encryption: encryption,
+        }
+    }
+
+    /// Returns the size of the header, i.e. the position of the first entry or hint in the file.
+    pub fn size(&self) -> u64 {
+        if self.version == 0 {
+            0
+        } else if self.encryption.is_some() {
+            HEADER_SIZE + ENCRYPTION_HEADER_SIZE
+        } else {
+            HEADER_SIZE
+        }
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let mut header = Vec::with_capacity(self.size() as usize);
+        header.write_all(MAGIC)?;
+        header.write_u16::<LittleEndian>(self.version)?;
+
+        match self.encryption {
+            Some((key_id, ref nonce_prefix)) => {
+                header.write_u16::<LittleEndian>(FLAG_ENCRYPTED)?;
+                header.write_u32::<LittleEndian>(key_id)?;
+                header.write_all(nonce_prefix)?;
+            }
+            None => header.write_u16::<LittleEndian>(0)?,
+        }
+
+        let checksum = xxhash32(&header);
+        header.write_u32::<LittleEndian>(checksum)?;
+
+        writer.write_all(&header)?;
+        Ok(())
+    }
+
+    /// Returns whether the whole contents of a file are a header that was cut short, as left
+    /// behind by a crash while the file was being created. Such a file doesn't hold any data.
+    pub fn is_partial<R: Read>(reader: &mut R) -> Result<bool> {
+        let mut bytes = Vec::new();
+        reader
+            .take(HEADER_SIZE + ENCRYPTION_HEADER_SIZE)
+            .read_to_end(&mut bytes)?;
+
+        if bytes.len() <= MAGIC.len() {
+            return Ok(bytes[..] == MAGIC[..bytes.len()]);
+        }
+
+        if &bytes[..MAGIC.len()] != MAGIC {
+            return Ok(false);
+        }
+
+        // the flags tell whether the header is followed by the encryption header
+        let flags_pos = MAGIC.len() + 2;
+        if bytes.len() < flags_pos + 2 {
+            return Ok(true);
+        }
+
+        let flags = (&bytes[flags_pos..]).read_u16::<LittleEndian>()?;
+        let size = if flags & FLAG_ENCRYPTED != 0 {
+            HEADER_SIZE + ENCRYPTION_HEADER_SIZE
+        } else {
+            HEADER_SIZE
+        };
+
+        Ok((bytes.len() as u64) < size)
+    }
+
+    /// Reads the header at the start of a file that failed to be read with `read` without
+    /// validating it, for salvaging the entries of a file whose header is corrupt. A version or
+    /// flags that aren't supported are presumed to be the ones of a file written by this version
+    /// without encryption.
+    pub fn presume<R: Read>(reader: &mut R) -> Result<FileHeader> {
+        let mut bytes = Vec::new();
+        reader
+            .take(HEADER_SIZE + ENCRYPTION_HEADER_SIZE)
+            .read_to_end(&mut bytes)?;
+
+        let mut header = FileHeader::new(None);
+        if bytes.len() < HEADER_SIZE as usize {
+            return Ok(header);
+        }
+
+        let mut cursor = &bytes[MAGIC.len()..];
+        let version = cursor.read_u16::<LittleEndian>()?;
+        if version > 0 && version <= FORMAT_VERSION {
+            header.version = version;
+        }
+
+        let flags = cursor.read_u16::<LittleEndian>()?;
+        if flags == FLAG_ENCRYPTED && bytes.len() as u64 == HEADER_SIZE + ENCRYPTION_HEADER_SIZE {
+            let key_id = cursor.read_u32::<LittleEndian>()?;
+            let mut nonce_prefix = [0u8; NONCE_PREFIX_SIZE];
+            cursor.read_exact(&mut nonce_prefix)?;
+            header.encryption = Some((key_id, nonce_prefix));
+        }
+
+        Ok(header)
+    }
+
+    /// Reads the header at the start of a file, leaving `reader` positioned after it. Files
+    /// without a header are left untouched and read as version `0`. Fails if the file was written
+    /// in a format version that isn't supported.
+    pub fn read<R: Read + Seek>(reader: &mut R) -> Result<FileHeader> {
+        let mut magic = Vec::with_capacity(MAGIC.len());
+        reader.by_ref().take(MAGIC.len() as u64).read_to_end(
+            &mut magic,
+        )?;
+
+        if &magic[..] != MAGIC {
+            reader.seek(SeekFrom::Start(0))?;
+            return Ok(FileHeader {
+                version: 0,
+                encryption: None,
+            });
+        }
+
+        let mut header = magic;
+        let version = reader.read_u16::<LittleEndian>()?;
+
+        // the layout of the rest of the header may change in later versions
+        if version == 0 || version > FORMAT_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        let flags = reader.read_u16::<LittleEndian>()?;
+        header.write_u16::<LittleEndian>(version)?;
+        header.write_u16::<LittleEndian>(flags)?;
+
+        if flags & !FLAG_ENCRYPTED != 0 {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported file flags: {:#x}", flags),
+            )));
+        }
+
+        let encryption = if flags & FLAG_ENCRYPTED != 0 {
+            let key_id = reader.read_u32::<LittleEndian>()?;
+            let mut nonce_prefix = [0u8; NONCE_PREFIX_SIZE];
+            reader.read_exact(&mut nonce_prefix)?;
+
+            header.write_u32::<LittleEndian>(key_id)?;
+            header.write_all(&nonce_prefix)?;
+
+            Some((key_id, nonce_prefix))
+        } else {
+            None
+        };
+
+        let checksum = reader.read_u32::<LittleEndian>()?;
+        let hash = xxhash32(&header);
+
+        if hash != checksum {
+            return Err(Error::InvalidChecksum {
+                expected: checksum,
+                found: hash,
+            });
+        }
+
+        Ok(FileHeader {
+            version: version,
+            encryption: encryption,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use byteorder::{LittleEndian, WriteBytesExt};
+
+    use errors::Error;
+    use header::{FORMAT_VERSION, FileHeader, HEADER_SIZE};
+
+    #[test]
+    fn test_header() {
+        for header in &[FileHeader::new(None), FileHeader::new(Some((7, [1; 16])))] {
+            let mut bytes = Vec::new();
+            header.write(&mut bytes).unwrap();
+            bytes.extend_from_slice(b"data");
+            assert_eq!(bytes.len() as u64, header.size() + 4);
+
+            let mut cursor = Cursor::new(&bytes);
+            assert_eq!(&FileHeader::read(&mut cursor).unwrap(), header);
+            assert_eq!(cursor.position(), header.size());
+        }
+
+        // files without a header are read from the start
+        let mut cursor = Cursor::new(b"legacy data");
+        let header = FileHeader::read(&mut cursor).unwrap();
+        assert_eq!(header.version, 0);
+        assert_eq!(header.size(), 0);
+        assert_eq!(cursor.position(), 0);
+
+        // files written by a later version are rejected
+        let mut bytes = Vec::new();
+        FileHeader::new(None).write(&mut bytes).unwrap();
+        (&mut bytes[8..10])
+            .write_u16::<LittleEndian>(FORMAT_VERSION + 1)
+            .unwrap();
+        match FileHeader::read(&mut Cursor::new(&bytes)) {
+            Err(Error::UnsupportedVersion(version)) => assert_eq!(version, FORMAT_VERSION + 1),
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        // a corrupt header isn't mistaken for a legacy file
+        let mut bytes = Vec::new();
+        FileHeader::new(None).write(&mut bytes).unwrap();
+        bytes[HEADER_SIZE as usize - 1] ^= 1;
+        match FileHeader::read(&mut Cursor::new(&bytes)) {
+            Err(Error::InvalidChecksum { .. }) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert!(!FileHeader::is_partial(&mut Cursor::new(&bytes)).unwrap());
+    }
+
+    #[test]
+    fn test_presume_header() {
+        for header in &[FileHeader::new(None), FileHeader::new(Some((7, [1; 16])))] {
+            let mut bytes = Vec::new();
+            header.write(&mut bytes).unwrap();
+            let checksum_pos = bytes.len() - 1;
+            bytes[checksum_pos] ^= 1;
+
+            assert!(FileHeader::read(&mut Cursor::new(&bytes)).is_err());
+            assert_eq!(&FileHeader::presume(&mut Cursor::new(&bytes)).unwrap(), header);
+        }
+
+        // unsupported fields are presumed to be the ones written by this version
+        let mut bytes = Vec::new();
+        FileHeader::new(None).write(&mut bytes).unwrap();
+        bytes[8..12].copy_from_slice(&[0xff; 4]);
+        assert_eq!(
+            FileHeader::presume(&mut Cursor::new(&bytes)).unwrap(),
+            FileHeader::new(None)
+        );
+        assert_eq!(
+            FileHeader::presume(&mut Cursor::new(&bytes[..5])).unwrap(),
+            FileHeader::new(None)
+        );
+    }
+
+    #[test]
+    fn test_partial_header() {
+        for header in &[FileHeader::new(None), FileHeader::new(Some((7, [1; 16])))] {
+            let mut bytes = Vec::new();
+            header.write(&mut bytes).unwrap();
+
+            // any prefix of a header is left by a crash while writing it
+            for len in 0..bytes.len() {
+                assert!(FileHeader::is_partial(&mut Cursor::new(&bytes[..len])).unwrap());
+            }
+
+            assert!(!FileHeader::is_partial(&mut Cursor::new(&bytes)).unwrap());
+            bytes.extend_from_slice(b"data");
+            assert!(!FileHeader::is_partial(&mut Cursor::new(&bytes)).unwrap());
+        }
+
+        // legacy files don't start with the magic
+        assert!(!FileHeader::is_partial(&mut Cursor::new(b"legacy")).unwrap());
+    }
+}
@@ -37,17 +37,37 @@ extern crate lazy_static;
 #[macro_use]
 extern crate log as logrs;
 extern crate byteorder;
+extern crate chacha20poly1305;
 extern crate fs2;
+extern crate getrandom;
+extern crate lz4_flex;
+extern crate memmap2;
 extern crate regex;
 extern crate time;
 extern crate twox_hash;
+extern crate zstd;
 
+mod batch;
+mod blob;
+mod cache;
 mod cask;
+mod commit;
+mod crypto;
 mod data;
 pub mod errors;
 mod file_pool;
+mod header;
 mod log;
+mod migrate;
+mod repair;
 mod stats;
 mod util;
+mod verify;
 
-pub use cask::{Cask, CaskOptions, SyncStrategy};
+pub use batch::WriteBatch;
+pub use blob::ValueReader;
+pub use cask::{Cask, CaskOptions, Compression, CorruptionPolicy, Iter, KeysIter, RecoveryReport,
+                 Snapshot, SyncStrategy, Transaction, WriteOptions};
+pub use migrate::{migrate, migrate_with_options};
+pub use repair::{RepairReport, repair};
+pub use verify::{FileReport, VerifyError, VerifyReport, verify, verify_with_options};
@@ -1,33 +1,86 @@
+use std::cmp;
+use std::collections::HashMap;
 use std::fs;
-use std::fs::File;
+use std::fs::{File, OpenOptions};
+use std::io;
 use std::io::prelude::*;
-use std::io::{Cursor, SeekFrom, Take};
+use std::io::{BufReader, Cursor, ErrorKind, SeekFrom, Take};
 use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
 use std::result::Result::Ok;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, RwLock};
+use std::vec;
 use std::vec::Vec;
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use fs2::FileExt;
+use memmap2::Mmap;
 use regex::Regex;
 
-use data::{Entry, Hint};
+use blob::{BlobReader, BlobWriter, get_blob_file_path, get_temp_blob_file_path};
+use commit::{Commit, GroupCommit};
+use crypto::{FileCipher, Keyring, TAG_SIZE, check_key_size};
+use data::{Entry, EntryHeader, Hint, SequenceNumber};
 use errors::{Error, Result};
 use file_pool::FilePool;
-use util::{Sequence, XxHash32, get_file_handle, human_readable_byte_count, xxhash32};
+use header::FileHeader;
+use util::{FileReader, Sequence, XxHash32, get_file_handle, human_readable_byte_count, sync_dir};
 
 const DATA_FILE_EXTENSION: &'static str = "cask.data";
 const HINT_FILE_EXTENSION: &'static str = "cask.hint";
 const LOCK_FILE_NAME: &'static str = "cask.lock";
+const CORRUPT_DIR_NAME: &'static str = "corrupt";
+const COMPACT_DIR_NAME: &'static str = "compact";
+// format version from which hint files are written in blocks ending with a checkpoint
+pub const CHECKPOINTS_VERSION: u16 = 3;
+// bytes of entries written to a data file between the checkpoints of its hint file, unless set
+// with `Log::set_checkpoint_interval`
+const CHECKPOINT_INTERVAL: u64 = 1024 * 1024;
+const CHECKPOINT_SIZE: u64 = 12; // data_file_pos(8) + checksum(4)
+// bytes that may be read to validate candidate entries for each byte scanned while looking for
+// the next valid entry past a corrupt region, on top of the size of the data file
+const RESYNC_READ_FACTOR: u64 = 16;
+
+/// The header of a data file along with its cipher, if it's encrypted.
+struct FileFormat {
+    header: FileHeader,
+    cipher: Option<FileCipher>,
+}
+
+/// The last valid checkpoint of a hint file, up to which the hint file can be trusted after a
+/// crash.
+///
+/// Hint files are written in blocks of hints, each ending with a checkpoint that holds the
+/// position in the data file up to which the hints cover its entries, and a checksum of the
+/// hint file up to that point. Entries are synchronized to disk before any checkpoint covering
+/// them is written, so the entries past the last valid checkpoint are the only ones that need to
+/// be read again when recovering from a crash.
+pub struct Checkpoint {
+    header: FileHeader,
+    // position of the end of the last valid block in the hint file
+    hint_file_pos: u64,
+    // state of the checksum at `hint_file_pos`
+    hint_file_hasher: XxHash32,
+    data_file_pos: u64,
+}
 
 pub struct Log {
     pub path: PathBuf,
     max_file_size: usize,
-    lock_file: File,
+    lock_file: Option<File>,
     files: Vec<u32>,
     file_id_seq: Arc<Sequence>,
-    file_pool: Mutex<FilePool>,
+    // ids of the temporary blob files
+    blob_id_seq: Sequence,
+    file_pool: FilePool,
+    keyring: Keyring,
+    // formats of the data files read so far
+    formats: RwLock<HashMap<u32, Arc<FileFormat>>>,
+    mmap: bool,
+    // memory maps of the sealed data files read so far
+    maps: RwLock<HashMap<u32, Arc<Mmap>>>,
+    sync: bool,
+    checkpoint_interval: u64,
     log_writer: LogWriter,
     pub active_file_id: Option<u32>,
 }
@@ -39,6 +92,8 @@ impl Log {
         sync: bool,
         max_file_size: usize,
         file_pool_size: usize,
+        keyring: Keyring,
+        mmap: bool,
     ) -> Result<Log> {
         let path_str = path;
         let path = PathBuf::from(path);
@@ -58,8 +113,84 @@ impl Log {
         let lock_file = File::create(path.join(LOCK_FILE_NAME))?;
         lock_file.try_lock_exclusive()?;
 
+        let mut files = find_data_files(&path)?;
+        let mut partial_files = Vec::new();
+
+        for &file_id in &files {
+            let data_file_path = get_data_file_path(&path, file_id);
+
+            // a crash while creating a data file may leave nothing but part of its header
+            if FileHeader::is_partial(&mut get_file_handle(&data_file_path, false)?)? {
+                warn!("Removing data file {} left with a partial header", file_id);
+                let _ = fs::remove_file(get_hint_file_path(&path, file_id));
+                fs::remove_file(data_file_path)?;
+                partial_files.push(file_id);
+                continue;
+            }
+
+            if let Err(err) = read_header(&data_file_path) {
+                warn!("Failed to read the header of data file {}: {}", file_id, err);
+                return Err(err);
+            }
+        }
+
+        if !partial_files.is_empty() {
+            files.retain(|file_id| !partial_files.contains(file_id));
+            sync_dir(&path)?;
+        }
+
+        let mut log = Log::new(
+            path,
+            Some(lock_file),
+            files,
+            max_file_size,
+            file_pool_size,
+            keyring,
+        );
+        log.sync = sync;
+        log.mmap = mmap;
+
+        Ok(log)
+    }
+
+    /// Opens the existing log at `path` for checking or salvaging the entries of its data files,
+    /// which are left as they are: data files with a partial or corrupt header are neither removed
+    /// nor rejected, and reading them fails until `presume_header` is used. The lock is only taken
+    /// if `lock` is set, since it creates the lock file, so that a check leaves the log untouched.
+    pub fn open_unchecked(
+        path: &str,
+        lock: bool,
+        file_pool_size: usize,
+        keyring: Keyring,
+    ) -> Result<Log> {
+        let path_str = path;
+        let path = PathBuf::from(path);
+
+        if !path.is_dir() {
+            return Err(Error::InvalidPath(path_str.to_string()));
+        }
+
+        let lock_file = if lock {
+            let lock_file = File::create(path.join(LOCK_FILE_NAME))?;
+            lock_file.try_lock_exclusive()?;
+            Some(lock_file)
+        } else {
+            None
+        };
+
         let files = find_data_files(&path)?;
 
+        Ok(Log::new(path, lock_file, files, 0, file_pool_size, keyring))
+    }
+
+    fn new(
+        path: PathBuf,
+        lock_file: Option<File>,
+        files: Vec<u32>,
+        max_file_size: usize,
+        file_pool_size: usize,
+        keyring: Keyring,
+    ) -> Log {
         let current_file_id = if files.is_empty() {
             0
         } else {
@@ -70,131 +201,642 @@ impl Log {
 
         info!("Current file id: {}", current_file_id);
 
-        let log_writer = LogWriter::new(&path, sync, max_file_size, file_id_seq.clone());
+        let log_writer = LogWriter::new(
+            &path,
+            true,
+            max_file_size,
+            CHECKPOINT_INTERVAL,
+            file_id_seq.clone(),
+            keyring.clone(),
+        );
 
-        Ok(Log {
+        Log {
             path: path,
             max_file_size: max_file_size,
             lock_file: lock_file,
             files: files,
             file_id_seq: file_id_seq,
-            file_pool: Mutex::new(FilePool::new(file_pool_size)),
+            blob_id_seq: Sequence::new(0),
+            file_pool: FilePool::new(file_pool_size),
+            keyring: keyring,
+            formats: RwLock::new(HashMap::new()),
+            mmap: false,
+            maps: RwLock::new(HashMap::new()),
+            sync: false,
+            checkpoint_interval: CHECKPOINT_INTERVAL,
             log_writer: log_writer,
             active_file_id: None,
-        })
+        }
     }
 
-    pub fn file_size(&self, file_id: u32) -> Result<u64> {
-        let data_file = self.file_pool
-            .lock()
-            .unwrap()
-            .get(file_id)
-            .map(Ok)
-            .unwrap_or_else(|| {
-                get_file_handle(&get_data_file_path(&self.path, file_id), false)
-            })?;
+    /// Sets the number of bytes of entries written to a data file between the checkpoints of its
+    /// hint file. Each checkpoint synchronizes the data file to disk.
+    pub fn set_checkpoint_interval(&mut self, checkpoint_interval: u64) {
+        self.checkpoint_interval = checkpoint_interval;
+        self.log_writer.checkpoint_interval = checkpoint_interval;
+    }
 
-        let res = Ok(data_file.metadata()?.len());
+    pub fn file_size(&self, file_id: u32) -> Result<u64> {
+        Ok(self.data_file(file_id)?.metadata()?.len())
+    }
 
-        self.file_pool.lock().unwrap().put(file_id, data_file);
+    /// Returns the shared read handle of the data file `file_id`. It must only be read with
+    /// positional reads, e.g. through a `FileReader`.
+    fn data_file(&self, file_id: u32) -> Result<Arc<File>> {
+        let data_file = self.file_pool.get(file_id, || {
+            get_file_handle(&get_data_file_path(&self.path, file_id), false)
+        })?;
 
-        res
+        Ok(data_file)
     }
 
     pub fn files(&self) -> Vec<u32> {
         self.files.clone()
     }
 
+    /// Returns the header of the data file `file_id`.
+    pub fn file_header(&self, file_id: u32) -> Result<FileHeader> {
+        read_header(&get_data_file_path(&self.path, file_id))
+    }
+
+    /// Returns whether the data file `file_id` holds nothing but part of its header, see
+    /// `FileHeader::is_partial`.
+    pub fn has_partial_header(&self, file_id: u32) -> Result<bool> {
+        let data_file_path = get_data_file_path(&self.path, file_id);
+        FileHeader::is_partial(&mut get_file_handle(&data_file_path, false)?)
+    }
+
+    /// Reads the header of the data file `file_id` without validating it, see
+    /// `FileHeader::presume`, and reads the file with it from then on. This is for salvaging the
+    /// entries of a data file whose header is corrupt.
+    pub fn presume_header(&self, file_id: u32) -> Result<FileHeader> {
+        let data_file_path = get_data_file_path(&self.path, file_id);
+        let header = FileHeader::presume(&mut get_file_handle(&data_file_path, false)?)?;
+        let format = Arc::new(FileFormat {
+            cipher: self.keyring.file_cipher(&header)?,
+            header: header.clone(),
+        });
+
+        self.formats.write().unwrap().insert(file_id, format);
+
+        Ok(header)
+    }
+
+    fn format(&self, file_id: u32) -> Result<Arc<FileFormat>> {
+        if let Some(format) = self.formats.read().unwrap().get(&file_id) {
+            return Ok(format.clone());
+        }
+
+        let header = self.file_header(file_id)?;
+        let format = Arc::new(FileFormat {
+            cipher: self.keyring.file_cipher(&header)?,
+            header: header,
+        });
+
+        self.formats.write().unwrap().insert(
+            file_id,
+            format.clone(),
+        );
+
+        Ok(format)
+    }
+
+    /// Returns a cipher with the key of the data file `file_id` and a new nonce prefix, for
+    /// encrypting another file with it, or `None` if the data file isn't encrypted.
+    pub fn renew_cipher(&self, file_id: u32) -> Result<Option<FileCipher>> {
+        match self.format(file_id)?.cipher {
+            Some(ref cipher) => Ok(Some(cipher.renew()?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns `true` if the data file `file_id` isn't encrypted with the current encryption key,
+    /// and should be rewritten by compaction.
+    pub fn needs_rotation(&self, file_id: u32) -> Result<bool> {
+        Ok(match self.keyring.current_key_id() {
+            Some(key_id) => {
+                self.format(file_id)?.cipher.as_ref().map(|c| c.key_id()) != Some(key_id)
+            }
+            None => false,
+        })
+    }
+
     pub fn entries<'a>(&self, file_id: u32) -> Result<Entries<'a>> {
+        let data_file_pos = self.format(file_id)?.header.size();
+        self.entries_from(file_id, data_file_pos)
+    }
+
+    /// Returns the entries of the data file `file_id` starting at `data_file_pos`.
+    fn entries_from<'a>(&self, file_id: u32, data_file_pos: u64) -> Result<Entries<'a>> {
         let data_file_path = get_data_file_path(&self.path, file_id);
-        info!("Loading data file: {:?}", data_file_path);
-        let data_file = get_file_handle(&data_file_path, false)?;
+        info!(
+            "Loading data file: {:?} from offset {}",
+            data_file_path,
+            data_file_pos
+        );
+        let mut data_file = get_file_handle(&data_file_path, false)?;
         let data_file_size = data_file.metadata()?.len();
 
+        let format = self.format(file_id)?;
+        data_file.seek(SeekFrom::Start(data_file_pos))?;
+
         Ok(Entries {
-            data_file: data_file.take(data_file_size),
-            data_file_pos: 0,
+            data_file: data_file.take(data_file_size - data_file_pos),
+            data_file_pos: data_file_pos,
+            data_file_size: data_file_size,
+            format: format,
             phantom: PhantomData,
         })
     }
 
+    /// Returns the hints of the data file `file_id`, or `None` if it has no hint file or it was
+    /// found to be corrupt up front.
+    ///
+    /// The hints are validated while they're read, and are only returned once the checkpoint
+    /// following them was validated. Reading the hints fails if the hint file turns out to be
+    /// corrupt or to end before covering the whole data file, after which `into_checkpoint`
+    /// returns the last valid checkpoint, up to which the hints can be trusted. Hint files written
+    /// before checkpoints are validated by the checksum at their end once all of their hints were
+    /// read, none of their hints can be trusted before then.
     pub fn hints<'a>(&self, file_id: u32) -> Result<Option<Hints<'a>>> {
         let hint_file_path = get_hint_file_path(&self.path, file_id);
-        Ok(if is_valid_hint_file(&hint_file_path)? {
-            info!("Loading hint file: {:?}", hint_file_path);
-            let hint_file = get_file_handle(&hint_file_path, false)?;
-            let hint_file_size = hint_file.metadata()?.len();
-
-            Some(Hints {
-                hint_file: hint_file.take(hint_file_size - 4),
-                phantom: PhantomData,
-            })
+        if !hint_file_path.is_file() {
+            return Ok(None);
+        }
+
+        info!("Loading hint file: {:?}", hint_file_path);
+        let mut hint_file = get_file_handle(&hint_file_path, false)?;
+        let hint_file_size = hint_file.metadata()?.len();
+
+        let header = match FileHeader::read(&mut hint_file) {
+            Ok(header) => header,
+            Err(Error::Io(err)) if err.kind() != ErrorKind::UnexpectedEof => {
+                return Err(Error::Io(err));
+            }
+            Err(err) => {
+                warn!("Found corrupt hint file {:?}: {}", hint_file_path, err);
+                return Ok(None);
+            }
+        };
+        let cipher = self.keyring.file_cipher(&header)?;
+
+        let (checkpoint, hint_file_hasher, hints_size) = if header.version >= CHECKPOINTS_VERSION {
+            let mut hint_file_hasher = XxHash32::new();
+            header.write(&mut hint_file_hasher)?;
+
+            let checkpoint = Checkpoint {
+                header: header.clone(),
+                hint_file_pos: header.size(),
+                hint_file_hasher: hint_file_hasher,
+                data_file_pos: self.format(file_id)?.header.size(),
+            };
+
+            (Some(checkpoint), None, u64::MAX)
         } else {
-            None
-        })
+            // hint files written before checkpoints end with a checksum of the whole file
+            if hint_file_size < header.size() + 4 {
+                warn!("Found corrupt hint file: {:?}", hint_file_path);
+                return Ok(None);
+            }
+
+            let mut hint_file_hasher = XxHash32::new();
+            if header.version > 0 {
+                header.write(&mut hint_file_hasher)?;
+            }
+
+            (None, Some(hint_file_hasher), hint_file_size - header.size() - 4)
+        };
+
+        Ok(Some(Hints {
+            hint_file: BufReader::new(hint_file).take(hints_size),
+            version: header.version,
+            cipher: cipher,
+            checkpoint: checkpoint,
+            hint_file_hasher: hint_file_hasher,
+            data_file_size: self.file_size(file_id)?,
+            block: Vec::new().into_iter(),
+            failed: false,
+            phantom: PhantomData,
+        }))
     }
 
-    pub fn recreate_hints<'a>(&mut self, file_id: u32) -> Result<RecreateHints<'a>> {
+    pub fn has_hint_file(&self, file_id: u32) -> bool {
+        get_hint_file_path(&self.path, file_id).is_file()
+    }
+
+    /// Returns the hints of the data file `file_id` while writing its hint file again. The hints
+    /// up to the last valid `checkpoint` of the hint file, if any, are read from it and only the
+    /// entries past it are read from the data file.
+    pub fn recreate_hints<'a>(
+        &mut self,
+        file_id: u32,
+        checkpoint: Option<Checkpoint>,
+    ) -> Result<RecreateHints<'a>> {
         let hint_file_path = get_hint_file_path(&self.path, file_id);
-        warn!("Re-creating hint file: {:?}", hint_file_path);
 
-        let hint_writer = HintWriter::new(&self.path, file_id)?;
-        let entries = self.entries(file_id)?;
+        // the entries we're about to write checkpoints for may not have reached the disk yet
+        OpenOptions::new()
+            .write(true)
+            .open(get_data_file_path(&self.path, file_id))?
+            .sync_data()?;
+
+        let (hint_writer, entries) = match checkpoint {
+            Some(checkpoint) => {
+                warn!(
+                    "Resuming hint file {:?} from offset {}",
+                    hint_file_path,
+                    checkpoint.hint_file_pos
+                );
+
+                let entries = self.entries_from(file_id, checkpoint.data_file_pos)?;
+                let cipher = self.keyring.file_cipher(&checkpoint.header)?;
+
+                (
+                    HintWriter::resume(&self.path, file_id, checkpoint, cipher)?,
+                    entries,
+                )
+            }
+            None => {
+                warn!("Re-creating hint file: {:?}", hint_file_path);
+
+                // the hint file is encrypted with the same key as its data file
+                let cipher = self.renew_cipher(file_id)?;
+
+                (
+                    HintWriter::new(&self.path, file_id, cipher)?,
+                    self.entries(file_id)?,
+                )
+            }
+        };
 
         Ok(RecreateHints {
             hint_writer: hint_writer,
             entries: entries,
+            checkpoint_interval: self.checkpoint_interval,
+            corrupt_pos: None,
+            batch: Vec::new().into_iter(),
+        })
+    }
+
+    /// Moves the data file `file_id` and its hint file to the `corrupt` directory, removing it
+    /// from the log.
+    pub fn quarantine(&mut self, file_id: u32) -> Result<()> {
+        let corrupt_path = self.path.join(CORRUPT_DIR_NAME);
+        if !corrupt_path.exists() {
+            fs::create_dir(&corrupt_path)?;
+        }
+
+        warn!(
+            "Moving corrupt data file {:?} to {:?}",
+            get_data_file_path(&self.path, file_id),
+            corrupt_path
+        );
+
+        move_files(&self.path, &corrupt_path, &[file_id])?;
+        sync_dir(&corrupt_path)?;
+        sync_dir(&self.path)?;
+        self.file_pool.remove(file_id);
+        self.formats.write().unwrap().remove(&file_id);
+        self.maps.write().unwrap().remove(&file_id);
+
+        if let Ok(idx) = self.files.binary_search(&file_id) {
+            self.files.remove(idx);
+        }
+
+        Ok(())
+    }
+
+    /// Returns `true` if the data file `file_id` was closed, i.e. its hint file ends with a
+    /// checkpoint covering the whole data file. The hint file isn't validated, other than a hint
+    /// file written before checkpoints.
+    pub fn is_sealed(&self, file_id: u32) -> Result<bool> {
+        let hint_file_path = get_hint_file_path(&self.path, file_id);
+        if !hint_file_path.is_file() {
+            return Ok(false);
+        }
+
+        let mut hint_file = get_file_handle(&hint_file_path, false)?;
+        let hint_file_size = hint_file.metadata()?.len();
+
+        let header = match FileHeader::read(&mut hint_file) {
+            Ok(header) => header,
+            Err(Error::Io(err)) if err.kind() != ErrorKind::UnexpectedEof => {
+                return Err(Error::Io(err));
+            }
+            Err(_) => return Ok(false),
+        };
+
+        // hint files written before checkpoints were written along with their data file, the
+        // checksum at their end was only added once it was closed
+        if header.version < CHECKPOINTS_VERSION {
+            return is_valid_hint_file(&mut hint_file, hint_file_size);
+        }
+
+        if hint_file_size < header.size() + CHECKPOINT_SIZE {
+            return Ok(false);
+        }
+
+        hint_file.seek(SeekFrom::End(-(CHECKPOINT_SIZE as i64)))?;
+        let data_file_pos = hint_file.read_u64::<LittleEndian>()?;
+
+        Ok(data_file_pos == self.file_size(file_id)?)
+    }
+
+    /// Truncates a torn write at the tail of the data file `file_id`, i.e. trailing data that
+    /// can't be read as a valid entry and past which no valid entry is found by scanning forward,
+    /// along with any incomplete batch preceding it. Only the entries past the last valid
+    /// `checkpoint` of its hint file, if any, are read. Returns the number of bytes discarded.
+    pub fn recover_tail(&self, file_id: u32, checkpoint: Option<&Checkpoint>) -> Result<u64> {
+        let mut tail_pos = None;
+        // position of the marker and number of entries left of the last batch
+        let mut batch = None;
+
+        let mut entries = match checkpoint {
+            // checkpoints are only written between batches
+            Some(checkpoint) => self.entries_from(file_id, checkpoint.data_file_pos)?,
+            None => self.entries(file_id)?,
+        };
+
+        for (entry_pos, entry) in entries.by_ref() {
+            match entry {
+                Ok(entry) => {
+                    batch = match batch {
+                        Some((marker_pos, remaining)) if remaining > 1 => {
+                            Some((marker_pos, remaining - 1))
+                        }
+                        Some(_) => None,
+                        None => {
+                            entry.batch_size().filter(|&count| count > 0).map(
+                                |count| (entry_pos, count),
+                            )
+                        }
+                    };
+                }
+                Err(Error::InvalidChecksum { .. }) => {
+                    tail_pos = Some(entry_pos);
+                    break;
+                }
+                Err(Error::Io(ref err)) if err.kind() == ErrorKind::UnexpectedEof => {
+                    tail_pos = Some(entry_pos);
+                    break;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        // a corrupt size can make a valid entry appear to run past the end of the file, so it's
+        // only a torn write if there's no valid entry after it
+        if let Some(tail_pos) = tail_pos {
+            if entries.resync(tail_pos)?.is_some() {
+                return Ok(0);
+            }
+        }
+
+        Ok(match batch.map(|b| b.0).or(tail_pos) {
+            Some(tail_pos) => {
+                let data_file_path = get_data_file_path(&self.path, file_id);
+                let data_file = OpenOptions::new().write(true).open(&data_file_path)?;
+                let data_file_size = data_file.metadata()?.len();
+
+                data_file.set_len(tail_pos)?;
+                data_file.sync_all()?;
+
+                warn!(
+                    "Truncated torn write at the tail of data file {:?}, discarded {} bytes",
+                    data_file_path,
+                    data_file_size - tail_pos
+                );
+
+                data_file_size - tail_pos
+            }
+            None => 0,
         })
     }
 
     pub fn read_entry<'a>(&self, file_id: u32, entry_pos: u64) -> Result<Entry<'a>> {
-        let mut data_file = self.file_pool
-            .lock()
-            .unwrap()
-            .get(file_id)
-            .map(Ok)
-            .unwrap_or_else(|| {
-                get_file_handle(&get_data_file_path(&self.path, file_id), false)
-            })?;
+        let format = self.format(file_id)?;
+
+        let res = match self.map(file_id)? {
+            Some(map) => {
+                let mut cursor = Cursor::new(&map[..]);
+                cursor.set_position(entry_pos);
+                Entry::from_read(&mut cursor, format.header.version)
+            }
+            None => {
+                let data_file = self.data_file(file_id)?;
+                let mut reader = FileReader::new(&data_file, entry_pos);
+                Entry::from_read(&mut reader, format.header.version)
+            }
+        };
 
-        data_file.seek(SeekFrom::Start(entry_pos))?;
-        let res = Entry::from_read(&mut data_file);
+        match format.cipher {
+            Some(ref cipher) => cipher.decrypt_entry(res?, entry_pos),
+            None => res,
+        }
+    }
+
+    /// Returns the memory map of the data file `file_id`, if memory mapping is enabled and the
+    /// file is sealed, i.e. it's no longer written to.
+    fn map(&self, file_id: u32) -> Result<Option<Arc<Mmap>>> {
+        if !self.mmap || self.files.binary_search(&file_id).is_err() {
+            return Ok(None);
+        }
+
+        if let Some(map) = self.maps.read().unwrap().get(&file_id) {
+            return Ok(Some(map.clone()));
+        }
+
+        let data_file = get_file_handle(&get_data_file_path(&self.path, file_id), false)?;
+        // sealed data files are never modified, they're only removed after being compacted while
+        // readers holding the map keep it alive
+        let map = Arc::new(unsafe { Mmap::map(&data_file)? });
 
-        self.file_pool.lock().unwrap().put(file_id, data_file);
+        self.maps.write().unwrap().insert(file_id, map.clone());
 
-        res
+        Ok(Some(map))
+    }
+
+    /// Reads up to `len` bytes at `offset` of the value of the entry at `entry_pos`, without
+    /// reading the rest of the entry nor validating its checksum. Returns `None` if the value
+    /// can't be read partially, i.e. it's compressed, encrypted or stored in a blob file.
+    pub fn read_value_range(
+        &self,
+        file_id: u32,
+        entry_pos: u64,
+        offset: u64,
+        len: u64,
+    ) -> Result<Option<Vec<u8>>> {
+        let format = self.format(file_id)?;
+
+        if format.cipher.is_some() {
+            return Ok(None);
+        }
+
+        let data_file = self.data_file(file_id)?;
+
+        read_value_range(
+            &data_file,
+            entry_pos,
+            offset,
+            len,
+            format.header.version,
+        )
+    }
+
+    /// Creates a writer for the blob file of `sequence`, encrypted with the current key. The blob
+    /// is written to a temporary file which is moved in place with `commit_blob` once it's
+    /// finished.
+    pub fn blob_writer(&self, sequence: SequenceNumber) -> Result<BlobWriter> {
+        let blob_file_path = get_temp_blob_file_path(&self.path, self.blob_id_seq.increment());
+        BlobWriter::new(blob_file_path, sequence, self.keyring.new_cipher()?)
+    }
+
+    /// Moves the blob written to `blob_file_path` in place for `sequence`.
+    pub fn commit_blob(&self, blob_file_path: &Path, sequence: SequenceNumber) -> Result<()> {
+        fs::rename(blob_file_path, get_blob_file_path(&self.path, sequence))?;
+        sync_dir(&self.path)?;
+        Ok(())
+    }
+
+    /// Opens the blob file of `sequence`, which holds a value of `len` bytes.
+    pub fn blob_reader(&self, sequence: SequenceNumber, len: u64) -> Result<BlobReader> {
+        BlobReader::open(
+            &get_blob_file_path(&self.path, sequence),
+            sequence,
+            &self.keyring,
+            len,
+        )
+    }
+
+    /// Rewrites the blob file of `sequence` with the current encryption key, if it isn't
+    /// encrypted with it.
+    pub fn rotate_blob(&self, sequence: SequenceNumber, len: u64) -> Result<()> {
+        let key_id = self.keyring.current_key_id();
+        let mut blob_reader = self.blob_reader(sequence, len)?;
+
+        if key_id.is_none() || blob_reader.key_id() == key_id {
+            return Ok(());
+        }
+
+        let mut blob_writer = self.blob_writer(sequence)?;
+        blob_writer.copy_from(&mut blob_reader, len)?;
+
+        let blob_file_path = blob_writer.path().to_path_buf();
+        blob_writer.finish()?;
+
+        self.commit_blob(&blob_file_path, sequence)
+    }
+
+    pub fn remove_blob(&self, sequence: SequenceNumber) -> Result<()> {
+        match fs::remove_file(get_blob_file_path(&self.path, sequence)) {
+            Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            res => Ok(res?),
+        }
     }
 
     pub fn append_entry<'a>(&mut self, entry: &Entry<'a>) -> Result<(u32, u64)> {
         Ok(match self.log_writer.write(entry)? {
-            LogWrite::NewFile(file_id) => {
-                if let Some(active_file_id) = self.active_file_id {
-                    self.add_file(active_file_id);
-                }
-                self.active_file_id = Some(file_id);
-                info!(
-                    "New active data file {:?}",
-                    self.log_writer.entry_writer()?.data_file_path
-                );
-                (file_id, 0)
+            LogWrite::NewFile(file_id, entry_pos) => {
+                self.set_active_file(file_id)?;
+                (file_id, entry_pos)
             }
             LogWrite::Ok(entry_pos) => (self.active_file_id.unwrap(), entry_pos),
         })
     }
 
-    pub fn writer(&self) -> LogWriter {
-        LogWriter::new(
-            &self.path,
-            false, // FIXME: should this be configurable?
+    /// Appends `entries` as an atomic batch. Returns the file id and position of each entry.
+    pub fn append_batch<'a>(
+        &mut self,
+        sequence: SequenceNumber,
+        entries: &[Entry<'a>],
+    ) -> Result<Vec<(u32, u64)>> {
+        let (new_file_id, entry_positions) = self.log_writer.write_batch(sequence, entries)?;
+
+        if let Some(file_id) = new_file_id {
+            self.set_active_file(file_id)?;
+        }
+
+        let file_id = self.active_file_id.unwrap();
+        Ok(
+            entry_positions
+                .into_iter()
+                .map(|entry_pos| (file_id, entry_pos))
+                .collect(),
+        )
+    }
+
+    fn set_active_file(&mut self, file_id: u32) -> Result<()> {
+        if let Some(active_file_id) = self.active_file_id {
+            self.add_file(active_file_id);
+        }
+        self.active_file_id = Some(file_id);
+        info!(
+            "New active data file {:?}",
+            self.log_writer.entry_writer()?.data_file_path
+        );
+        Ok(())
+    }
+
+    /// Creates a writer for the output of a compaction. Its files are written to a separate
+    /// directory and only become part of the log once they're finished and moved in place with
+    /// `publish_files`. The files left behind by a compaction that failed are removed.
+    pub fn writer(&self) -> Result<LogWriter> {
+        self.remove_unpublished_files()?;
+
+        let compact_path = self.path.join(COMPACT_DIR_NAME);
+        fs::create_dir(&compact_path)?;
+
+        Ok(LogWriter::new(
+            &compact_path,
+            false,
             self.max_file_size,
+            self.checkpoint_interval,
             self.file_id_seq.clone(),
-        )
+            self.keyring.clone(),
+        ))
+    }
+
+    /// Moves the data files `file_ids` written by a finished compaction writer in place, so that
+    /// they're found when the log is opened again. They're not read from until `swap_files`.
+    pub fn publish_files(&self, file_ids: &[u32]) -> Result<()> {
+        let compact_path = self.path.join(COMPACT_DIR_NAME);
+
+        move_files(&compact_path, &self.path, file_ids)?;
+        sync_dir(&self.path)?;
+        fs::remove_dir(&compact_path)?;
+
+        Ok(())
+    }
+
+    /// Removes the files of a compaction that was interrupted before they were published.
+    pub fn remove_unpublished_files(&self) -> Result<()> {
+        let compact_path = self.path.join(COMPACT_DIR_NAME);
+        if compact_path.exists() {
+            warn!("Removing unfinished compaction files in {:?}", compact_path);
+            fs::remove_dir_all(&compact_path)?;
+        }
+
+        Ok(())
     }
 
     pub fn sync(&self) -> Result<()> {
         self.log_writer.sync()
     }
 
+    pub fn flush(&mut self) -> Result<()> {
+        self.log_writer.flush()
+    }
+
+    /// Returns the last write appended, which must be waited on for it to be durable, if writes
+    /// are synchronized or `sync` is set.
+    pub fn last_commit(&self, sync: bool) -> Option<Commit> {
+        if self.sync || sync {
+            self.log_writer.last_commit()
+        } else {
+            None
+        }
+    }
+
     pub fn swap_files(&mut self, old_files: &[u32], new_files: &[u32]) -> Result<()> {
         for &file_id in old_files {
             let idx = self.files.binary_search(&file_id).map_err(|_| {
@@ -206,13 +848,39 @@ impl Log {
             let data_file_path = get_data_file_path(&self.path, file_id);
             let hint_file_path = get_hint_file_path(&self.path, file_id);
 
-            fs::remove_file(data_file_path)?;
+            // a hint file must never outlive its data file
             let _ = fs::remove_file(hint_file_path);
+            fs::remove_file(data_file_path)?;
+
+            self.file_pool.remove(file_id);
+            self.formats.write().unwrap().remove(&file_id);
+            self.maps.write().unwrap().remove(&file_id);
         }
 
         self.files.extend(new_files);
         self.files.sort();
 
+        // the compacted files would be resurrected by a crash otherwise
+        sync_dir(&self.path)?;
+
+        Ok(())
+    }
+
+    /// Replaces the data file `file_id` and its hint file with the ones in directory `from`.
+    pub fn replace_file(&mut self, from: &Path, file_id: u32) -> Result<()> {
+        // the old hint file must never be paired with the new data file
+        let hint_file_path = get_hint_file_path(&self.path, file_id);
+        if hint_file_path.exists() {
+            fs::remove_file(hint_file_path)?;
+        }
+
+        move_files(from, &self.path, &[file_id])?;
+        sync_dir(&self.path)?;
+
+        self.file_pool.remove(file_id);
+        self.formats.write().unwrap().remove(&file_id);
+        self.maps.write().unwrap().remove(&file_id);
+
         Ok(())
     }
 
@@ -224,36 +892,48 @@ impl Log {
 
 impl Drop for Log {
     fn drop(&mut self) {
-        let _ = self.lock_file.unlock();
+        if let Some(ref lock_file) = self.lock_file {
+            let _ = lock_file.unlock();
+        }
     }
 }
 
 pub struct LogWriter {
     path: PathBuf,
-    sync: bool,
+    group_commit: Option<Arc<GroupCommit>>,
     max_file_size: usize,
+    checkpoint_interval: u64,
     file_id_seq: Arc<Sequence>,
+    keyring: Keyring,
     entry_writer: Option<EntryWriter>,
 }
 
 pub enum LogWrite {
     Ok(u64),
-    NewFile(u32),
+    NewFile(u32, u64),
 }
 
 impl LogWriter {
     pub fn new(
         path: &Path,
-        sync: bool,
+        group_commit: bool,
         max_file_size: usize,
+        checkpoint_interval: u64,
         file_id_seq: Arc<Sequence>,
+        keyring: Keyring,
     ) -> LogWriter {
 
         LogWriter {
             path: path.to_path_buf(),
-            sync: sync,
+            group_commit: if group_commit {
+                Some(Arc::new(GroupCommit::new()))
+            } else {
+                None
+            },
             max_file_size: max_file_size,
+            checkpoint_interval: checkpoint_interval,
             file_id_seq: file_id_seq,
+            keyring: keyring,
             entry_writer: None,
         }
     }
@@ -268,43 +948,113 @@ impl LogWriter {
     fn new_entry_writer(&mut self) -> Result<u32> {
         let file_id = self.file_id_seq.increment();
 
-        if self.entry_writer.is_some() {
-            info!(
-                "Closed data file {:?}",
-                self.entry_writer.as_ref().unwrap().data_file_path
-            );
+        if let Some(ref mut entry_writer) = self.entry_writer {
+            // also needed by the group commit, which only syncs the current data file
+            entry_writer.finish()?;
+
+            info!("Closed data file {:?}", entry_writer.data_file_path);
         }
 
-        self.entry_writer = Some(EntryWriter::new(&self.path, self.sync, file_id)?);
+        self.entry_writer = Some(EntryWriter::new(
+            &self.path,
+            file_id,
+            self.keyring.new_cipher()?,
+            self.checkpoint_interval,
+        )?);
         Ok(file_id)
     }
 
-    pub fn write(&mut self, entry: &Entry) -> Result<LogWrite> {
+    /// Returns the size of `entry` once written, i.e. including the tag if it's encrypted.
+    fn stored_size(&self, entry: &Entry) -> u64 {
+        match self.keyring.current_key_id() {
+            Some(_) => entry.size() + TAG_SIZE as u64,
+            None => entry.size(),
+        }
+    }
+
+    /// Makes sure the current data file can fit `size` more bytes, creating a new data file
+    /// otherwise. Returns the id of the new data file, if any.
+    fn reserve(&mut self, size: u64) -> Result<Option<u32>> {
         Ok(if self.entry_writer.is_none() || // FIXME: clean up
-              self.entry_writer.as_ref().unwrap().data_file_pos + entry.size() >
+              self.entry_writer.as_ref().unwrap().data_file_pos + size >
               self.max_file_size as u64
         {
 
-            if self.entry_writer.is_some() {
+            if let Some(ref entry_writer) = self.entry_writer {
                 info!(
                     "Data file {:?} reached file limit of {}",
-                    self.entry_writer.as_ref().unwrap().data_file_path,
+                    entry_writer.data_file_path,
                     human_readable_byte_count(self.max_file_size, true)
                 );
             }
 
-            let file_id = self.new_entry_writer()?;
-            let entry_pos = self.entry_writer.as_mut().unwrap().write(entry)?;
-
-            assert_eq!(entry_pos, 0);
-
-            LogWrite::NewFile(file_id)
+            Some(self.new_entry_writer()?)
         } else {
-            let entry_pos = self.entry_writer.as_mut().unwrap().write(entry)?;
-            LogWrite::Ok(entry_pos)
+            None
         })
     }
 
+    pub fn write(&mut self, entry: &Entry) -> Result<LogWrite> {
+        let size = self.stored_size(entry);
+        let new_file_id = self.reserve(size)?;
+        let entry_writer = self.entry_writer.as_mut().unwrap();
+        let entry_pos = entry_writer.write(entry)?;
+        entry_writer.checkpoint()?;
+        self.commit();
+
+        Ok(match new_file_id {
+            Some(file_id) => LogWrite::NewFile(file_id, entry_pos),
+            None => LogWrite::Ok(entry_pos),
+        })
+    }
+
+    /// Writes `entries` preceded by a batch marker, all to the same data file, so that on
+    /// recovery the batch is either fully applied or ignored. Returns the id of the new data file,
+    /// if one had to be created, and the position of each entry.
+    pub fn write_batch(
+        &mut self,
+        sequence: SequenceNumber,
+        entries: &[Entry],
+    ) -> Result<(Option<u32>, Vec<u64>)> {
+        let marker = Entry::batch(sequence, entries.len() as u32);
+        let size = entries.iter().fold(self.stored_size(&marker), |size, e| {
+            size + self.stored_size(e)
+        });
+
+        // an entry that can't be encrypted must fail the batch before the marker is written
+        if self.keyring.current_key_id().is_some() {
+            for entry in entries {
+                check_key_size(entry.key.len())?;
+            }
+        }
+
+        let new_file_id = self.reserve(size)?;
+        let entry_writer = self.entry_writer.as_mut().unwrap();
+
+        entry_writer.write(&marker)?;
+
+        let mut entry_positions = Vec::with_capacity(entries.len());
+        for entry in entries {
+            entry_positions.push(entry_writer.write(entry)?);
+        }
+
+        entry_writer.checkpoint()?;
+        self.commit();
+
+        Ok((new_file_id, entry_positions))
+    }
+
+    /// Records the last write with the group commit, if there's one.
+    fn commit(&self) {
+        if let Some(ref group_commit) = self.group_commit {
+            group_commit.append(&self.entry_writer.as_ref().unwrap().data_file);
+        }
+    }
+
+    pub fn last_commit(&self) -> Option<Commit> {
+        self.group_commit.as_ref().map(GroupCommit::last)
+    }
+
     pub fn sync(&self) -> Result<()> {
         if let Some(ref writer) = self.entry_writer {
             writer.data_file.sync_data()?
@@ -312,47 +1062,126 @@ impl LogWriter {
 
         Ok(())
     }
+
+    /// Finishes the current data file, see `EntryWriter::finish`.
+    pub fn finish(&mut self) -> Result<()> {
+        if let Some(ref mut entry_writer) = self.entry_writer {
+            entry_writer.finish()?;
+        }
+
+        Ok(())
+    }
+
+    /// Synchronizes the current data file to disk along with a checkpoint of its hint file.
+    pub fn flush(&mut self) -> Result<()> {
+        if let Some(ref mut entry_writer) = self.entry_writer {
+            entry_writer.flush()?;
+        }
+
+        Ok(())
+    }
 }
 
 pub struct EntryWriter {
-    sync: bool,
     data_file_path: PathBuf,
-    data_file: File,
+    data_file: Arc<File>,
     data_file_pos: u64,
+    cipher: Option<FileCipher>,
     hint_writer: HintWriter,
+    checkpoint_interval: u64,
+    finished: bool,
 }
 
 impl EntryWriter {
-    pub fn new(path: &Path, sync: bool, file_id: u32) -> Result<EntryWriter> {
+    pub fn new(
+        path: &Path,
+        file_id: u32,
+        cipher: Option<FileCipher>,
+        checkpoint_interval: u64,
+    ) -> Result<EntryWriter> {
         let data_file_path = get_data_file_path(path, file_id);
-        let data_file = get_file_handle(&data_file_path, true)?;
+        let mut data_file = get_file_handle(&data_file_path, true)?;
+
+        let (header, hint_cipher) = match cipher {
+            Some(ref cipher) => (cipher.header(), Some(cipher.renew()?)),
+            None => (FileHeader::new(None), None),
+        };
+        header.write(&mut data_file)?;
 
         info!("Created new data file {:?}", data_file_path);
 
-        let hint_writer = HintWriter::new(path, file_id)?;
+        let hint_writer = HintWriter::new(path, file_id, hint_cipher)?;
+
+        // the new files must still exist after a crash once their entries are durable
+        sync_dir(path)?;
 
         Ok(EntryWriter {
-            sync: sync,
             data_file_path: data_file_path,
-            data_file: data_file,
-            data_file_pos: 0,
+            data_file: Arc::new(data_file),
+            data_file_pos: header.size(),
+            cipher: cipher,
             hint_writer: hint_writer,
+            checkpoint_interval: checkpoint_interval,
+            finished: false,
         })
     }
 
+    /// Synchronizes the data file to disk and then completes its hint file, so that a valid hint
+    /// file never points to entries that were lost in a crash. No more entries can be written.
+    pub fn finish(&mut self) -> Result<()> {
+        if !self.finished {
+            self.data_file.sync_data()?;
+            self.hint_writer.finish(self.data_file_pos)?;
+            self.finished = true;
+        }
+
+        Ok(())
+    }
+
+    /// Writes a checkpoint to the hint file once enough entries were written since the last one,
+    /// synchronizing the data file to disk first. Must only be called between batches.
+    pub fn checkpoint(&mut self) -> Result<()> {
+        if self.hint_writer.needs_checkpoint(self.data_file_pos, self.checkpoint_interval) {
+            self.data_file.sync_data()?;
+            self.hint_writer.checkpoint(self.data_file_pos)?;
+        }
+
+        Ok(())
+    }
+
+    /// Synchronizes the data file to disk and writes a checkpoint covering all of its entries.
+    pub fn flush(&mut self) -> Result<()> {
+        self.data_file.sync_data()?;
+
+        if !self.finished {
+            self.hint_writer.checkpoint(self.data_file_pos)?;
+            self.hint_writer.hint_file.sync_data()?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes `entry` without synchronizing it to disk. Batch markers don't have hints.
     pub fn write<'a>(&mut self, entry: &Entry<'a>) -> Result<u64> {
         let entry_pos = self.data_file_pos;
 
-        let hint = Hint::new(entry, entry_pos);
-        entry.write_bytes(&mut self.data_file)?;
-
-        self.hint_writer.write(&hint)?;
+        let size = match self.cipher {
+            Some(ref cipher) => {
+                let encrypted = cipher.encrypt_entry(entry, entry_pos)?;
+                encrypted.write_bytes(&mut &*self.data_file)?;
+                encrypted.size()
+            }
+            None => {
+                entry.write_bytes(&mut &*self.data_file)?;
+                entry.size()
+            }
+        };
 
-        if self.sync {
-            self.data_file.sync_data()?;
+        if !entry.batch {
+            self.hint_writer.write(&Hint::new(entry, entry_pos))?;
         }
 
-        self.data_file_pos += entry.size();
+        self.data_file_pos += size;
 
         Ok(entry_pos)
     }
@@ -360,46 +1189,145 @@ impl EntryWriter {
 
 impl Drop for EntryWriter {
     fn drop(&mut self) {
-        let _ = self.data_file.sync_data();
+        let _ = self.finish();
     }
 }
 
 struct HintWriter {
     hint_file: File,
     hint_file_hasher: XxHash32,
+    cipher: Option<FileCipher>,
+    // hints written since the last checkpoint
+    block: Vec<u8>,
+    // position in the data file covered by the last checkpoint
+    checkpoint_pos: u64,
+    abandoned: bool,
+    finished: bool,
 }
 
 impl HintWriter {
-    pub fn new(path: &Path, file_id: u32) -> Result<HintWriter> {
-        let hint_file = get_file_handle(&get_hint_file_path(path, file_id), true)?;
+    pub fn new(path: &Path, file_id: u32, cipher: Option<FileCipher>) -> Result<HintWriter> {
+        let mut hint_file = get_file_handle(&get_hint_file_path(path, file_id), true)?;
+        let mut hint_file_hasher = XxHash32::new();
+
+        let header = match cipher {
+            Some(ref cipher) => cipher.header(),
+            None => FileHeader::new(None),
+        };
+        header.write(&mut hint_file)?;
+        header.write(&mut hint_file_hasher)?;
 
         Ok(HintWriter {
             hint_file: hint_file,
-            hint_file_hasher: XxHash32::new(),
+            hint_file_hasher: hint_file_hasher,
+            cipher: cipher,
+            block: Vec::new(),
+            checkpoint_pos: 0,
+            abandoned: false,
+            finished: false,
         })
     }
 
+    /// Opens the hint file of `file_id` to write more hints after its last valid `checkpoint`,
+    /// discarding everything past it.
+    pub fn resume(
+        path: &Path,
+        file_id: u32,
+        checkpoint: Checkpoint,
+        cipher: Option<FileCipher>,
+    ) -> Result<HintWriter> {
+        let mut hint_file = OpenOptions::new().write(true).open(
+            get_hint_file_path(path, file_id),
+        )?;
+        hint_file.set_len(checkpoint.hint_file_pos)?;
+        hint_file.seek(SeekFrom::End(0))?;
+
+        Ok(HintWriter {
+            hint_file: hint_file,
+            hint_file_hasher: checkpoint.hint_file_hasher,
+            cipher: cipher,
+            block: Vec::new(),
+            checkpoint_pos: checkpoint.data_file_pos,
+            abandoned: false,
+            finished: false,
+        })
+    }
+
+    /// Buffers `hint` until the next checkpoint.
     pub fn write<'a>(&mut self, hint: &Hint<'a>) -> Result<()> {
-        hint.write_bytes(&mut self.hint_file)?;
-        hint.write_bytes(&mut self.hint_file_hasher)?;
+        match self.cipher {
+            Some(ref cipher) => cipher.encrypt_hint(hint)?.write_bytes(&mut self.block)?,
+            None => hint.write_bytes(&mut self.block)?,
+        }
         Ok(())
     }
-}
 
-impl Drop for HintWriter {
-    fn drop(&mut self) {
-        let _ = self.hint_file.write_u32::<LittleEndian>(
-            self.hint_file_hasher.get(),
-        );
+    /// Returns `true` if the entries written up to `data_file_pos` warrant a new checkpoint.
+    pub fn needs_checkpoint(&self, data_file_pos: u64, checkpoint_interval: u64) -> bool {
+        data_file_pos - self.checkpoint_pos >= checkpoint_interval
+    }
+
+    /// Writes the hints buffered so far followed by a checkpoint covering the entries up to
+    /// `data_file_pos`, which must already be synchronized to disk.
+    pub fn checkpoint(&mut self, data_file_pos: u64) -> Result<()> {
+        let mut block = Vec::with_capacity(4 + self.block.len() + CHECKPOINT_SIZE as usize);
+        block.write_u32::<LittleEndian>(self.block.len() as u32)?;
+        block.extend_from_slice(&self.block);
+        block.write_u64::<LittleEndian>(data_file_pos)?;
+
+        self.hint_file_hasher.update(&block);
+        block.write_u32::<LittleEndian>(self.hint_file_hasher.get())?;
+        self.hint_file_hasher.update(&block[block.len() - 4..]);
+
+        self.hint_file.write_all(&block)?;
+        self.block.clear();
+        self.checkpoint_pos = data_file_pos;
+
+        Ok(())
+    }
+
+    /// Writes the checkpoint covering all the entries of the data file, which are the ones up to
+    /// `data_file_pos`, and synchronizes the hint file to disk.
+    pub fn finish(&mut self, data_file_pos: u64) -> Result<()> {
+        // an abandoned hint file never covers the whole data file, so it's never considered valid
+        if !self.abandoned && !self.finished {
+            self.checkpoint(data_file_pos)?;
+            self.hint_file.sync_data()?;
+            self.finished = true;
+        }
+
+        Ok(())
     }
 }
 
 pub struct Entries<'a> {
     data_file: Take<File>,
     data_file_pos: u64,
+    data_file_size: u64,
+    format: Arc<FileFormat>,
     phantom: PhantomData<&'a ()>,
 }
 
+impl<'a> Entries<'a> {
+    /// Skips the corrupt entry at `entry_pos` by scanning forward for the next offset holding a
+    /// valid entry, from which iteration resumes. Returns `None` if there's no valid entry left.
+    pub fn resync(&mut self, entry_pos: u64) -> Result<Option<u64>> {
+        let next_pos = find_next_entry(
+            self.data_file.get_mut(),
+            entry_pos + 1,
+            self.data_file_size,
+            self.format.header.version,
+        )?;
+        let resume_pos = next_pos.unwrap_or(self.data_file_size);
+
+        self.data_file.get_mut().seek(SeekFrom::Start(resume_pos))?;
+        self.data_file.set_limit(self.data_file_size - resume_pos);
+        self.data_file_pos = resume_pos;
+
+        Ok(next_pos)
+    }
+}
+
 impl<'a> Iterator for Entries<'a> {
     type Item = (u64, Result<Entry<'a>>);
 
@@ -409,7 +1337,8 @@ impl<'a> Iterator for Entries<'a> {
         if limit == 0 {
             None
         } else {
-            let entry = Entry::from_read(&mut self.data_file);
+            let version = self.format.header.version;
+            let entry = Entry::from_read_bounded(&mut self.data_file, limit, version);
             let entry_pos = self.data_file_pos;
 
             let read = limit - self.data_file.limit();
@@ -418,8 +1347,11 @@ impl<'a> Iterator for Entries<'a> {
 
             let entry = match entry {
                 Ok(entry) => {
-                    assert_eq!(entry.size(), read);
-                    Ok(entry)
+                    assert_eq!(entry.size_in(version), read);
+                    match self.format.cipher {
+                        Some(ref cipher) => cipher.decrypt_entry(entry, entry_pos),
+                        None => Ok(entry),
+                    }
                 }
                 e => e,
             };
@@ -430,18 +1362,146 @@ impl<'a> Iterator for Entries<'a> {
 }
 
 pub struct Hints<'a> {
-    hint_file: Take<File>,
+    hint_file: Take<BufReader<File>>,
+    version: u16,
+    cipher: Option<FileCipher>,
+    // last valid checkpoint, for hint files with checkpoints
+    checkpoint: Option<Checkpoint>,
+    // checksum of the hints read so far, for hint files without checkpoints until they're fully
+    // read or fail to be
+    hint_file_hasher: Option<XxHash32>,
+    data_file_size: u64,
+    // validated hints of the last block read
+    block: vec::IntoIter<Hint<'a>>,
+    failed: bool,
     phantom: PhantomData<&'a ()>,
 }
 
+impl<'a> Hints<'a> {
+    /// Returns `true` if the hint file is written in blocks ending with a checkpoint, whose hints
+    /// are validated before they're returned.
+    pub fn has_checkpoints(&self) -> bool {
+        self.checkpoint.is_some()
+    }
+
+    /// Returns the last valid checkpoint read, which covers every hint returned so far once
+    /// reading the hints failed, or `None` for hint files written before checkpoints.
+    pub fn into_checkpoint(self) -> Option<Checkpoint> {
+        self.checkpoint
+    }
+
+    /// Reads the next hint of a hint file without checkpoints. Returns `None` once all the hints
+    /// were read and the checksum at the end of the hint file was validated.
+    fn next_legacy_hint(&mut self, hint_file_hasher: &mut XxHash32) -> Result<Option<Hint<'a>>> {
+        if self.hint_file.limit() == 0 {
+            let checksum = self.hint_file.get_mut().read_u32::<LittleEndian>()?;
+            let hash = hint_file_hasher.get();
+
+            if hash != checksum {
+                return Err(Error::InvalidChecksum {
+                    expected: checksum,
+                    found: hash,
+                });
+            }
+
+            return Ok(None);
+        }
+
+        let hint = Hint::from_read(
+            &mut HashingReader {
+                reader: &mut self.hint_file,
+                hasher: hint_file_hasher,
+            },
+            self.version,
+        )?;
+
+        Ok(Some(match self.cipher {
+            Some(ref cipher) => cipher.decrypt_hint(hint)?,
+            None => hint,
+        }))
+    }
+
+    /// Reads and validates the next block of hints. Returns `false` once the hints cover the whole
+    /// data file.
+    fn next_block(&mut self) -> Result<bool> {
+        let checkpoint = self.checkpoint.as_mut().unwrap();
+        if checkpoint.data_file_pos == self.data_file_size {
+            return Ok(false);
+        }
+
+        let mut hint_file_hasher = checkpoint.hint_file_hasher.clone();
+        let (block, data_file_pos) = match read_block(&mut self.hint_file, &mut hint_file_hasher) {
+            Err(Error::Io(ref err)) if err.kind() == ErrorKind::UnexpectedEof => {
+                return Err(Error::Io(io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    format!(
+                        "hint file only covers the data file up to offset {}",
+                        checkpoint.data_file_pos
+                    ),
+                )));
+            }
+            block => block?,
+        };
+
+        if data_file_pos < checkpoint.data_file_pos || data_file_pos > self.data_file_size {
+            return Err(Error::Io(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("hint file checkpoint at invalid offset {}", data_file_pos),
+            )));
+        }
+
+        let mut hints = Vec::new();
+        let mut cursor = Cursor::new(&block[..]);
+        while cursor.position() < block.len() as u64 {
+            let hint = Hint::from_read(&mut cursor, self.version)?;
+            hints.push(match self.cipher {
+                Some(ref cipher) => cipher.decrypt_hint(hint)?,
+                None => hint,
+            });
+        }
+
+        checkpoint.hint_file_pos += 4 + block.len() as u64 + CHECKPOINT_SIZE;
+        checkpoint.hint_file_hasher = hint_file_hasher;
+        checkpoint.data_file_pos = data_file_pos;
+        self.block = hints.into_iter();
+
+        Ok(true)
+    }
+}
+
 impl<'a> Iterator for Hints<'a> {
     type Item = Result<Hint<'a>>;
 
     fn next(&mut self) -> Option<Result<Hint<'a>>> {
-        if self.hint_file.limit() == 0 {
-            None
-        } else {
-            Some(Hint::from_read(&mut self.hint_file))
+        if self.checkpoint.is_none() {
+            let mut hint_file_hasher = self.hint_file_hasher.take()?;
+            let hint = self.next_legacy_hint(&mut hint_file_hasher);
+
+            // reading stops at the end of the hint file or at the first error
+            if let Ok(Some(_)) = hint {
+                self.hint_file_hasher = Some(hint_file_hasher);
+            }
+
+            return hint.transpose();
+        }
+
+        loop {
+            if let Some(hint) = self.block.next() {
+                return Some(Ok(hint));
+            }
+
+            if self.failed {
+                return None;
+            }
+
+            match self.next_block() {
+                Ok(true) => {}
+                Ok(false) => return None,
+                Err(err) => {
+                    self.failed = true;
+                    return Some(Err(err));
+                }
+            }
         }
     }
 }
@@ -449,24 +1509,120 @@ impl<'a> Iterator for Hints<'a> {
 pub struct RecreateHints<'a> {
     hint_writer: HintWriter,
     entries: Entries<'a>,
+    checkpoint_interval: u64,
+    corrupt_pos: Option<u64>,
+    batch: vec::IntoIter<Hint<'a>>,
+}
+
+impl<'a> RecreateHints<'a> {
+    /// Resumes iteration past the corrupt entry that caused the last error. Returns the range of
+    /// bytes that was skipped.
+    pub fn skip_corrupt(&mut self) -> Result<(u64, u64)> {
+        let corrupt_pos = self.corrupt_pos.take().expect(
+            "no corrupt entry to skip",
+        );
+        let resume_pos = match self.entries.resync(corrupt_pos)? {
+            Some(next_pos) => next_pos,
+            None => self.entries.data_file_size,
+        };
+
+        self.hint_writer.abandoned = false;
+
+        Ok((corrupt_pos, resume_pos))
+    }
+
+    /// Writes a checkpoint once enough entries were read since the last one.
+    fn checkpoint(&mut self) -> Result<()> {
+        let data_file_pos = self.entries.data_file_pos;
+        if self.hint_writer.needs_checkpoint(data_file_pos, self.checkpoint_interval) {
+            self.hint_writer.checkpoint(data_file_pos)?;
+        }
+
+        Ok(())
+    }
+
+    fn corrupt(&mut self, entry_pos: u64, err: Error) -> Error {
+        // stop here until the corrupt entry is explicitly skipped, the hint file is incomplete
+        // until then
+        self.corrupt_pos = Some(entry_pos);
+        self.hint_writer.abandoned = true;
+        err
+    }
+
+    /// Reads the `count` entries of a batch. Returns `None` if the batch is incomplete.
+    fn read_batch(&mut self, marker_pos: u64, count: u32) -> Result<Option<Vec<Hint<'a>>>> {
+        let mut hints = Vec::with_capacity(count as usize);
+
+        for _ in 0..count {
+            match self.entries.next() {
+                Some((entry_pos, Ok(entry))) => hints.push(Hint::from(entry, entry_pos)),
+                Some((entry_pos, Err(err))) => return Err(self.corrupt(entry_pos, err)),
+                None => {
+                    warn!(
+                        "Ignoring incomplete batch of {} entries at offset {}",
+                        count,
+                        marker_pos
+                    );
+                    return Ok(None);
+                }
+            }
+        }
+
+        for hint in &hints {
+            self.hint_writer.write(hint)?;
+        }
+        self.checkpoint()?;
+
+        Ok(Some(hints))
+    }
 }
 
 impl<'a> Iterator for RecreateHints<'a> {
     type Item = Result<Hint<'a>>;
 
     fn next(&mut self) -> Option<Result<Hint<'a>>> {
-        self.entries.next().map(|e| {
-            let (entry_pos, entry) = e;
-            let hint = Hint::from(entry?, entry_pos);
-            self.hint_writer.write(&hint)?;
-            Ok(hint)
-        })
+        loop {
+            if let Some(hint) = self.batch.next() {
+                return Some(Ok(hint));
+            }
+
+            if self.corrupt_pos.is_some() {
+                return None;
+            }
+
+            let (entry_pos, entry) = self.entries.next()?;
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => return Some(Err(self.corrupt(entry_pos, err))),
+            };
+
+            match entry.batch_size() {
+                Some(count) => {
+                    // the entries of a batch are only returned once all of them were read
+                    match self.read_batch(entry_pos, count) {
+                        Ok(Some(hints)) => self.batch = hints.into_iter(),
+                        Ok(None) => return None,
+                        Err(err) => return Some(Err(err)),
+                    }
+                }
+                None => {
+                    let hint = Hint::from(entry, entry_pos);
+                    return Some(
+                        self.hint_writer
+                            .write(&hint)
+                            .and_then(|_| self.checkpoint())
+                            .map(|_| hint),
+                    );
+                }
+            }
+        }
     }
 }
 
 impl<'a> Drop for RecreateHints<'a> {
     fn drop(&mut self) {
         while self.next().is_some() {}
+        let _ = self.hint_writer.finish(self.entries.data_file_size);
     }
 }
 
@@ -480,6 +1636,52 @@ fn get_hint_file_path(path: &Path, file_id: u32) -> PathBuf {
     path.join(file_id).with_extension(HINT_FILE_EXTENSION)
 }
 
+/// Moves the data files `file_ids` and their hint files from directory `from` to `to`.
+pub fn move_files(from: &Path, to: &Path, file_ids: &[u32]) -> Result<()> {
+    for &file_id in file_ids {
+        fs::rename(
+            get_data_file_path(from, file_id),
+            get_data_file_path(to, file_id),
+        )?;
+
+        let hint_file_path = get_hint_file_path(from, file_id);
+        if hint_file_path.exists() {
+            fs::rename(hint_file_path, get_hint_file_path(to, file_id))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn read_value_range(
+    data_file: &File,
+    entry_pos: u64,
+    offset: u64,
+    len: u64,
+    version: u16,
+) -> Result<Option<Vec<u8>>> {
+    let mut reader = FileReader::new(data_file, entry_pos);
+    let header = EntryHeader::read(&mut reader, version)?;
+
+    if !header.is_plain() {
+        return Ok(None);
+    }
+
+    let value_size = header.value_size as u64;
+    let offset = cmp::min(offset, value_size);
+    let value_pos = entry_pos + header.size() + header.key_size as u64;
+
+    let mut value = vec![0u8; cmp::min(len, value_size - offset) as usize];
+    reader.set_position(value_pos + offset);
+    reader.read_exact(&mut value)?;
+
+    Ok(Some(value))
+}
+
+fn read_header(path: &Path) -> Result<FileHeader> {
+    FileHeader::read(&mut get_file_handle(path, false)?)
+}
+
 fn find_data_files(path: &Path) -> Result<Vec<u32>> {
     let files = fs::read_dir(path)?;
 
@@ -509,31 +1711,128 @@ fn find_data_files(path: &Path) -> Result<Vec<u32>> {
     Ok(data_files)
 }
 
-fn is_valid_hint_file(path: &Path) -> Result<bool> {
-    Ok(
-        path.is_file() &&
-            {
-                let mut hint_file = get_file_handle(path, false)?;
+fn find_next_entry(
+    data_file: &mut File,
+    from: u64,
+    data_file_size: u64,
+    version: u16,
+) -> Result<Option<u64>> {
+    let mut reader = BufReader::new(data_file);
+    reader.seek(SeekFrom::Start(from))?;
+
+    // bounds the bytes read to validate candidates, which would otherwise be quadratic in the size
+    // of a corrupt region since most of its offsets can claim an entry up to the end of the file.
+    // Only candidates larger than what's left are passed over.
+    let mut budget = data_file_size;
+
+    // tracked from what the reads consume, which spares a seek syscall per offset
+    let mut reader_pos = from;
+
+    let mut pos = from;
+    while pos < data_file_size {
+        let limit = data_file_size - pos;
+
+        // only read and checksum the whole entry if its header looks valid
+        let entry_size = {
+            let mut entry_reader = (&mut reader).take(limit);
+            let res = EntryHeader::read(&mut entry_reader, version);
+            reader_pos += limit - entry_reader.limit();
+
+            match res {
+                Ok(ref header) if header.is_plausible(limit) => header.entry_size(),
+                Ok(_) => 0,
+                Err(Error::Io(ref err)) if err.kind() == ErrorKind::UnexpectedEof => 0,
+                Err(Error::Io(err)) => return Err(Error::Io(err)),
+                Err(_) => 0,
+            }
+        };
+
+        if entry_size > 0 && entry_size <= budget {
+            budget -= entry_size;
 
-                // FIXME: avoid reading the whole hint file into memory;
-                let mut buf = Vec::new();
-                hint_file.read_to_end(&mut buf)?;
+            reader.seek_relative(pos as i64 - reader_pos as i64)?;
+            reader_pos = pos;
 
-                buf.len() >= 4 &&
-                    {
-                        let hash = xxhash32(&buf[..buf.len() - 4]);
+            let mut entry_reader = (&mut reader).take(limit);
+            let res = Entry::from_read_bounded(&mut entry_reader, limit, version);
+            reader_pos += limit - entry_reader.limit();
 
-                        let mut cursor = Cursor::new(&buf[buf.len() - 4..]);
-                        let checksum = cursor.read_u32::<LittleEndian>()?;
+            match res {
+                Ok(_) => return Ok(Some(pos)),
+                Err(Error::Io(ref err)) if err.kind() == ErrorKind::UnexpectedEof => {}
+                Err(Error::Io(err)) => return Err(Error::Io(err)),
+                Err(_) => {}
+            }
+        }
 
-                        let valid = hash == checksum;
+        // try again at the next byte, this doesn't discard the read buffer
+        pos += 1;
+        budget += RESYNC_READ_FACTOR;
+        reader.seek_relative(pos as i64 - reader_pos as i64)?;
+        reader_pos = pos;
+    }
 
-                        if !valid {
-                            warn!("Found corrupt hint file: {:?}", &path);
-                        }
+    Ok(None)
+}
 
-                        valid
-                    }
-            },
-    )
+/// Reads the next block of a hint file with checkpoints, updating `hasher` with its contents.
+/// Returns its hints along with the position in the data file covered by its checkpoint, once the
+/// checksum of the checkpoint was validated.
+fn read_block<R: Read>(reader: &mut R, hasher: &mut XxHash32) -> Result<(Vec<u8>, u64)> {
+    let hints_size = reader.read_u32::<LittleEndian>()?;
+    hasher.write_u32::<LittleEndian>(hints_size)?;
+
+    let mut block = Vec::new();
+    reader.take(hints_size as u64).read_to_end(&mut block)?;
+    if block.len() < hints_size as usize {
+        return Err(Error::Io(ErrorKind::UnexpectedEof.into()));
+    }
+    hasher.update(&block);
+
+    let data_file_pos = reader.read_u64::<LittleEndian>()?;
+    hasher.write_u64::<LittleEndian>(data_file_pos)?;
+
+    let checksum = reader.read_u32::<LittleEndian>()?;
+    let hash = hasher.get();
+    if hash != checksum {
+        return Err(Error::InvalidChecksum {
+            expected: checksum,
+            found: hash,
+        });
+    }
+    hasher.write_u32::<LittleEndian>(checksum)?;
+
+    Ok((block, data_file_pos))
+}
+
+/// Returns `true` if the checksum at the end of a hint file written before checkpoints matches
+/// its contents, which are read without holding them in memory.
+fn is_valid_hint_file(hint_file: &mut File, hint_file_size: u64) -> Result<bool> {
+    if hint_file_size < 4 {
+        return Ok(false);
+    }
+
+    hint_file.seek(SeekFrom::Start(0))?;
+    let mut reader = BufReader::new(hint_file);
+
+    let mut hasher = XxHash32::new();
+    io::copy(&mut (&mut reader).take(hint_file_size - 4), &mut hasher)?;
+    let checksum = reader.read_u32::<LittleEndian>()?;
+
+    Ok(hasher.get() == checksum)
+}
+
+/// Hashes the bytes read through it, so that a file can be validated in the same pass that
+/// reads it.
+struct HashingReader<'r, R: 'r> {
+    reader: &'r mut R,
+    hasher: &'r mut XxHash32,
+}
+
+impl<'r, R: Read> Read for HashingReader<'r, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
 }
@@ -0,0 +1,247 @@
+use std::fs;
+
+use cask::CaskOptions;
+use errors::Result;
+use header::FORMAT_VERSION;
+use log::{EntryWriter, Log};
+
+const MIGRATE_DIR_NAME: &'static str = "migrate";
+
+/// Rewrites the data files of the `Cask` at `path` that were written in an older format version,
+/// e.g. before file headers were introduced, into the current format. Returns the ids of the
+/// migrated data files.
+///
+/// Each data file is rewritten along with its hint file into a temporary directory and then moved
+/// over the original one, so that an interrupted migration can simply be re-run. A data file with
+/// corrupt entries fails the migration and should be fixed with `repair` first. The `Cask` must not
+/// be open while it's being migrated.
+pub fn migrate(path: &str) -> Result<Vec<u32>> {
+    migrate_with_options(path, &CaskOptions::default())
+}
+
+/// Migrates the `Cask` at `path` like `migrate`, using the encryption keys set in `options` to
+/// rewrite encrypted files. Each file is encrypted again with the key it was encrypted with.
+pub fn migrate_with_options(path: &str, options: &CaskOptions) -> Result<Vec<u32>> {
+    let mut log = Log::open(path, false, false, 0, 1, options.keyring.clone(), false)?;
+
+    let migrate_path = log.path.join(MIGRATE_DIR_NAME);
+    if migrate_path.exists() {
+        fs::remove_dir_all(&migrate_path)?;
+    }
+    fs::create_dir(&migrate_path)?;
+
+    let mut migrated = Vec::new();
+
+    for file_id in log.files() {
+        let version = log.file_header(file_id)?.version;
+        if version == FORMAT_VERSION {
+            continue;
+        }
+
+        info!(
+            "Migrating data file {} from format version {} to {}",
+            file_id,
+            version,
+            FORMAT_VERSION
+        );
+
+        {
+            let cipher = log.renew_cipher(file_id)?;
+            let mut entry_writer = EntryWriter::new(
+                &migrate_path,
+                file_id,
+                cipher,
+                options.checkpoint_interval,
+            )?;
+
+            for (entry_pos, entry) in log.entries(file_id)? {
+                match entry {
+                    Ok(entry) => {
+                        entry_writer.write(&entry)?;
+                    }
+                    Err(err) => {
+                        warn!(
+                            "Found corrupt entry at offset {} of data file {}: {}",
+                            entry_pos,
+                            file_id,
+                            err
+                        );
+                        return Err(err);
+                    }
+                }
+            }
+
+            entry_writer.finish()?;
+        }
+
+        log.replace_file(&migrate_path, file_id)?;
+        migrated.push(file_id);
+    }
+
+    fs::remove_dir(&migrate_path)?;
+
+    info!("Migrated data files {:?} of database {:?}", migrated, log.path);
+
+    Ok(migrated)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::fs::{File, OpenOptions};
+    use std::io::{Seek, SeekFrom, Write};
+
+    use byteorder::{LittleEndian, WriteBytesExt};
+
+    use cask::{CaskOptions, SyncStrategy};
+    use data::{Entry, Hint};
+    use errors::Error;
+    use header::{FORMAT_VERSION, FileHeader};
+    use migrate::migrate;
+    use util::xxhash32;
+    use verify::verify;
+
+    /// Writes a data file and hint file in the format used before file headers were introduced.
+    /// The checksum at the end of the hint file was only written once the data file was `closed`.
+    fn write_legacy_files(path: &str, closed: bool) {
+        fs::create_dir(path).unwrap();
+
+        let entries = vec![
+            Entry::new(1, &b"a"[..], &b"1"[..]).unwrap(),
+            Entry::new(2, &b"b"[..], &b"2"[..]).unwrap(),
+            Entry::new(3, &b"c"[..], &b"3"[..]).unwrap(),
+            Entry::deleted(4, &b"a"[..]),
+        ];
+
+        // key sizes were stored as a u16
+        let mut data = Vec::new();
+        let mut hints = Vec::new();
+        for entry in &entries {
+            let mut hint = Vec::new();
+            Hint::new(entry, data.len() as u64)
+                .write_bytes(&mut hint)
+                .unwrap();
+            hint.drain(10..12);
+            hints.extend(hint);
+
+            let mut bytes = entry.to_bytes().unwrap();
+            bytes.drain(14..16);
+            let checksum = xxhash32(&bytes[4..]);
+            (&mut bytes[..4]).write_u32::<LittleEndian>(checksum).unwrap();
+            data.extend(bytes);
+        }
+
+        if closed {
+            let checksum = xxhash32(&hints);
+            hints.write_u32::<LittleEndian>(checksum).unwrap();
+        }
+
+        File::create(format!("{}/0000000001.cask.data", path))
+            .unwrap()
+            .write_all(&data)
+            .unwrap();
+        File::create(format!("{}/0000000001.cask.hint", path))
+            .unwrap()
+            .write_all(&hints)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_migrate() {
+        let path = "test-migrate.db";
+        let _ = fs::remove_dir_all(path);
+        write_legacy_files(path, true);
+
+        let data_file_path = format!("{}/0000000001.cask.data", path);
+
+        let open = || {
+            CaskOptions::default()
+                .sync(SyncStrategy::Never)
+                .compaction(false)
+                .open(path)
+        };
+
+        let check = || {
+            let cask = open().unwrap();
+            assert_eq!(cask.get("a").unwrap(), None);
+            assert_eq!(cask.get("b").unwrap(), Some(b"2".to_vec()));
+            assert_eq!(cask.get("c").unwrap(), Some(b"3".to_vec()));
+        };
+
+        // legacy files are still readable before they're migrated
+        check();
+
+        // the hints of a legacy hint file that doesn't match its checksum aren't applied, e.g. the
+        // key of the second hint
+        let hint_file_path = format!("{}/0000000001.cask.hint", path);
+        let mut hints = fs::read(&hint_file_path).unwrap();
+        hints[23 + 22] = b'x';
+        fs::write(&hint_file_path, &hints).unwrap();
+
+        assert_eq!(open().unwrap().get("x").unwrap(), None);
+        check();
+
+        assert_eq!(migrate(path).unwrap(), vec![1]);
+        assert_eq!(migrate(path).unwrap(), Vec::<u32>::new());
+
+        let mut data_file = File::open(&data_file_path).unwrap();
+        assert_eq!(
+            FileHeader::read(&mut data_file).unwrap().version,
+            FORMAT_VERSION
+        );
+
+        check();
+
+        // files written by a later version are rejected
+        let mut data_file = OpenOptions::new()
+            .write(true)
+            .open(&data_file_path)
+            .unwrap();
+        data_file.seek(SeekFrom::Start(8)).unwrap();
+        data_file
+            .write_u16::<LittleEndian>(FORMAT_VERSION + 1)
+            .unwrap();
+
+        match open() {
+            Err(Error::UnsupportedVersion(version)) => assert_eq!(version, FORMAT_VERSION + 1),
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("opened a cask written by a later version"),
+        }
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_legacy_torn_tail() {
+        let path = "test-legacy-torn-tail.db";
+        let _ = fs::remove_dir_all(path);
+
+        // a crash while the data file was being written tore its last write, and the hint file
+        // never got its checksum
+        write_legacy_files(path, false);
+        OpenOptions::new()
+            .append(true)
+            .open(format!("{}/0000000001.cask.data", path))
+            .unwrap()
+            .write_all(&[1; 9])
+            .unwrap();
+
+        {
+            let cask = CaskOptions::default()
+                .sync(SyncStrategy::Never)
+                .compaction(false)
+                .open(path)
+                .unwrap();
+
+            assert_eq!(cask.recovery_report().truncated, vec![(1, 9)]);
+            assert_eq!(cask.get("a").unwrap(), None);
+            assert_eq!(cask.get("b").unwrap(), Some(b"2".to_vec()));
+            assert_eq!(cask.get("c").unwrap(), Some(b"3".to_vec()));
+        }
+
+        assert_eq!(migrate(path).unwrap(), vec![1]);
+        assert!(verify(path).unwrap().is_ok());
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+}
@@ -0,0 +1,446 @@
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::Entry as HashMapEntry;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use time;
+
+use blob::move_unreferenced_blobs;
+use cask::CaskOptions;
+use data::{SequenceNumber, is_expired};
+use errors::{Error, Result};
+use log::{Log, LogWrite, LogWriter, move_files};
+use util::{Sequence, sync_dir};
+
+const REPAIR_DIR_NAME: &'static str = "repair";
+
+/// Summary of a `Cask` repair.
+#[derive(Debug)]
+pub struct RepairReport {
+    /// Number of live entries salvaged into the new data files.
+    pub salvaged: u64,
+    /// Corrupt byte ranges `(file_id, start, end)` that were discarded, including the entries of
+    /// incomplete batches and corrupt file headers.
+    pub discarded: Vec<(u32, u64, u64)>,
+    /// Ids of the new data files.
+    pub new_files: Vec<u32>,
+    /// Sequence numbers of the blob files moved to the backup directory, which no salvaged entry
+    /// references.
+    pub backup_blobs: Vec<SequenceNumber>,
+    /// Directory holding the original data and hint files, and the blob files of the entries that
+    /// weren't salvaged.
+    pub backup_path: PathBuf,
+}
+
+struct Latest {
+    sequence: SequenceNumber,
+    file_id: u32,
+    entry_pos: u64,
+    deleted: bool,
+    expires: Option<u64>,
+}
+
+/// A batch being read, whose entries are only kept once all of them were read.
+struct Batch {
+    marker_pos: u64,
+    // number of entries left to read
+    remaining: u32,
+    entries: Vec<(Vec<u8>, Latest)>,
+}
+
+/// Rebuilds the `Cask` at `path` from every valid entry found in its data files.
+///
+/// All data files are scanned skipping over any corrupt data, only the entry with the highest
+/// sequence number for each key is kept and written to new data and hint files. Batches missing
+/// any of their entries are discarded as a whole, as when the `Cask` is opened. A data file whose
+/// header is corrupt is scanned from where its entries presumably start. The new files are moved
+/// into the `Cask` dir before the original files, and the blob files only referenced by entries
+/// that weren't kept, are moved to a `backup-<timestamp>` directory inside it, so that an
+/// interrupted repair never loses data and can simply be re-run. The `Cask` must not be open while
+/// it's being repaired.
+pub fn repair(path: &str, options: &CaskOptions) -> Result<RepairReport> {
+    let log = Log::open_unchecked(path, true, options.file_pool_size, options.keyring.clone())?;
+
+    let files = log.files();
+    let mut latest: HashMap<Vec<u8>, Latest> = HashMap::new();
+    let mut discarded = Vec::new();
+
+    for &file_id in &files {
+        // a data file left with a partial header by a crash doesn't hold any entries
+        let header_error = if log.has_partial_header(file_id)? {
+            Some("partial header".to_string())
+        } else {
+            log.file_header(file_id).err().map(|err| err.to_string())
+        };
+
+        if let Some(err) = header_error {
+            let file_size = log.file_size(file_id)?;
+            let header_size = match log.presume_header(file_id) {
+                Ok(header) => cmp::min(header.size(), file_size),
+                Err(_) => file_size,
+            };
+
+            warn!(
+                "Discarding the corrupt header of data file {}: {}",
+                file_id,
+                err
+            );
+            discarded.push((file_id, 0, header_size));
+
+            if header_size == file_size {
+                continue;
+            }
+        }
+
+        let mut batch: Option<Batch> = None;
+
+        let mut entries = log.entries(file_id)?;
+        while let Some((entry_pos, entry)) = entries.next() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(Error::Io(err)) if err.kind() != ErrorKind::UnexpectedEof => {
+                    return Err(Error::Io(err));
+                }
+                Err(err) => {
+                    // the batch the corrupt entry belongs to is incomplete, and discarded with it
+                    let start = batch.take().map_or(entry_pos, |b| b.marker_pos);
+                    let next_pos = entries.resync(entry_pos)?;
+                    let end = match next_pos {
+                        Some(next_pos) => next_pos,
+                        None => log.file_size(file_id)?,
+                    };
+                    warn!(
+                        "Discarding {} bytes of corrupt data at offset {} of data file {}: {}",
+                        end - start,
+                        start,
+                        file_id,
+                        err
+                    );
+                    discarded.push((file_id, start, end));
+                    continue;
+                }
+            };
+
+            if let Some(count) = entry.batch_size() {
+                if let Some(batch) = batch.take() {
+                    discard_batch(&mut discarded, file_id, batch.marker_pos, entry_pos);
+                }
+                if count > 0 {
+                    batch = Some(Batch {
+                        marker_pos: entry_pos,
+                        remaining: count,
+                        entries: Vec::new(),
+                    });
+                }
+                continue;
+            }
+
+            let l = Latest {
+                sequence: entry.sequence,
+                file_id: file_id,
+                entry_pos: entry_pos,
+                deleted: entry.deleted,
+                expires: entry.expires,
+            };
+
+            match batch {
+                Some(ref mut batch) => {
+                    batch.entries.push((entry.key.into_owned(), l));
+                    batch.remaining -= 1;
+                }
+                None => keep_latest(&mut latest, entry.key.into_owned(), l),
+            }
+
+            if batch.as_ref().is_some_and(|b| b.remaining == 0) {
+                for (key, l) in batch.take().unwrap().entries {
+                    keep_latest(&mut latest, key, l);
+                }
+            }
+        }
+
+        if let Some(batch) = batch {
+            discard_batch(&mut discarded, file_id, batch.marker_pos, log.file_size(file_id)?);
+        }
+    }
+
+    // every older version is dropped, so there's no need to keep the tombstones or expired entries
+    let mut live: Vec<_> = latest
+        .into_values()
+        .filter(|l| !l.deleted && !is_expired(l.expires))
+        .collect();
+    live.sort_by_key(|l| (l.file_id, l.entry_pos));
+
+    let repair_path = log.path.join(REPAIR_DIR_NAME);
+    if repair_path.exists() {
+        fs::remove_dir_all(&repair_path)?;
+    }
+    fs::create_dir(&repair_path)?;
+
+    let mut new_files = Vec::new();
+    let mut live_blobs = HashSet::new();
+
+    {
+        let last_file_id = files.last().cloned().unwrap_or(0);
+        let mut log_writer = LogWriter::new(
+            &repair_path,
+            false,
+            options.max_file_size,
+            options.checkpoint_interval,
+            Arc::new(Sequence::new(last_file_id)),
+            options.keyring.clone(),
+        );
+
+        for l in &live {
+            let entry = log.read_entry(l.file_id, l.entry_pos)?;
+            if entry.blob {
+                live_blobs.insert(entry.blob_sequence());
+            }
+            if let LogWrite::NewFile(file_id, _) = log_writer.write(&entry)? {
+                new_files.push(file_id);
+            }
+        }
+
+        log_writer.finish()?;
+    }
+
+    let backup_path = log.path.join(format!(
+        "backup-{}",
+        time::strftime("%Y%m%d%H%M%S", &time::now_utc()).unwrap()
+    ));
+    fs::create_dir(&backup_path)?;
+
+    move_files(&repair_path, &log.path, &new_files)?;
+    move_files(&log.path, &backup_path, &files)?;
+    let backup_blobs = move_unreferenced_blobs(&log.path, &backup_path, &live_blobs)?;
+    sync_dir(&backup_path)?;
+    sync_dir(&log.path)?;
+    fs::remove_dir(&repair_path)?;
+
+    info!(
+        "Repaired database {:?}: salvaged {} entries into {:?}, original files moved to {:?}",
+        log.path,
+        live.len(),
+        new_files,
+        backup_path
+    );
+
+    Ok(RepairReport {
+        salvaged: live.len() as u64,
+        discarded: discarded,
+        new_files: new_files,
+        backup_blobs: backup_blobs,
+        backup_path: backup_path,
+    })
+}
+
+/// Keeps the entry `l` of `key` if it's newer than the one found so far.
+fn keep_latest(latest: &mut HashMap<Vec<u8>, Latest>, key: Vec<u8>, l: Latest) {
+    match latest.entry(key) {
+        HashMapEntry::Occupied(mut o) => {
+            if o.get().sequence <= l.sequence {
+                o.insert(l);
+            }
+        }
+        HashMapEntry::Vacant(e) => {
+            e.insert(l);
+        }
+    }
+}
+
+/// Discards the entries of an incomplete batch, from its marker at `marker_pos` up to `end`.
+fn discard_batch(discarded: &mut Vec<(u32, u64, u64)>, file_id: u32, marker_pos: u64, end: u64) {
+    warn!(
+        "Discarding incomplete batch at offset {} of data file {}",
+        marker_pos,
+        file_id
+    );
+    discarded.push((file_id, marker_pos, end));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+    use std::path::Path;
+
+    use batch::WriteBatch;
+    use blob::get_blob_file_path;
+    use cask::{CaskOptions, SyncStrategy};
+    use header::HEADER_SIZE;
+    use repair::repair;
+
+    #[test]
+    fn test_repair() {
+        let path = "test-repair.db";
+        let _ = fs::remove_dir_all(path);
+
+        let mut options = CaskOptions::default();
+        options.sync(SyncStrategy::Never).compaction(false);
+
+        {
+            let cask = options.open(path).unwrap();
+
+            cask.put("key1", "value1").unwrap();
+            cask.put("key2", "value2").unwrap();
+            cask.put("key3", "value3").unwrap();
+            cask.put("key1", "value4").unwrap();
+            cask.delete("key3").unwrap();
+        }
+
+        // flip a byte in the value of the second entry
+        let mut data_file = OpenOptions::new()
+            .write(true)
+            .open(format!("{}/0000000001.cask.data", path))
+            .unwrap();
+        data_file.seek(SeekFrom::Start(HEADER_SIZE + 30 + 25)).unwrap();
+        data_file.write_all(&[0]).unwrap();
+
+        let report = repair(path, &options).unwrap();
+        assert_eq!(report.salvaged, 1);
+        assert_eq!(report.discarded, vec![(1, HEADER_SIZE + 30, HEADER_SIZE + 60)]);
+        assert_eq!(report.new_files, vec![2]);
+        assert!(report.backup_path.join("0000000001.cask.data").is_file());
+
+        {
+            let cask = options.open(path).unwrap();
+
+            assert!(cask.recovery_report().is_clean());
+            assert_eq!(cask.keys(), vec![b"key1".to_vec()]);
+            assert_eq!(cask.get("key1").unwrap(), Some(b"value4".to_vec()));
+        }
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_repair_torn_batch() {
+        let path = "test-repair-torn-batch.db";
+        let _ = fs::remove_dir_all(path);
+
+        let mut options = CaskOptions::default();
+        options.sync(SyncStrategy::Never).compaction(false);
+
+        {
+            let cask = options.open(path).unwrap();
+
+            cask.put("a", "1").unwrap();
+            let mut batch = WriteBatch::new();
+            batch.put("b", "2").put("c", "3").put("d", "4");
+            cask.write(batch).unwrap();
+            cask.put("e", "5").unwrap();
+        }
+
+        // entries after the file header: put a (0..22), batch marker (22..46), put b (46..68),
+        // put c (68..90), put d (90..112), put e (112..134). Tear the last entry of the batch in
+        // the middle of the file.
+        let data_file_path = format!("{}/0000000001.cask.data", path);
+        let mut data = fs::read(&data_file_path).unwrap();
+        let start = (HEADER_SIZE + 100) as usize;
+        let end = (HEADER_SIZE + 112) as usize;
+        data.drain(start..end);
+        fs::write(&data_file_path, &data).unwrap();
+
+        let report = repair(path, &options).unwrap();
+        assert_eq!(report.salvaged, 2);
+        assert_eq!(report.discarded, vec![(1, HEADER_SIZE + 22, HEADER_SIZE + 100)]);
+
+        {
+            let cask = options.open(path).unwrap();
+
+            assert_eq!(cask.get("a").unwrap(), Some(b"1".to_vec()));
+            assert_eq!(cask.get("b").unwrap(), None);
+            assert_eq!(cask.get("c").unwrap(), None);
+            assert_eq!(cask.get("e").unwrap(), Some(b"5".to_vec()));
+        }
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+    #[test]
+    fn test_repair_corrupt_header() {
+        let path = "test-repair-corrupt-header.db";
+        let _ = fs::remove_dir_all(path);
+
+        let mut options = CaskOptions::default();
+        options.sync(SyncStrategy::Never).compaction(false).max_file_size(64);
+
+        // each entry takes 30 bytes, so that every data file holds a single one
+        {
+            let cask = options.open(path).unwrap();
+
+            cask.put("key1", "value1").unwrap();
+            cask.put("key2", "value2").unwrap();
+            cask.put("key3", "value3").unwrap();
+        }
+
+        // flip a byte in the checksum of the header of the second data file
+        let mut data_file = OpenOptions::new()
+            .write(true)
+            .open(format!("{}/0000000002.cask.data", path))
+            .unwrap();
+        data_file.seek(SeekFrom::Start(12)).unwrap();
+        data_file.write_all(&[0]).unwrap();
+
+        assert!(options.open(path).is_err());
+
+        // a data file left with a partial header is discarded as a whole
+        fs::write(format!("{}/0000000004.cask.data", path), b"\x89CASK").unwrap();
+
+        let report = repair(path, &options).unwrap();
+        assert_eq!(report.salvaged, 3);
+        assert_eq!(report.discarded, vec![(2, 0, HEADER_SIZE), (4, 0, 5)]);
+        assert!(report.backup_path.join("0000000002.cask.data").is_file());
+        assert!(report.backup_path.join("0000000004.cask.data").is_file());
+
+        {
+            let cask = options.open(path).unwrap();
+
+            assert_eq!(cask.get("key1").unwrap(), Some(b"value1".to_vec()));
+            assert_eq!(cask.get("key2").unwrap(), Some(b"value2".to_vec()));
+            assert_eq!(cask.get("key3").unwrap(), Some(b"value3".to_vec()));
+        }
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_repair_blobs() {
+        let path = "test-repair-blobs.db";
+        let _ = fs::remove_dir_all(path);
+
+        let mut options = CaskOptions::default();
+        options.sync(SyncStrategy::Never).compaction(false).blob_threshold(10);
+
+        {
+            let cask = options.open(path).unwrap();
+
+            cask.put("key1", vec![1u8; 20]).unwrap();
+            cask.put("key2", vec![2u8; 20]).unwrap();
+            cask.put("key1", vec![3u8; 20]).unwrap();
+            cask.delete("key2").unwrap();
+        }
+
+        let report = repair(path, &options).unwrap();
+        assert_eq!(report.salvaged, 1);
+        assert!(report.discarded.is_empty());
+
+        // the blobs of the older version of key1 and of the deleted key2 are backed up
+        assert_eq!(report.backup_blobs, vec![1, 2]);
+        for &sequence in &[1, 2] {
+            assert!(get_blob_file_path(&report.backup_path, sequence).is_file());
+        }
+        assert!(get_blob_file_path(Path::new(path), 3).is_file());
+
+        {
+            let cask = options.open(path).unwrap();
+
+            assert_eq!(cask.get("key1").unwrap(), Some(vec![3u8; 20]));
+            assert_eq!(cask.get("key2").unwrap(), None);
+        }
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+}
@@ -1,12 +1,14 @@
 use std::fs::{File, OpenOptions};
 use std::hash::Hasher;
-use std::io::{Result, Write};
+use std::io::{Read, Result, Write};
 use std::path::Path;
 use std::result::Result::Ok;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use twox_hash::XxHash32 as TwoXhash32;
 
+#[derive(Clone)]
 pub struct XxHash32(TwoXhash32);
 
 impl XxHash32 {
@@ -52,6 +54,69 @@ pub fn get_file_handle(path: &Path, write: bool) -> Result<File> {
     }
 }
 
+/// Reads from `file` at `pos` without using or changing the file's cursor, so that the same handle
+/// can be read concurrently.
+#[cfg(unix)]
+pub fn read_at(file: &File, buf: &mut [u8], pos: u64) -> Result<usize> {
+    use std::os::unix::fs::FileExt;
+    file.read_at(buf, pos)
+}
+
+/// Reads from `file` at `pos`. On windows this moves the file's cursor, which isn't relied upon by
+/// any reader.
+#[cfg(windows)]
+pub fn read_at(file: &File, buf: &mut [u8], pos: u64) -> Result<usize> {
+    use std::os::windows::fs::FileExt;
+    file.seek_read(buf, pos)
+}
+
+/// A `Read` over a shared file handle that keeps its own position and uses positional reads.
+pub struct FileReader<'a> {
+    file: &'a File,
+    pos: u64,
+}
+
+impl<'a> FileReader<'a> {
+    pub fn new(file: &'a File, pos: u64) -> FileReader<'a> {
+        FileReader {
+            file: file,
+            pos: pos,
+        }
+    }
+
+    pub fn set_position(&mut self, pos: u64) {
+        self.pos = pos;
+    }
+}
+
+impl<'a> Read for FileReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let read = read_at(self.file, buf, self.pos)?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+/// Synchronizes the directory at `path` to disk, so that the files created, renamed or removed in
+/// it are too.
+#[cfg(unix)]
+pub fn sync_dir(path: &Path) -> Result<()> {
+    File::open(path)?.sync_all()
+}
+
+/// Directories can't be opened on windows, where their entries are synchronized along with the
+/// files themselves.
+#[cfg(windows)]
+pub fn sync_dir(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// Returns the current time in milliseconds since the unix epoch.
+pub fn now_millis() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    now.as_secs() * 1000 + now.subsec_millis() as u64
+}
+
 pub struct Sequence(AtomicUsize);
 
 impl Sequence {
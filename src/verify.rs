@@ -0,0 +1,576 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::ErrorKind;
+
+use cask::CaskOptions;
+use data::{Hint, SequenceNumber};
+use errors::{Error, Result};
+use log::Log;
+
+/// A problem found while verifying a data file or its hint file.
+#[derive(Debug, PartialEq)]
+pub enum VerifyError {
+    /// The data file holds nothing but part of its header, as left by a crash while creating it.
+    /// It's removed when the `Cask` is opened.
+    PartialHeader,
+    /// The header of the data file is corrupt, so none of its entries were read.
+    CorruptHeader { error: String },
+    /// A corrupt entry was found at `entry_pos`. The following `length` bytes couldn't be read as
+    /// valid entries.
+    CorruptEntry {
+        entry_pos: u64,
+        length: u64,
+        error: String,
+    },
+    /// The batch marker at `marker_pos` is followed by only `found` of its `count` entries, which
+    /// are ignored when the `Cask` is opened.
+    IncompleteBatch {
+        marker_pos: u64,
+        count: u32,
+        found: u32,
+    },
+    /// The blob file referenced by the entry at `entry_pos` is missing or corrupt.
+    CorruptBlob { entry_pos: u64, error: String },
+    /// The data file has no hint file.
+    MissingHintFile,
+    /// The hint file checksum doesn't match its contents, or it doesn't cover the whole data
+    /// file.
+    InvalidHintFile,
+    /// The hint file couldn't be read after `hints` hints.
+    UnreadableHintFile { hints: u64, error: String },
+    /// A hint doesn't match the entry it points to.
+    HintMismatch { entry_pos: u64, reason: String },
+    /// The number of hints doesn't match the number of valid entries in the data file.
+    HintCountMismatch { hints: u64, entries: u64 },
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            VerifyError::PartialHeader => write!(f, "partial file header"),
+            VerifyError::CorruptHeader { ref error } => write!(f, "corrupt file header: {}", error),
+            VerifyError::CorruptEntry {
+                entry_pos,
+                length,
+                ref error,
+            } => {
+                write!(
+                    f,
+                    "corrupt entry at offset {} ({} bytes unreadable): {}",
+                    entry_pos,
+                    length,
+                    error
+                )
+            }
+            VerifyError::IncompleteBatch {
+                marker_pos,
+                count,
+                found,
+            } => {
+                write!(
+                    f,
+                    "incomplete batch at offset {} ({} of {} entries)",
+                    marker_pos,
+                    found,
+                    count
+                )
+            }
+            VerifyError::CorruptBlob {
+                entry_pos,
+                ref error,
+            } => write!(f, "corrupt blob of entry at offset {}: {}", entry_pos, error),
+            VerifyError::MissingHintFile => write!(f, "missing hint file"),
+            VerifyError::InvalidHintFile => write!(f, "invalid or incomplete hint file"),
+            VerifyError::UnreadableHintFile { hints, ref error } => {
+                write!(f, "unreadable hint file after {} hints: {}", hints, error)
+            }
+            VerifyError::HintMismatch {
+                entry_pos,
+                ref reason,
+            } => write!(f, "hint for entry at offset {} {}", entry_pos, reason),
+            VerifyError::HintCountMismatch { hints, entries } => {
+                write!(
+                    f,
+                    "hint file has {} hints but data file has {} entries",
+                    hints,
+                    entries
+                )
+            }
+        }
+    }
+}
+
+/// Verification result for a single data file and its hint file.
+#[derive(Debug)]
+pub struct FileReport {
+    /// Id of the data file.
+    pub file_id: u32,
+    /// Size of the data file in bytes.
+    pub size: u64,
+    /// Number of valid entries in the data file, not counting batch markers nor the entries of
+    /// incomplete batches.
+    pub entries: u64,
+    /// Problems found in the data file and its hint file.
+    pub errors: Vec<VerifyError>,
+}
+
+/// Verification result for all data and hint files in a `Cask`.
+#[derive(Debug)]
+pub struct VerifyReport {
+    /// Per-file results, ordered by file id.
+    pub files: Vec<FileReport>,
+}
+
+impl VerifyReport {
+    /// Returns `true` if no problems were found.
+    pub fn is_ok(&self) -> bool {
+        self.files.iter().all(|f| f.errors.is_empty())
+    }
+}
+
+impl Display for VerifyReport {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for file in &self.files {
+            writeln!(
+                f,
+                "file {:010}: {} bytes, {} entries, {}",
+                file.file_id,
+                file.size,
+                file.entries,
+                if file.errors.is_empty() {
+                    "ok"
+                } else {
+                    "FAILED"
+                }
+            )?;
+
+            for error in &file.errors {
+                writeln!(f, "  {}", error)?;
+            }
+        }
+
+        let failed = self.files.iter().filter(|f| !f.errors.is_empty()).count();
+        writeln!(
+            f,
+            "{} files checked, {} with errors",
+            self.files.len(),
+            failed
+        )
+    }
+}
+
+/// Verifies every data and hint file of the `Cask` at `path`.
+///
+/// Every entry is read and its checksum validated, and every hint is checked against the entry it
+/// points to. Nothing in the `Cask` dir is modified, and it must not be open while it's being
+/// verified.
+pub fn verify(path: &str) -> Result<VerifyReport> {
+    verify_with_options(path, &CaskOptions::default())
+}
+
+/// Verifies the `Cask` at `path` like `verify`, using the encryption keys set in `options` to
+/// authenticate the entries of encrypted files.
+pub fn verify_with_options(path: &str, options: &CaskOptions) -> Result<VerifyReport> {
+    let log = Log::open_unchecked(path, false, 1, options.keyring.clone())?;
+
+    let mut files = Vec::new();
+
+    for file_id in log.files() {
+        files.push(verify_file(&log, file_id)?);
+    }
+
+    Ok(VerifyReport { files: files })
+}
+
+fn verify_file(log: &Log, file_id: u32) -> Result<FileReport> {
+    let mut report = FileReport {
+        file_id: file_id,
+        size: log.file_size(file_id)?,
+        entries: 0,
+        errors: Vec::new(),
+    };
+
+    if log.has_partial_header(file_id)? {
+        report.errors.push(VerifyError::PartialHeader);
+        return Ok(report);
+    }
+
+    if let Err(err) = log.file_header(file_id) {
+        report.errors.push(VerifyError::CorruptHeader { error: err.to_string() });
+        return Ok(report);
+    }
+
+    // position of the marker, number of entries and number of entries read so far of the batch
+    // being read, whose entries are only counted once all of them were read
+    let mut batch = None;
+
+    let mut entries = log.entries(file_id)?;
+    while let Some((entry_pos, entry)) = entries.next() {
+        match entry {
+            Ok(entry) => {
+                // batch markers don't have hints
+                match (entry.batch_size(), batch) {
+                    (Some(count), _) => {
+                        if let Some(batch) = batch.take() {
+                            report.errors.push(incomplete_batch(batch));
+                        }
+                        if count > 0 {
+                            batch = Some((entry_pos, count, 0));
+                        }
+                    }
+                    (None, Some((marker_pos, count, found))) => {
+                        if found + 1 == count {
+                            report.entries += count as u64;
+                            batch = None;
+                        } else {
+                            batch = Some((marker_pos, count, found + 1));
+                        }
+                    }
+                    (None, None) => report.entries += 1,
+                }
+
+                if let Some(len) = entry.blob_size() {
+                    if let Err(err) = verify_blob(log, entry.blob_sequence(), len) {
+                        report.errors.push(VerifyError::CorruptBlob {
+                            entry_pos: entry_pos,
+                            error: err.to_string(),
+                        });
+                    }
+                }
+            }
+            Err(Error::Io(err)) if err.kind() != ErrorKind::UnexpectedEof => {
+                return Err(Error::Io(err));
+            }
+            Err(err) => {
+                if let Some(batch) = batch.take() {
+                    report.errors.push(incomplete_batch(batch));
+                }
+
+                let next_pos = entries.resync(entry_pos)?.unwrap_or(report.size);
+                report.errors.push(VerifyError::CorruptEntry {
+                    entry_pos: entry_pos,
+                    length: next_pos - entry_pos,
+                    error: err.to_string(),
+                });
+            }
+        }
+    }
+
+    if let Some(batch) = batch {
+        report.errors.push(incomplete_batch(batch));
+    }
+
+    let hints = match log.hints(file_id)? {
+        Some(hints) => hints,
+        None => {
+            report.errors.push(if log.has_hint_file(file_id) {
+                VerifyError::InvalidHintFile
+            } else {
+                VerifyError::MissingHintFile
+            });
+            return Ok(report);
+        }
+    };
+
+    let mut count = 0;
+    for hint in hints {
+        let hint = match hint {
+            Ok(hint) => hint,
+            // hint files are validated while they're read
+            Err(Error::InvalidChecksum { .. }) => {
+                report.errors.push(VerifyError::InvalidHintFile);
+                return Ok(report);
+            }
+            Err(Error::Io(ref err)) if err.kind() == ErrorKind::UnexpectedEof => {
+                report.errors.push(VerifyError::InvalidHintFile);
+                return Ok(report);
+            }
+            Err(err) => {
+                report.errors.push(VerifyError::UnreadableHintFile {
+                    hints: count,
+                    error: err.to_string(),
+                });
+                return Ok(report);
+            }
+        };
+
+        count += 1;
+
+        if let Some(reason) = check_hint(log, file_id, &hint) {
+            report.errors.push(VerifyError::HintMismatch {
+                entry_pos: hint.entry_pos,
+                reason: reason,
+            });
+        }
+    }
+
+    if count != report.entries {
+        report.errors.push(VerifyError::HintCountMismatch {
+            hints: count,
+            entries: report.entries,
+        });
+    }
+
+    Ok(report)
+}
+
+fn incomplete_batch((marker_pos, count, found): (u64, u32, u32)) -> VerifyError {
+    VerifyError::IncompleteBatch {
+        marker_pos: marker_pos,
+        count: count,
+        found: found,
+    }
+}
+
+/// Reads the whole blob of the entry with `sequence`, which validates the checksum of every chunk.
+fn verify_blob(log: &Log, sequence: SequenceNumber, len: u64) -> Result<()> {
+    io::copy(&mut log.blob_reader(sequence, len)?, &mut io::sink())?;
+    Ok(())
+}
+
+fn check_hint(log: &Log, file_id: u32, hint: &Hint) -> Option<String> {
+    let entry = match log.read_entry(file_id, hint.entry_pos) {
+        Ok(entry) => entry,
+        Err(err) => return Some(format!("points to an unreadable entry: {}", err)),
+    };
+
+    if entry.key != hint.key {
+        Some(format!(
+            "has key {:?}, entry has key {:?}",
+            hint.key,
+            entry.key
+        ))
+    } else if entry.sequence != hint.sequence {
+        Some(format!(
+            "has sequence {}, entry has sequence {}",
+            hint.sequence,
+            entry.sequence
+        ))
+    } else if entry.deleted != hint.deleted {
+        Some(format!(
+            "has tombstone {}, entry has tombstone {}",
+            hint.deleted,
+            entry.deleted
+        ))
+    } else if entry.value.len() as u32 != hint.value_size {
+        Some(format!(
+            "has value size {}, entry has value size {}",
+            hint.value_size,
+            entry.value.len()
+        ))
+    } else if entry.expires != hint.expires {
+        Some(format!(
+            "has expiry {:?}, entry has expiry {:?}",
+            hint.expires,
+            entry.expires
+        ))
+    } else if entry.blob != hint.blob {
+        Some(format!(
+            "has blob {}, entry has blob {}",
+            hint.blob,
+            entry.blob
+        ))
+    } else if entry.compression != hint.compression {
+        Some(format!(
+            "has compression {:?}, entry has compression {:?}",
+            hint.compression,
+            entry.compression
+        ))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+
+    use batch::WriteBatch;
+    use cask::{CaskOptions, CorruptionPolicy, SyncStrategy};
+    use header::HEADER_SIZE;
+    use verify::{VerifyError, verify};
+
+    #[test]
+    fn test_verify() {
+        let path = "test-verify.db";
+        let _ = fs::remove_dir_all(path);
+
+        {
+            let cask = CaskOptions::default()
+                .sync(SyncStrategy::Never)
+                .compaction(false)
+                .open(path)
+                .unwrap();
+
+            cask.put("key1", "value1").unwrap();
+            cask.put("key2", "value2").unwrap();
+            cask.delete("key1").unwrap();
+        }
+
+        let report = verify(path).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.files.len(), 1);
+        assert_eq!(report.files[0].entries, 3);
+
+        // flip a byte in the value of the second entry
+        let mut data_file = OpenOptions::new()
+            .write(true)
+            .open(format!("{}/0000000001.cask.data", path))
+            .unwrap();
+        data_file.seek(SeekFrom::Start(HEADER_SIZE + 30 + 25)).unwrap();
+        data_file.write_all(&[0]).unwrap();
+
+        let report = verify(path).unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(report.files[0].entries, 2);
+
+        let errors = &report.files[0].errors;
+        assert_eq!(errors.len(), 3);
+        match errors[0] {
+            VerifyError::CorruptEntry {
+                entry_pos, length, ..
+            } => assert_eq!((entry_pos, length), (HEADER_SIZE + 30, 30)),
+            ref e => panic!("unexpected error: {:?}", e),
+        }
+        match errors[1] {
+            VerifyError::HintMismatch { entry_pos, .. } => {
+                assert_eq!(entry_pos, HEADER_SIZE + 30)
+            }
+            ref e => panic!("unexpected error: {:?}", e),
+        }
+        assert_eq!(
+            errors[2],
+            VerifyError::HintCountMismatch {
+                hints: 3,
+                entries: 2,
+            }
+        );
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_verify_torn_batch() {
+        let path = "test-verify-torn-batch.db";
+        let _ = fs::remove_dir_all(path);
+
+        let mut options = CaskOptions::default();
+        options.sync(SyncStrategy::Never).compaction(false);
+
+        {
+            let cask = options.open(path).unwrap();
+
+            cask.put("a", "1").unwrap();
+            let mut batch = WriteBatch::new();
+            batch.put("b", "2").put("c", "3").put("d", "4");
+            cask.write(batch).unwrap();
+            cask.put("e", "5").unwrap();
+        }
+
+        // entries after the file header: put a (0..22), batch marker (22..46), put b (46..68),
+        // put c (68..90), put d (90..112), put e (112..134). Tear the last entry of the batch in
+        // the middle of the file.
+        let data_file_path = format!("{}/0000000001.cask.data", path);
+        let mut data = fs::read(&data_file_path).unwrap();
+        let start = (HEADER_SIZE + 100) as usize;
+        let end = (HEADER_SIZE + 112) as usize;
+        data.drain(start..end);
+        fs::write(&data_file_path, &data).unwrap();
+
+        let errors = vec![
+            VerifyError::IncompleteBatch {
+                marker_pos: HEADER_SIZE + 22,
+                count: 3,
+                found: 2,
+            },
+            VerifyError::CorruptEntry {
+                entry_pos: HEADER_SIZE + 90,
+                length: 10,
+                error: "IO error: entry extends past the end of the file".to_string(),
+            },
+        ];
+
+        let report = verify(path).unwrap();
+        assert_eq!(report.files[0].entries, 2);
+        assert_eq!(report.files[0].errors[..2], errors[..]);
+
+        // the hints recreated when opening the cask leave out the whole batch as well
+        options
+            .clone()
+            .corruption_policy(CorruptionPolicy::SkipEntry)
+            .open(path)
+            .unwrap();
+
+        let report = verify(path).unwrap();
+        assert_eq!(report.files[0].entries, 2);
+        assert_eq!(report.files[0].errors, errors);
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+    #[test]
+    fn test_verify_corrupt_header() {
+        let path = "test-verify-corrupt-header.db";
+        let _ = fs::remove_dir_all(path);
+
+        // each entry takes 30 bytes, so that every data file holds a single one
+        {
+            let cask = CaskOptions::default()
+                .sync(SyncStrategy::Never)
+                .compaction(false)
+                .max_file_size(64)
+                .open(path)
+                .unwrap();
+
+            cask.put("key1", "value1").unwrap();
+            cask.put("key2", "value2").unwrap();
+            cask.put("key3", "value3").unwrap();
+        }
+
+        // flip a byte in the checksum of the header of the second data file, and add a data file
+        // left with a partial header
+        let mut data_file = OpenOptions::new()
+            .write(true)
+            .open(format!("{}/0000000002.cask.data", path))
+            .unwrap();
+        data_file.seek(SeekFrom::Start(12)).unwrap();
+        data_file.write_all(&[0]).unwrap();
+
+        fs::write(format!("{}/0000000004.cask.data", path), b"\x89CASK").unwrap();
+        fs::remove_file(format!("{}/cask.lock", path)).unwrap();
+
+        let file_names = || {
+            let mut file_names: Vec<_> = fs::read_dir(path)
+                .unwrap()
+                .map(|file| file.unwrap().file_name())
+                .collect();
+            file_names.sort();
+            file_names
+        };
+        let before = file_names();
+
+        let report = verify(path).unwrap();
+        assert!(!report.is_ok());
+
+        let file_ids: Vec<_> = report.files.iter().map(|f| f.file_id).collect();
+        assert_eq!(file_ids, vec![1, 2, 3, 4]);
+
+        for &i in &[0, 2] {
+            assert!(report.files[i].errors.is_empty());
+            assert_eq!(report.files[i].entries, 1);
+        }
+
+        match report.files[1].errors[..] {
+            [VerifyError::CorruptHeader { .. }] => {}
+            ref e => panic!("unexpected errors: {:?}", e),
+        }
+        assert_eq!(report.files[3].errors, vec![VerifyError::PartialHeader]);
+
+        // nothing was removed nor created
+        assert_eq!(file_names(), before);
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+}